@@ -0,0 +1,899 @@
+//!
+//! Analyses that run over a set of parsed targets
+//!
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::Path;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::functions::pattern_stem;
+use crate::implicit;
+use crate::types::{PatternRule, PrereqKind, RecipeLine, SearchPath, Target};
+
+/// An output that isn't removed by any `rm` command in the makefile
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CleanGap {
+    // target that produces the output
+    pub target: String,
+    // output path that won't be cleaned up
+    pub output: String,
+}
+
+/// Comparison of the paths a makefile deletes against the outputs it produces
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CleanReport {
+    // every path (or glob) passed to `rm`, across all targets
+    pub removed: Vec<String>,
+    // outputs not covered by any of the removed paths
+    pub gaps: Vec<CleanGap>,
+}
+
+/// Find outputs that a clean step won't delete. An output counts as removed if it is
+/// removed directly, lives under a removed directory, or matches a removed glob.
+pub fn clean_gaps(targets: &[Target]) -> CleanReport {
+    let mut removed: Vec<String> = targets
+        .iter()
+        .flat_map(|t| t.removes.iter().map(|r| normalize(r)))
+        .collect();
+    removed.sort();
+    removed.dedup();
+
+    let globs: Vec<Regex> = removed.iter().filter_map(|r| glob_to_regex(r)).collect();
+
+    let mut gaps = Vec::new();
+    for target in targets {
+        for output in target.output.iter().flatten() {
+            let output = normalize(output);
+            let is_removed = removed
+                .iter()
+                .any(|r| output == *r || output.starts_with(&format!("{}/", r)))
+                || globs.iter().any(|re| re.is_match(&output));
+
+            if !is_removed {
+                gaps.push(CleanGap {
+                    target: target.name.clone(),
+                    output,
+                });
+            }
+        }
+    }
+
+    CleanReport { removed, gaps }
+}
+
+/// Summary of everything the makefile builds
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ArtifactReport {
+    // number of distinct output paths across all targets
+    pub count: usize,
+    // combined size in bytes of the outputs that currently exist
+    pub total_size: u64,
+    // outputs that don't exist, and so don't count towards the size
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub missing: Vec<String>,
+}
+
+/// Count the distinct outputs of all targets and add up the size of those that exist.
+/// Outputs are looked for relative to `dir`.
+pub fn artifact_report(targets: &[Target], dir: &Path) -> ArtifactReport {
+    let mut outputs: Vec<String> = targets
+        .iter()
+        .flat_map(|t| t.output.iter().flatten().map(|o| normalize(o)))
+        .collect();
+    outputs.sort();
+    outputs.dedup();
+
+    let mut total_size = 0;
+    let mut missing = Vec::new();
+    for output in &outputs {
+        match std::fs::metadata(dir.join(output)) {
+            Ok(meta) => total_size += meta.len(),
+            Err(_) => missing.push(output.clone()),
+        }
+    }
+
+    ArtifactReport {
+        count: outputs.len(),
+        total_size,
+        missing,
+    }
+}
+
+/// A target that refers to a variable in its definition
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct VarUsage {
+    // target that refers to the variable
+    pub target: String,
+    // which parts of the target refer to the variable: name, prerequisites, and/or outputs
+    pub used_in: Vec<String>,
+}
+
+/// Find the targets whose unexpanded name, prerequisites, or outputs refer directly to the
+/// variable `name`
+pub fn variable_usage(targets: &[Target], name: &str) -> Vec<VarUsage> {
+    // match $(NAME), ${NAME}, substitution refs like $(NAME:.c=.o), and $N for 1-char names
+    let mut pattern = format!(r"\$[({{]{}[:)}}]", regex::escape(name));
+    if name.chars().count() == 1 {
+        pattern.push_str(&format!(r"|\${}", regex::escape(name)));
+    }
+    let re = Regex::new(&pattern).unwrap();
+
+    targets
+        .iter()
+        .filter_map(|target| {
+            let mut used_in = Vec::new();
+            if re.is_match(&target.raw.name) {
+                used_in.push("name".to_string());
+            }
+            if target.raw.prerequisites.iter().any(|p| re.is_match(p)) {
+                used_in.push("prerequisites".to_string());
+            }
+            if target.raw.outputs.iter().any(|o| re.is_match(o)) {
+                used_in.push("outputs".to_string());
+            }
+
+            if used_in.is_empty() {
+                None
+            } else {
+                Some(VarUsage {
+                    target: target.name.clone(),
+                    used_in,
+                })
+            }
+        })
+        .collect()
+}
+
+/// The outputs that fall into one category
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct OutputGroup {
+    pub count: usize,
+    pub outputs: Vec<String>,
+}
+
+/// Group the distinct outputs of all targets by their file extension (like `.o`), with
+/// outputs that don't have one under `none`
+pub fn outputs_by_extension(targets: &[Target]) -> BTreeMap<String, OutputGroup> {
+    let mut outputs: Vec<String> = targets
+        .iter()
+        .flat_map(|t| t.output.iter().flatten().map(|o| normalize(o)))
+        .collect();
+    outputs.sort();
+    outputs.dedup();
+
+    let mut groups: BTreeMap<String, OutputGroup> = BTreeMap::new();
+    for output in outputs {
+        let key = match Path::new(&output).extension() {
+            Some(ext) => format!(".{}", ext.to_string_lossy()),
+            None => "none".to_string(),
+        };
+
+        let group = groups.entry(key).or_default();
+        group.count += 1;
+        group.outputs.push(output);
+    }
+
+    groups
+}
+
+/// An output path produced by more than one target
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputCollision {
+    pub output: String,
+    // every target that produces the output, in the order they're defined
+    pub targets: Vec<String>,
+}
+
+/// Find output paths that more than one target claims to produce
+pub fn output_collisions(targets: &[Target]) -> Vec<OutputCollision> {
+    let mut producers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for target in targets {
+        for output in target.output.iter().flatten() {
+            let names = producers.entry(normalize(output)).or_default();
+            if !names.contains(&target.name) {
+                names.push(target.name.clone());
+            }
+        }
+    }
+
+    producers
+        .into_iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(output, targets)| OutputCollision { output, targets })
+        .collect()
+}
+
+/// A target whose `# Output:` annotations don't agree with the outputs found in its recipe
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AnnotationMismatch {
+    pub target: String,
+    // annotated outputs that the recipe doesn't appear to produce
+    pub annotated: Vec<String>,
+    // outputs found in the recipe itself
+    pub detected: Vec<String>,
+}
+
+/// Check the `# Output:` annotations of targets against the outputs found in their recipes.
+/// Only targets with both kinds of output are checked; a target is reported if any of its
+/// annotated outputs isn't among the detected ones.
+pub fn annotation_mismatches(targets: &[Target]) -> Vec<AnnotationMismatch> {
+    targets
+        .iter()
+        .filter_map(|target| {
+            let (annotated, detected): (Vec<_>, Vec<_>) =
+                target.output_sources.iter().partition(|s| s.via == "annotation");
+            let detected: Vec<String> = detected.iter().map(|s| normalize(&s.output)).collect();
+            if annotated.is_empty() || detected.is_empty() {
+                return None;
+            }
+
+            let mut stale: Vec<String> = annotated
+                .iter()
+                .map(|s| normalize(&s.output))
+                .filter(|o| !detected.contains(o))
+                .collect();
+            stale.dedup();
+            if stale.is_empty() {
+                return None;
+            }
+
+            Some(AnnotationMismatch {
+                target: target.name.clone(),
+                annotated: stale,
+                detected,
+            })
+        })
+        .collect()
+}
+
+/// Where an output comes from: the command that produces it and what goes into it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub output: String,
+    // target whose recipe produces the output
+    pub target: String,
+    // the expanded recipe command that produces the output
+    pub command: String,
+    // makefile and line number of the command, and the column of the output in it
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    // how the output was found, like "-o" or "annotation"
+    pub via: String,
+    // the target's prerequisites
+    pub inputs: Vec<String>,
+}
+
+/// Get a provenance record for every output of every target
+pub fn provenance(targets: &[Target]) -> Vec<Provenance> {
+    targets
+        .iter()
+        .flat_map(|target| {
+            target.output_sources.iter().map(move |source| Provenance {
+                output: source.output.clone(),
+                target: target.name.clone(),
+                command: source.command.clone(),
+                file: source.file.clone(),
+                line: source.line,
+                column: source.column,
+                via: source.via.clone(),
+                inputs: target.prerequisites.clone(),
+            })
+        })
+        .collect()
+}
+
+/// The environment variables read by a target's recipe
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EnvUsage {
+    // target whose recipe reads the variables
+    pub target: String,
+    // names of the variables, sorted
+    pub variables: Vec<String>,
+}
+
+/// Find the shell variables (like `$$HOME` or `$${HOME}`) that each target's recipe reads.
+/// Make turns `$$` into a single `$` for the shell, so these are the variables the build
+/// reads from its environment rather than make variables.
+pub fn env_usage(targets: &[Target]) -> Vec<EnvUsage> {
+    let re = Regex::new(
+        r"\$\$(\{(?P<braced>[A-Za-z_][A-Za-z0-9_]*)\}|(?P<bare>[A-Za-z_][A-Za-z0-9_]*))",
+    )
+    .unwrap();
+
+    targets
+        .iter()
+        .filter_map(|target| {
+            let mut variables: Vec<String> = target
+                .recipe
+                .iter()
+                .flat_map(|line| re.captures_iter(&line.command))
+                .filter_map(|c| c.name("braced").or_else(|| c.name("bare")))
+                .map(|m| m.as_str().to_string())
+                .collect();
+            variables.sort();
+            variables.dedup();
+
+            if variables.is_empty() {
+                None
+            } else {
+                Some(EnvUsage {
+                    target: target.name.clone(),
+                    variables,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Restrict targets to those reachable from `goals` through their prerequisites (or
+/// recursive make calls), keeping the original order of the targets
+pub fn reachable_from(targets: Vec<Target>, goals: &[String]) -> Vec<Target> {
+    let mut reached: HashSet<&str> = HashSet::new();
+    let mut queue: Vec<&str> = goals.iter().map(|g| g.as_str()).collect();
+
+    while let Some(name) = queue.pop() {
+        if !reached.insert(name) {
+            continue;
+        }
+
+        for target in targets.iter().filter(|t| t.name == name) {
+            queue.extend(target.prerequisites.iter().map(|p| p.as_str()));
+            queue.extend(target.order_only.iter().map(|p| p.as_str()));
+            queue.extend(target.delegates_to.iter().map(|d| d.as_str()));
+        }
+    }
+
+    let reached: HashSet<String> = reached.into_iter().map(|r| r.to_string()).collect();
+    targets
+        .into_iter()
+        .filter(|t| reached.contains(&t.name))
+        .collect()
+}
+
+/// Get the order that `goal` and the targets it depends on would be built in, with each
+/// target after all of its prerequisites. Prerequisites that aren't targets (i.e. source
+/// files) aren't included. Returns an error if the goal isn't a target or the targets
+/// depend on each other in a cycle.
+pub fn build_plan(targets: &[Target], goal: &str) -> Result<Vec<String>, String> {
+    if !targets.iter().any(|t| t.name == goal) {
+        return Err(format!("No target named '{}'", goal));
+    }
+
+    let mut plan = Vec::new();
+    let mut visiting = Vec::new();
+    visit_for_plan(targets, goal, &mut visiting, &mut plan)?;
+    Ok(plan)
+}
+
+/// Add a target to the build plan after its prerequisites. `visiting` is the chain of
+/// targets currently being visited, which is used to report cycles.
+fn visit_for_plan<'a>(
+    targets: &'a [Target],
+    name: &'a str,
+    visiting: &mut Vec<&'a str>,
+    plan: &mut Vec<String>,
+) -> Result<(), String> {
+    if plan.iter().any(|p| p == name) {
+        return Ok(());
+    }
+    if let Some(idx) = visiting.iter().position(|v| *v == name) {
+        let mut cycle = visiting[idx..].to_vec();
+        cycle.push(name);
+        return Err(format!("Dependency cycle: {}", cycle.join(" -> ")));
+    }
+
+    let matching: Vec<&Target> = targets.iter().filter(|t| t.name == name).collect();
+    if matching.is_empty() {
+        return Ok(());
+    }
+
+    visiting.push(name);
+    // order-only prerequisites still have to be built first
+    for target in matching {
+        for prereq in target.prerequisites.iter().chain(&target.order_only) {
+            visit_for_plan(targets, prereq, visiting, plan)?;
+        }
+    }
+    visiting.pop();
+
+    plan.push(name.to_string());
+    Ok(())
+}
+
+/// Find targets that look like they should be declared .PHONY but aren't: they have no
+/// detected outputs, don't look like a file name, and don't exist as a file in `dir`
+/// (the makefile's directory, where make would look for them).
+pub fn suggest_phony(targets: &[Target], declared: &[String], dir: &Path) -> Vec<String> {
+    targets
+        .iter()
+        .filter(|t| !declared.contains(&t.name))
+        .filter(|t| t.output.iter().flatten().next().is_none())
+        .filter(|t| !t.name.contains(['.', '/', '%', '$']))
+        .filter(|t| !dir.join(&t.name).exists())
+        .map(|t| t.name.clone())
+        .collect()
+}
+
+/// Fill in the outputs and recipes of targets that make would build with one of its built-in
+/// implicit rules (like `%.o: %.c`) because their own rules have no recipe. The first built-in
+/// rule whose source is a target, a prerequisite, or a file in `dir` is used, or one whose
+/// source another built-in rule can make from such a file (like `.c` files from yacc
+/// grammars). Prerequisites that aren't targets but can be made this way are added as
+/// targets. Names matching one of the makefile's own `pattern_rules` are left alone, since
+/// those rules take precedence.
+pub fn infer_implicit_outputs(
+    targets: &mut Vec<Target>,
+    pattern_rules: &[PatternRule],
+    dir: &Path,
+) {
+    let mut known: HashSet<String> = targets.iter().map(|t| t.name.clone()).collect();
+    let listed: HashSet<String> = targets
+        .iter()
+        .flat_map(|t| t.prerequisites.iter().chain(&t.order_only))
+        .cloned()
+        .collect();
+    let exists = |name: &str, known: &HashSet<String>| {
+        known.contains(name) || listed.contains(name) || dir.join(name).exists()
+    };
+    let find_rule = |name: &str, known: &HashSet<String>| {
+        if PatternRule::is_pattern(name)
+            || pattern_rules.iter().any(|r| pattern_stem(&r.target, name).is_some())
+        {
+            return None;
+        }
+        implicit::RULES.iter().find_map(|rule| {
+            let source = rule.source_for(name)?;
+            let chained = || {
+                implicit::RULES
+                    .iter()
+                    .filter_map(|r| r.source_for(&source))
+                    .any(|s| exists(&s, known))
+            };
+            if exists(&source, known) || chained() {
+                Some((rule, source))
+            } else {
+                None
+            }
+        })
+    };
+
+    // prerequisites are checked in the order they're listed
+    let mut queue: Vec<String> = targets
+        .iter()
+        .flat_map(|t| t.prerequisites.iter().chain(&t.order_only))
+        .rev()
+        .cloned()
+        .collect();
+
+    // targets without a recipe, which make looks for a built-in rule for
+    for target in targets.iter_mut() {
+        if target.phony
+            || !target.recipe.is_empty()
+            || target.output.iter().flatten().next().is_some()
+        {
+            continue;
+        }
+        if let Some((rule, source)) = find_rule(&target.name, &known) {
+            if !target.prerequisites.contains(&source) {
+                target.prerequisites.insert(0, source.clone());
+            }
+            let recipe = rule.recipe_for(&target.name, &source, &target.prerequisites);
+            target.recipe = recipe.iter().map(|line| RecipeLine::parse(line)).collect();
+            target.output = Some(vec![target.name.clone()]);
+            queue.push(source);
+        }
+    }
+
+    // prerequisites that aren't targets, but that a built-in rule can make
+    while let Some(name) = queue.pop() {
+        if known.contains(&name) {
+            continue;
+        }
+        let (rule, source) = match find_rule(&name, &known) {
+            Some(found) => found,
+            None => continue,
+        };
+        let mut target = Target::new(name.clone());
+        target.prerequisites = vec![source.clone()];
+        target.recipe = rule
+            .recipe_for(&name, &source, &target.prerequisites)
+            .iter()
+            .map(|line| RecipeLine::parse(line))
+            .collect();
+        target.output = Some(vec![name.clone()]);
+        targets.push(target);
+        known.insert(name);
+        queue.push(source);
+    }
+}
+
+/// Classify each target's prerequisites as another target, a file, or unknown. Files are
+/// looked for relative to `dir`, and names matching any of the `assume_existing` patterns
+/// are treated as files even if they don't exist yet (e.g. generated headers).
+pub fn resolve_prerequisites(targets: &mut [Target], dir: &Path, assume_existing: &[Regex]) {
+    let names: HashSet<String> = targets.iter().map(|t| t.name.clone()).collect();
+
+    for target in targets.iter_mut() {
+        for prereq in &target.prerequisites {
+            let kind = if names.contains(prereq) {
+                PrereqKind::Target
+            } else if assume_existing.iter().any(|re| re.is_match(prereq))
+                || dir.join(prereq).exists()
+            {
+                PrereqKind::File
+            } else {
+                PrereqKind::Unknown
+            };
+            target.resolved_prerequisites.insert(prereq.clone(), kind);
+        }
+    }
+}
+
+/// Replace prerequisites and outputs that don't exist relative to `dir` with the first path
+/// found for them through the search paths, like make does when it looks for a file. Names
+/// of other targets are left alone, since make only searches for files it can't build.
+pub fn resolve_search_paths(targets: &mut [Target], dir: &Path, search_paths: &[SearchPath]) {
+    let names: HashSet<String> = targets.iter().map(|t| t.name.clone()).collect();
+    let resolve = |name: &mut String| {
+        if names.contains(name.as_str()) || Path::new(name.as_str()).is_absolute() {
+            return;
+        }
+        if dir.join(name.as_str()).exists() {
+            return;
+        }
+        let found = search_paths
+            .iter()
+            .filter(|p| p.applies_to(name))
+            .flat_map(|p| &p.directories)
+            .map(|d| format!("{}/{}", d.trim_end_matches('/'), name))
+            .find(|path| dir.join(path).exists());
+        if let Some(path) = found {
+            *name = path;
+        }
+    };
+
+    for target in targets.iter_mut() {
+        target.prerequisites.iter_mut().for_each(resolve);
+        target.output.iter_mut().flatten().for_each(resolve);
+    }
+}
+
+/// Convert a shell glob using `*`, `?`, or `[...]` into an anchored regex.
+/// Returns `None` if the pattern doesn't contain any glob characters.
+pub fn glob_to_regex(glob: &str) -> Option<Regex> {
+    if !glob.contains(['*', '?', '[']) {
+        return None;
+    }
+
+    glob_pattern(glob)
+}
+
+/// Convert a shell glob into an anchored regex, which matches the glob exactly if it doesn't
+/// contain any glob characters. Returns `None` if the glob is invalid.
+pub fn glob_pattern(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            '[' | ']' => pattern.push(c),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern).ok()
+}
+
+/// Strip the parts of a path that don't change what it refers to
+fn normalize(path: &str) -> String {
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    path.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn target(name: &str, prerequisites: &[&str], output: &[&str]) -> Target {
+        let mut target = Target::new(name.to_string());
+        target.prerequisites = prerequisites.iter().map(|p| p.to_string()).collect();
+        if !output.is_empty() {
+            target.output = Some(output.iter().map(|o| o.to_string()).collect());
+        }
+        target
+    }
+
+    #[test]
+    fn flags_outputs_that_clean_does_not_remove() {
+        let mut clean = target("clean", &[], &[]);
+        clean.removes = vec!["build".to_string(), "*.log".to_string()];
+        let targets = [
+            target("app", &[], &["build/app"]),
+            target("report", &[], &["test.log"]),
+            target("dist", &[], &["dist/app.tar.gz"]),
+            clean,
+        ];
+
+        let report = clean_gaps(&targets);
+        assert_eq!(report.removed, ["*.log", "build"]);
+        assert_eq!(
+            report.gaps,
+            [CleanGap { target: "dist".to_string(), output: "dist/app.tar.gz".to_string() }]
+        );
+    }
+
+    fn names(targets: &[Target]) -> Vec<&str> {
+        targets.iter().map(|t| t.name.as_str()).collect()
+    }
+
+    #[test]
+    fn restricts_targets_to_the_subtrees_of_goals() {
+        let mut all = target("all", &["app", "docs"], &[]);
+        all.delegates_to = vec!["install".to_string()];
+        let targets = vec![
+            all,
+            target("app", &["main.o"], &["app"]),
+            target("main.o", &["main.c"], &["main.o"]),
+            target("docs", &[], &["docs/index.html"]),
+            target("install", &[], &[]),
+            target("clean", &[], &[]),
+        ];
+
+        let reached = reachable_from(targets.clone(), &["app".to_string()]);
+        assert_eq!(names(&reached), ["app", "main.o"]);
+
+        let reached = reachable_from(targets, &["all".to_string()]);
+        assert_eq!(names(&reached), ["all", "app", "main.o", "docs", "install"]);
+    }
+
+    #[test]
+    fn suggests_undeclared_targets_without_outputs_as_phony() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("VERSION"), "1.0").unwrap();
+        let targets = [
+            target("all", &["app"], &[]),
+            target("app", &["main.c"], &["app"]),
+            target("test", &["app"], &[]),
+            target("lint", &[], &[]),
+            target("config.h", &[], &[]),
+            target("VERSION", &[], &[]),
+        ];
+
+        let suggested = suggest_phony(&targets, &["all".to_string()], dir.path());
+        assert_eq!(suggested, ["test", "lint"]);
+    }
+
+    #[test]
+    fn classifies_assumed_prerequisites_as_files() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("main.c"), "int main() {}").unwrap();
+        let mut targets = [
+            target("app", &["main.o", "main.c", "gen/version.h", "missing.h"], &["app"]),
+            target("main.o", &[], &["main.o"]),
+        ];
+
+        let assumed = [glob_pattern("gen/*.h").unwrap()];
+        resolve_prerequisites(&mut targets, dir.path(), &assumed);
+
+        let resolved = &targets[0].resolved_prerequisites;
+        assert_eq!(resolved["main.o"], PrereqKind::Target);
+        assert_eq!(resolved["main.c"], PrereqKind::File);
+        assert_eq!(resolved["gen/version.h"], PrereqKind::File);
+        assert_eq!(resolved["missing.h"], PrereqKind::Unknown);
+    }
+
+    #[test]
+    fn attributes_variable_references_to_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let makefile = dir.path().join("Makefile");
+        let text = "BINDIR = bin\nSRC = main.c\n\
+            app: $(SRC)\n\tgcc -o $(BINDIR)/app $(SRC)\n\
+            install: app\n\tcp app /usr/local/$(BINDIR)/app\n\
+            $(BINDIR)/tool:\n\ttouch $@\n\
+            clean:\n\trm -rf out\n";
+        std::fs::write(&makefile, text).unwrap();
+        let targets = crate::parser::Parser::new().parse_file(&makefile, false).unwrap();
+
+        let usage = variable_usage(&targets, "BINDIR");
+        let used: Vec<(&str, Vec<&str>)> = usage
+            .iter()
+            .map(|u| (u.target.as_str(), u.used_in.iter().map(|s| s.as_str()).collect()))
+            .collect();
+        assert_eq!(
+            used,
+            [("app", vec!["outputs"]), ("install", vec!["outputs"]), ("bin/tool", vec!["name"])]
+        );
+
+        let usage = variable_usage(&targets, "SRC");
+        assert_eq!(usage.len(), 1);
+        assert_eq!(usage[0].used_in, ["prerequisites"]);
+    }
+
+    #[test]
+    fn counts_distinct_artifacts_and_their_size() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("app"), [0u8; 100]).unwrap();
+        std::fs::write(dir.path().join("app.map"), [0u8; 20]).unwrap();
+        let targets = [
+            target("app", &[], &["app", "app.map"]),
+            target("install", &[], &["./app"]),
+            target("docs", &[], &["docs/index.html"]),
+        ];
+
+        let report = artifact_report(&targets, dir.path());
+        assert_eq!(
+            report,
+            ArtifactReport {
+                count: 3,
+                total_size: 120,
+                missing: vec!["docs/index.html".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn plans_a_diamond_with_prerequisites_first() {
+        let targets = [
+            target("app", &["lib.a", "main.o"], &[]),
+            target("lib.a", &["util.o", "config.h"], &[]),
+            target("main.o", &["main.c", "config.h"], &[]),
+            target("util.o", &["util.c"], &[]),
+            target("config.h", &["config.in"], &[]),
+        ];
+
+        let plan = build_plan(&targets, "app").unwrap();
+        assert_eq!(plan, ["util.o", "config.h", "lib.a", "main.o", "app"]);
+
+        let position = |name: &str| plan.iter().position(|p| p == name).unwrap();
+        for target in &targets {
+            for prereq in target.prerequisites.iter().filter(|p| plan.contains(p)) {
+                assert!(position(prereq) < position(&target.name));
+            }
+        }
+    }
+
+    #[test]
+    fn reports_dependency_cycles() {
+        let targets = [target("a", &["b"], &[]), target("b", &["a"], &[])];
+        assert_eq!(build_plan(&targets, "a"), Err("Dependency cycle: a -> b -> a".to_string()));
+    }
+
+    #[test]
+    fn refuses_to_plan_unknown_goals() {
+        let targets = [target("a", &[], &[])];
+        assert_eq!(build_plan(&targets, "b"), Err("No target named 'b'".to_string()));
+    }
+
+    #[test]
+    fn reports_environment_variables_read_by_recipes() {
+        let dir = tempfile::tempdir().unwrap();
+        let makefile = dir.path().join("Makefile");
+        let text = "PREFIX = /usr\n\
+            install:\n\tcp app $$HOME/bin/app\n\techo $${USER} $(PREFIX) $$HOME\n\
+            build:\n\tgcc -o app main.c\n";
+        std::fs::write(&makefile, text).unwrap();
+        let targets = crate::parser::Parser::new().parse_file(&makefile, false).unwrap();
+
+        assert_eq!(
+            env_usage(&targets),
+            [EnvUsage {
+                target: "install".to_string(),
+                variables: vec!["HOME".to_string(), "USER".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn flags_outputs_produced_by_several_targets() {
+        let targets = [
+            target("app", &[], &["build/app"]),
+            target("release", &[], &["./build/app", "build/app.sig"]),
+            target("debug", &[], &["build/app.debug"]),
+        ];
+
+        assert_eq!(
+            output_collisions(&targets),
+            [OutputCollision {
+                output: "build/app".to_string(),
+                targets: vec!["app".to_string(), "release".to_string()],
+            }]
+        );
+        assert!(output_collisions(&targets[1..]).is_empty());
+    }
+
+    #[test]
+    fn records_the_provenance_of_outputs() {
+        let dir = tempfile::tempdir().unwrap();
+        let makefile = dir.path().join("Makefile");
+        let text = "CC = gcc\n\n\
+            app: main.o util.o\n\t@echo linking\n\t$(CC) -o app main.o util.o\n";
+        std::fs::write(&makefile, text).unwrap();
+        let targets = crate::parser::Parser::new().parse_file(&makefile, false).unwrap();
+
+        let records = provenance(&targets);
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.output, "app");
+        assert_eq!(record.target, "app");
+        assert_eq!(record.command, "gcc -o app main.o util.o");
+        assert_eq!(record.inputs, ["main.o", "util.o"]);
+        assert_eq!(record.file, makefile.display().to_string());
+        // the column is where the output is in the line as written
+        assert_eq!((record.line, record.column), (5, 11));
+        assert_eq!(record.via, "-o");
+    }
+
+    #[test]
+    fn groups_outputs_by_extension() {
+        let targets = [
+            target("app", &[], &["bin/app", "obj/main.o", "obj/util.o"]),
+            target("libs", &[], &["lib/libapp.a", "lib/libapp.so", "./obj/util.o"]),
+            target("docs", &[], &["docs"]),
+        ];
+
+        let groups = outputs_by_extension(&targets);
+        let summary: Vec<(&str, usize, Vec<&str>)> = groups
+            .iter()
+            .map(|(ext, g)| (ext.as_str(), g.count, g.outputs.iter().map(|o| o.as_str()).collect()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (".a", 1, vec!["lib/libapp.a"]),
+                (".o", 2, vec!["obj/main.o", "obj/util.o"]),
+                (".so", 1, vec!["lib/libapp.so"]),
+                ("none", 2, vec!["bin/app", "docs"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_annotations_that_disagree_with_the_recipe() {
+        let dir = tempfile::tempdir().unwrap();
+        let makefile = dir.path().join("Makefile");
+        let text = "stale:\n\t# Output: build/app\n\tgcc -o bin/app main.c\n\
+            current:\n\t# Output: bin/tool\n\tgcc -o bin/tool tool.c\n\
+            annotated:\n\t# Output: dist\n\t./package.sh\n";
+        std::fs::write(&makefile, text).unwrap();
+        let targets = crate::parser::Parser::new().parse_file(&makefile, false).unwrap();
+
+        assert_eq!(
+            annotation_mismatches(&targets),
+            [AnnotationMismatch {
+                target: "stale".to_string(),
+                annotated: vec!["build/app".to_string()],
+                detected: vec!["bin/app".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn infers_the_outputs_of_builtin_rules() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("foo.c"), "").unwrap();
+        let mut targets = vec![target("foo", &["foo.o"], &[])];
+        infer_implicit_outputs(&mut targets, &[], dir.path());
+        assert_eq!(names(&targets), ["foo", "foo.o"]);
+        assert_eq!(targets[0].output, Some(vec!["foo".to_string()]));
+        assert_eq!(targets[1].prerequisites, ["foo.c"]);
+        assert_eq!(targets[1].output, Some(vec!["foo.o".to_string()]));
+        assert!(targets[1].recipe[0].command.ends_with("-c -o foo.o foo.c"));
+
+        // the makefile's own pattern rules take precedence
+        let rule = PatternRule {
+            target: "%.o".to_string(),
+            prerequisites: vec!["%.c".to_string()],
+            order_only: Vec::new(),
+            output: Vec::new(),
+            recipe: Vec::new(),
+            file: None,
+        };
+        let mut targets = vec![target("foo", &["foo.o"], &[])];
+        infer_implicit_outputs(&mut targets, &[rule], dir.path());
+        assert_eq!(names(&targets), ["foo"]);
+    }
+}
@@ -0,0 +1,578 @@
+//!
+//! Syntax specific to BSD make (bmake): directives, conditional expressions, and the
+//! modifiers that can follow a variable's name, like `${SRCS:S/.c/.o/}`
+//!
+
+use regex::Regex;
+
+use crate::functions::{self, map_words, suffix_start};
+
+/// Directives that start with a `.`, which can be followed by whitespace (`.  if`)
+const DIRECTIVES: &[&str] = &[
+    "include", "-include", "sinclude", "dinclude", "if", "ifdef", "ifndef", "ifmake", "ifnmake",
+    "elif", "elifdef", "elifndef", "elifmake", "elifnmake", "else", "endif", "for", "endfor",
+    "undef", "export", "export-env", "unexport", "error", "warning", "info",
+];
+
+/// Split a directive line like `.include "config.mk"` into its keyword and the rest of the
+/// line, if it's a directive
+pub fn directive(line: &str) -> Option<(&str, &str)> {
+    let rest = line.strip_prefix('.')?.trim_start();
+    let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+    let keyword = &rest[..end];
+    if DIRECTIVES.contains(&keyword) {
+        Some((keyword, rest[end..].trim()))
+    } else {
+        None
+    }
+}
+
+/// Get the automatic variable that a BSD local variable like `.TARGET` is another name for
+pub fn local_variable(name: &str) -> Option<&'static str> {
+    match name {
+        ".TARGET" => Some("@"),
+        ".ALLSRC" | ">" => Some("^"),
+        ".IMPSRC" => Some("<"),
+        ".OODATE" => Some("?"),
+        ".PREFIX" => Some("*"),
+        _ => None,
+    }
+}
+
+/// A parsed conditional expression, with its values still unexpanded
+#[derive(Clone, Debug, PartialEq)]
+pub enum Condition {
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    // a function like `defined(NAME)`, including a bare word given to the directive's default
+    Call(String, String),
+    // a value on its own, which is true if it's a non-zero number or a non-empty string
+    Value(String),
+    // two values compared with `==`, `!=`, `<`, `<=`, `>`, or `>=`
+    Compare(String, String, String),
+}
+
+/// Parse the expression of a conditional directive. A bare word is the argument of
+/// `default`, which is the function the directive uses, like `defined` for `.if`.
+pub fn parse_condition(text: &str, default: &str) -> Result<Condition, String> {
+    let mut input = text.trim();
+    let condition = parse_or(&mut input, default)?;
+    if !input.trim().is_empty() {
+        return Err(format!("Unexpected '{}' in condition '{}'", input.trim(), text.trim()));
+    }
+    Ok(condition)
+}
+
+fn parse_or(input: &mut &str, default: &str) -> Result<Condition, String> {
+    let mut left = parse_and(input, default)?;
+    while let Some(rest) = input.trim_start().strip_prefix("||") {
+        *input = rest;
+        left = Condition::Or(Box::new(left), Box::new(parse_and(input, default)?));
+    }
+    Ok(left)
+}
+
+fn parse_and(input: &mut &str, default: &str) -> Result<Condition, String> {
+    let mut left = parse_unary(input, default)?;
+    while let Some(rest) = input.trim_start().strip_prefix("&&") {
+        *input = rest;
+        left = Condition::And(Box::new(left), Box::new(parse_unary(input, default)?));
+    }
+    Ok(left)
+}
+
+fn parse_unary(input: &mut &str, default: &str) -> Result<Condition, String> {
+    *input = input.trim_start();
+    if let Some(rest) = input.strip_prefix('!') {
+        *input = rest;
+        return Ok(Condition::Not(Box::new(parse_unary(input, default)?)));
+    }
+    if let Some(rest) = input.strip_prefix('(') {
+        *input = rest;
+        let inner = parse_or(input, default)?;
+        *input = input
+            .trim_start()
+            .strip_prefix(')')
+            .ok_or_else(|| "Missing ')' in condition".to_string())?;
+        return Ok(inner);
+    }
+
+    let word = take_word(input)?;
+    if word.is_empty() {
+        return Err("Missing value in condition".to_string());
+    }
+
+    // a function call, like `defined(NAME)` or `empty(NAME:M*.c)`
+    if let Some(rest) = input.strip_prefix('(') {
+        let end = closing_paren(rest).ok_or_else(|| format!("Missing ')' after '{}'", word))?;
+        *input = &rest[end + 1..];
+        return Ok(Condition::Call(word, rest[..end].trim().to_string()));
+    }
+
+    // a comparison between two values
+    *input = input.trim_start();
+    for op in ["==", "!=", "<=", ">=", "<", ">"] {
+        if let Some(rest) = input.strip_prefix(op) {
+            *input = rest.trim_start();
+            let right = take_word(input)?;
+            return Ok(Condition::Compare(word, op.to_string(), right));
+        }
+    }
+
+    // a bare word is an argument to the directive's function, rather than a value
+    let quoted = word.starts_with('"');
+    if !quoted && !word.contains('$') && !word.starts_with(|c: char| c.is_ascii_digit()) {
+        return Ok(Condition::Call(default.to_string(), word));
+    }
+    Ok(Condition::Value(word))
+}
+
+/// Take the next value of a condition: a quoted string, or a word that can contain variable
+/// references
+fn take_word(input: &mut &str) -> Result<String, String> {
+    *input = input.trim_start();
+    if let Some(rest) = input.strip_prefix('"') {
+        let mut escaped = false;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '"' if !escaped => {
+                    *input = &rest[i + 1..];
+                    // the quotes are kept so that the value isn't taken as a function's argument
+                    return Ok(format!("\"{}\"", &rest[..i]));
+                }
+                '\\' => escaped = !escaped,
+                _ => escaped = false,
+            }
+        }
+        return Err("Unterminated string in condition".to_string());
+    }
+
+    let mut depth = 0;
+    let mut end = input.len();
+    for (i, c) in input.char_indices() {
+        match c {
+            '{' | '(' if depth > 0 || input[..i].ends_with('$') => depth += 1,
+            '}' | ')' if depth > 0 => depth -= 1,
+            _ if depth > 0 => (),
+            c if c.is_whitespace() || "()!=<>&|".contains(c) => {
+                end = i;
+                break;
+            }
+            _ => (),
+        }
+    }
+    let word = &input[..end];
+    *input = &input[end..];
+    Ok(word.to_string())
+}
+
+/// Get the index of the `)` that closes a `(` just before the start of `text`
+fn closing_paren(text: &str) -> Option<usize> {
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' if depth == 0 => return Some(i),
+            ')' | '}' => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Split the inside of a reference like `SRCS:M*.c:S/.c/.o/` into the variable name and its
+/// modifiers, if it has any. The `:` has to be outside of any nested references.
+pub fn split_modifiers(inner: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ':' if depth == 0 => {
+                let name = &inner[..i];
+                if !name.contains('$') && name.contains(char::is_whitespace) {
+                    return None;
+                }
+                return Some((name, &inner[i + 1..]));
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Apply a chain of modifiers to the value of a variable, or `None` if it's undefined.
+/// `:U` and `:D` give an undefined variable a value, and the other modifiers only apply to
+/// a defined one. Most modifiers work on each whitespace-separated word.
+pub fn apply_modifiers(
+    mut value: Option<String>,
+    modifiers: &str,
+) -> Result<Option<String>, String> {
+    let mut rest = modifiers;
+    while !rest.is_empty() {
+        let modifier = rest;
+        let first = modifier.chars().next().unwrap();
+        let (result, len) = match first {
+            'U' | 'D' => {
+                let len = argument_end(modifier);
+                let text = unescape(&modifier[1..len]);
+                let defined = value.is_some();
+                let value = match first {
+                    'U' => value.clone().or(Some(text)),
+                    _ if defined => Some(text),
+                    _ => Some(String::new()),
+                };
+                (value, len)
+            }
+            // the `old=new` form takes the rest of the modifiers, like make's substitution
+            // reference
+            _ if !modifier.starts_with(['S', 'C']) && modifier.contains('=') => {
+                let (from, to) = modifier.split_once('=').unwrap();
+                let (mut from, mut to) = (from.to_string(), to.to_string());
+                if !from.contains('%') {
+                    from.insert(0, '%');
+                    to.insert(0, '%');
+                }
+                let value = match value {
+                    Some(value) => Some(functions::call("patsubst", &[from, to, value])?),
+                    None => None,
+                };
+                (value, modifier.len())
+            }
+            _ => {
+                let (apply, len) = parse_modifier(modifier)?;
+                (value.map(|v| apply(&v)).transpose()?, len)
+            }
+        };
+        value = result;
+
+        rest = &modifier[len..];
+        if let Some(next) = rest.strip_prefix(':') {
+            rest = next;
+        } else if !rest.is_empty() {
+            return Err(format!("Unexpected '{}' after modifier", rest));
+        }
+    }
+    Ok(value)
+}
+
+type Modifier = Box<dyn Fn(&str) -> Result<String, String>>;
+
+/// Parse the modifier at the start of `text` into a function applying it, along with the
+/// number of bytes it spans
+fn parse_modifier(text: &str) -> Result<(Modifier, usize), String> {
+    let first = text.chars().next().unwrap();
+    let modifier: Modifier = match first {
+        'S' | 'C' => {
+            let (old, new, flags, len) = split_replacement(text)?;
+            let global = flags.contains('g');
+            let pattern = match first {
+                'S' => substitution_regex(&old),
+                _ => Regex::new(&old).map_err(|e| format!("Invalid regex in :C modifier: {}", e))?,
+            };
+            let new = replacement_template(&new, first == 'S');
+            let apply = move |value: &str| {
+                Ok(map_words(value, |word| match global {
+                    true => pattern.replace_all(word, new.as_str()).to_string(),
+                    false => pattern.replace(word, new.as_str()).to_string(),
+                }))
+            };
+            return Ok((Box::new(apply), len));
+        }
+        'M' | 'N' => {
+            let len = argument_end(text);
+            let glob = unescape(&text[1..len]);
+            let pattern = word_pattern(&glob)
+                .ok_or_else(|| format!("Invalid pattern in :{} modifier", first))?;
+            let keep = first == 'M';
+            let apply = move |value: &str| {
+                let words: Vec<&str> =
+                    value.split_whitespace().filter(|w| pattern.is_match(w) == keep).collect();
+                Ok(words.join(" "))
+            };
+            return Ok((Box::new(apply), len));
+        }
+        _ => {
+            let len = argument_end(text);
+            match &text[..len] {
+                "H" => Box::new(|value: &str| {
+                    Ok(map_words(value, |word| match word.rfind('/') {
+                        Some(0) => "/".to_string(),
+                        Some(idx) => word[..idx].to_string(),
+                        None => ".".to_string(),
+                    }))
+                }),
+                "T" => Box::new(|value: &str| {
+                    Ok(map_words(value, |word| match word.rfind('/') {
+                        Some(idx) => word[idx + 1..].to_string(),
+                        None => word.to_string(),
+                    }))
+                }),
+                "E" => Box::new(|value: &str| {
+                    let suffixes: Vec<&str> = value
+                        .split_whitespace()
+                        .filter_map(|word| suffix_start(word).map(|idx| &word[idx + 1..]))
+                        .collect();
+                    Ok(suffixes.join(" "))
+                }),
+                "R" => Box::new(|value: &str| {
+                    Ok(map_words(value, |word| match suffix_start(word) {
+                        Some(idx) => word[..idx].to_string(),
+                        None => word.to_string(),
+                    }))
+                }),
+                "tl" => Box::new(|value: &str| Ok(value.to_lowercase())),
+                "tu" => Box::new(|value: &str| Ok(value.to_uppercase())),
+                "u" => Box::new(|value: &str| {
+                    let mut words: Vec<&str> = value.split_whitespace().collect();
+                    words.dedup();
+                    Ok(words.join(" "))
+                }),
+                "O" | "Or" => {
+                    let reverse = len == 2;
+                    Box::new(move |value: &str| {
+                        let mut words: Vec<&str> = value.split_whitespace().collect();
+                        words.sort_unstable();
+                        if reverse {
+                            words.reverse();
+                        }
+                        Ok(words.join(" "))
+                    })
+                }
+                "Q" => Box::new(|value: &str| {
+                    let special = |c: char| !c.is_alphanumeric() && !"-_./,+=@%:".contains(c);
+                    Ok(value.chars().fold(String::new(), |mut quoted, c| {
+                        if special(c) {
+                            quoted.push('\\');
+                        }
+                        quoted.push(c);
+                        quoted
+                    }))
+                }),
+                other => return Err(format!("Unsupported modifier ':{}'", other)),
+            }
+        }
+    };
+    Ok((modifier, argument_end(text)))
+}
+
+/// Split a `:S` or `:C` modifier into its old and new text and its flags, along with the
+/// number of bytes it spans. The delimiter is whatever character follows the `S` or `C`.
+fn split_replacement(text: &str) -> Result<(String, String, String, usize), String> {
+    let mut chars = text.char_indices().skip(1);
+    let delim = match chars.next() {
+        Some((_, c)) if c != ':' => c,
+        _ => return Err(format!("Missing delimiter in modifier ':{}'", text)),
+    };
+
+    let mut parts = vec![String::new()];
+    let mut escaped = false;
+    let mut end = None;
+    for (i, c) in chars {
+        if escaped {
+            // only an escaped delimiter loses its backslash, so regexes keep theirs
+            if c != delim {
+                parts.last_mut().unwrap().push('\\');
+            }
+            parts.last_mut().unwrap().push(c);
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == delim {
+            if parts.len() == 2 {
+                end = Some(i + c.len_utf8());
+                break;
+            }
+            parts.push(String::new());
+        } else {
+            parts.last_mut().unwrap().push(c);
+        }
+    }
+
+    let end = end.ok_or_else(|| format!("Unterminated modifier ':{}'", text))?;
+    let flags_len = text[end..].find(':').unwrap_or(text.len() - end);
+    let flags = text[end..end + flags_len].to_string();
+    let new = parts.pop().unwrap();
+    let old = parts.pop().unwrap();
+    Ok((old, new, flags, end + flags_len))
+}
+
+/// Build the regex for the old text of a `:S` modifier, where a leading `^` and trailing `$`
+/// anchor it to the start and end of each word
+fn substitution_regex(old: &str) -> Regex {
+    let (start, old) = match old.strip_prefix('^') {
+        Some(old) => ("^", old),
+        None => ("", old),
+    };
+    let (old, end) = match old.strip_suffix('$') {
+        Some(old) => (old, "$"),
+        None => (old, ""),
+    };
+    Regex::new(&format!("{}{}{}", start, regex::escape(old), end)).unwrap()
+}
+
+/// Convert the new text of a `:S` or `:C` modifier into a regex replacement, where `&` is the
+/// matched text and (for `:C`) `\1` to `\9` are the matched groups
+fn replacement_template(new: &str, literal: bool) -> String {
+    let mut template = String::new();
+    let mut chars = new.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => template.push_str("${0}"),
+            '\\' => match chars.next() {
+                Some(n) if n.is_ascii_digit() && !literal => {
+                    template.push_str(&format!("${{{}}}", n))
+                }
+                Some('$') => template.push_str("$$"),
+                Some(n) => template.push(n),
+                None => template.push('\\'),
+            },
+            '$' => template.push_str("$$"),
+            _ => template.push(c),
+        }
+    }
+    template
+}
+
+/// Convert the pattern of a `:M` or `:N` modifier into an anchored regex. Unlike a file glob,
+/// `*` and `?` can match a `/`.
+fn word_pattern(glob: &str) -> Option<Regex> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            '[' | ']' => pattern.push(c),
+            '\\' => pattern.push_str(&regex::escape(&chars.next()?.to_string())),
+            _ => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+
+    Regex::new(&pattern).ok()
+}
+
+/// Get the end of a modifier that runs until the next unescaped `:`
+fn argument_end(text: &str) -> usize {
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            ':' if !escaped => return i,
+            '\\' => escaped = !escaped,
+            _ => escaped = false,
+        }
+    }
+    text.len()
+}
+
+/// Remove the backslashes escaping `:` in a modifier's argument
+fn unescape(text: &str) -> String {
+    text.replace("\\:", ":")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn modify(value: &str, modifiers: &str) -> String {
+        apply_modifiers(Some(value.to_string()), modifiers).unwrap().unwrap()
+    }
+
+    #[test]
+    fn finds_directives() {
+        assert_eq!(directive(".include \"config.mk\""), Some(("include", "\"config.mk\"")));
+        assert_eq!(directive(".  if defined(DEBUG)"), Some(("if", "defined(DEBUG)")));
+        assert_eq!(directive(".PHONY: all"), None);
+    }
+
+    #[test]
+    fn parses_conditions() {
+        assert_eq!(
+            parse_condition("!defined(A) || ${CC} == \"gcc\"", "defined").unwrap(),
+            Condition::Or(
+                Box::new(Condition::Not(Box::new(Condition::Call(
+                    "defined".to_string(),
+                    "A".to_string()
+                )))),
+                Box::new(Condition::Compare(
+                    "${CC}".to_string(),
+                    "==".to_string(),
+                    "\"gcc\"".to_string()
+                )),
+            )
+        );
+        // a bare word is given to the directive's function
+        assert_eq!(
+            parse_condition("install", "make").unwrap(),
+            Condition::Call("make".to_string(), "install".to_string())
+        );
+        assert!(parse_condition("defined(A", "defined").is_err());
+        assert!(parse_condition("(A && B", "defined").is_err());
+    }
+
+    #[test]
+    fn splits_modifiers_from_names() {
+        assert_eq!(split_modifiers("SRCS:M*.c:S/.c/.o/"), Some(("SRCS", "M*.c:S/.c/.o/")));
+        assert_eq!(split_modifiers("${A:T}"), None);
+        assert_eq!(split_modifiers("SRCS"), None);
+    }
+
+    #[test]
+    fn substitutes_text() {
+        assert_eq!(modify("a.c b.c.c", "S/.c/.o/"), "a.o b.o.c");
+        assert_eq!(modify("a.c b.c.c", "S/.c/.o/g"), "a.o b.o.o");
+        assert_eq!(modify("lib/a.c", "S/^lib/src/"), "src/a.c");
+        assert_eq!(modify("a.c", "S,a,&&,"), "aa.c");
+        assert_eq!(modify("foo-1.2 bar-3", "C/-([0-9.]+)$/ v\\1/"), "foo v1.2 bar v3");
+        assert_eq!(modify("main.c util.c", "c=o"), "main.o util.o");
+        assert_eq!(modify("main.c util.c", "%.c=obj/%.o"), "obj/main.o obj/util.o");
+    }
+
+    #[test]
+    fn filters_words() {
+        assert_eq!(modify("a.c b.h c.c", "M*.c"), "a.c c.c");
+        assert_eq!(modify("a.c b.h c.c", "N*.c"), "b.h");
+        assert_eq!(modify("src/a.c x:y", "M*\\:*"), "x:y");
+    }
+
+    #[test]
+    fn splits_paths() {
+        assert_eq!(modify("src/lib/a.c b.c /c", "H"), "src/lib . /");
+        assert_eq!(modify("src/lib/a.c b.c", "T"), "a.c b.c");
+        assert_eq!(modify("src/a.c b.tar.gz README", "E"), "c gz");
+        assert_eq!(modify("src/a.c b.tar.gz README", "R"), "src/a b.tar README");
+    }
+
+    #[test]
+    fn changes_case_order_and_quoting() {
+        assert_eq!(modify("Main.C", "tl"), "main.c");
+        assert_eq!(modify("Main.C", "tu"), "MAIN.C");
+        assert_eq!(modify("a a b a", "u"), "a b a");
+        assert_eq!(modify("c a b", "O"), "a b c");
+        assert_eq!(modify("c a b", "Or"), "c b a");
+        assert_eq!(modify("it's $x", "Q"), "it\\'s\\ \\$x");
+        assert_eq!(modify("src/b.c src/a.c", "T:O:tu"), "A.C B.C");
+    }
+
+    #[test]
+    fn gives_values_based_on_definedness() {
+        assert_eq!(apply_modifiers(None, "Udefault").unwrap(), Some("default".to_string()));
+        assert_eq!(modify("set", "Udefault"), "set");
+        assert_eq!(apply_modifiers(None, "Dyes").unwrap(), Some(String::new()));
+        assert_eq!(modify("set", "Dyes"), "yes");
+        // other modifiers leave an undefined variable undefined
+        assert_eq!(apply_modifiers(None, "T").unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_invalid_modifiers() {
+        assert_eq!(
+            apply_modifiers(Some("a".to_string()), "Z").unwrap_err(),
+            "Unsupported modifier ':Z'"
+        );
+        assert!(apply_modifiers(Some("a".to_string()), "S/a/b").is_err());
+        assert!(apply_modifiers(Some("a".to_string()), "C/(/b/").is_err());
+    }
+}
@@ -1,6 +1,6 @@
 //!
 //! Creates and applies filters to a vector of targets
-//! 
+//!
 
 use std::process::exit;
 use clap::Values;
@@ -12,49 +12,19 @@ pub fn filter_targets(
 	targets: Vec<Target>,
 	strict_mode: bool,
 	filters: Option<Values>,
-	includes: Option<Values>
-) -> Vec<Target> 
+	includes: Option<Values>,
+	depends_on: Option<Values>,
+	not_depends_on: Option<Values>,
+	depends_regex: bool,
+) -> Vec<Target>
 {
 	// make a list of all the filters
-	let filters: Option<Vec<Regex>> = match filters {
-		Some(filters) => {
-		    let res = filters.filter_map(|filter| {
-		    	match Regex::new(filter) {
-	                Ok(re) => Some(re),
-	                Err(e) => {
-	                    error!("Failed to apply user filter '{}': {}", filter, e);
-	                    if strict_mode {
-	                        exit(1);
-	                    } else {
-	                    	None
-	                    }
-	                }
-	            }
-	        }).collect();
-	        Some(res)
-		},
-		None => None
-	};
-
-	let includes: Option<Vec<Regex>> = match includes {
-		Some(includes) => {
-        	let res = includes.filter_map(|include| {
-        		match Regex::new(include) {
-	                Ok(re) => Some(re),
-	                Err(e) => {
-	                    error!("Failed to apply user include '{}' : {}", include, e);
-	                    if strict_mode {
-	                        exit(1);
-	                    } else {
-	                        None
-	                    }
-	                }
-	            }
-	        }).collect();
-	        Some(res)
-		},
-		None => None
-	};
+	let filters = compile_filters(filters, strict_mode, "filter", true);
+	let includes = compile_filters(includes, strict_mode, "include", true);
+
+	// prerequisite names are matched exactly unless regex matching was requested
+	let depends_on = compile_filters(depends_on, strict_mode, "depends-on", depends_regex);
+	let not_depends_on = compile_filters(not_depends_on, strict_mode, "not-depends-on", depends_regex);
 
 
 	// filter the targets based on the possible filters
@@ -62,7 +32,7 @@ pub fn filter_targets(
 		.filter(|target| {
 			if let Some(filters) = &filters {
 				// don't keep any target that matches a filter
-				!filters.iter().any(|re| re.is_match(&target.name)) 
+				!filters.iter().any(|re| re.is_match(&target.name))
 			} else { true }
 		})
 		.filter(|target| {
@@ -71,5 +41,98 @@ pub fn filter_targets(
 				includes.iter().any(|re| re.is_match(&target.name))
 			} else { true }
 		})
+		.filter(|target| {
+			if let Some(depends_on) = &depends_on {
+				// only keep targets with a prerequisite matching any dependency filter
+				depends_on.iter().any(|re| has_prerequisite(target, re))
+			} else { true }
+		})
+		.filter(|target| {
+			if let Some(not_depends_on) = &not_depends_on {
+				// don't keep any target with a prerequisite matching a dependency filter
+				!not_depends_on.iter().any(|re| has_prerequisite(target, re))
+			} else { true }
+		})
 		.collect()
-}
\ No newline at end of file
+}
+
+/// Compile a list of user-supplied filters. Non-regex filters are escaped and anchored so
+/// that they only match a whole name exactly.
+fn compile_filters(values: Option<Values>, strict_mode: bool, kind: &str, is_regex: bool) -> Option<Vec<Regex>> {
+	let values = values?;
+
+	let res = values.filter_map(|value| {
+		let pattern = if is_regex {
+			value.to_string()
+		} else {
+			format!("^{}$", regex::escape(value))
+		};
+
+		match Regex::new(&pattern) {
+			Ok(re) => Some(re),
+			Err(e) => {
+				error!("Failed to apply user {} '{}': {}", kind, value, e);
+				if strict_mode {
+					exit(1);
+				} else {
+					None
+				}
+			}
+		}
+	}).collect();
+
+	Some(res)
+}
+
+/// Check whether any of a target's prerequisites match a filter
+fn has_prerequisite(target: &Target, re: &Regex) -> bool {
+	target.prerequisites.iter().any(|prereq| re.is_match(prereq))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use clap::{App, Arg, ArgMatches};
+
+	fn targets() -> Vec<Target> {
+		let mut app = Target::new("app".to_string());
+		app.prerequisites = vec!["main.c".to_string(), "config.h".to_string()];
+		let mut docs = Target::new("docs".to_string());
+		docs.prerequisites = vec!["README.md".to_string()];
+		vec![app, docs]
+	}
+
+	fn matches(args: &[&str]) -> ArgMatches<'static> {
+		App::new("test")
+			.arg(Arg::with_name("name").long("name").takes_value(true).multiple(true))
+			.get_matches_from(std::iter::once("test").chain(args.iter().copied()))
+	}
+
+	fn names(targets: &[Target]) -> Vec<&str> {
+		targets.iter().map(|t| t.name.as_str()).collect()
+	}
+
+	#[test]
+	fn keeps_targets_depending_on_a_prerequisite() {
+		let matches = matches(&["--name", "config.h"]);
+		let filtered = filter_targets(targets(), true, None, None, matches.values_of("name"), None, false);
+		assert_eq!(names(&filtered), ["app"]);
+	}
+
+	#[test]
+	fn drops_targets_depending_on_a_prerequisite() {
+		let matches = matches(&["--name", "config.h"]);
+		let filtered = filter_targets(targets(), true, None, None, None, matches.values_of("name"), false);
+		assert_eq!(names(&filtered), ["docs"]);
+	}
+
+	#[test]
+	fn matches_prerequisites_exactly_unless_regex() {
+		let matches = matches(&["--name", r"\.h$"]);
+		let filtered = filter_targets(targets(), true, None, None, matches.values_of("name"), None, false);
+		assert!(filtered.is_empty());
+
+		let filtered = filter_targets(targets(), true, None, None, matches.values_of("name"), None, true);
+		assert_eq!(names(&filtered), ["app"]);
+	}
+}
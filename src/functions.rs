@@ -0,0 +1,239 @@
+//!
+//! Make's built-in text functions, which only depend on their expanded arguments
+//!
+
+/// Names of the functions that `call` can evaluate
+pub const NAMES: &[&str] = &[
+    "addprefix", "addsuffix", "basename", "dir", "filter", "filter-out", "firstword", "lastword",
+    "notdir", "patsubst", "sort", "subst", "suffix", "word", "wordlist", "words",
+];
+
+/// Evaluate a text function with its already expanded arguments. Commas past the last
+/// argument a function takes are part of that argument, like they are in make.
+pub fn call(name: &str, args: &[String]) -> Result<String, String> {
+    match name {
+        "subst" => {
+            let [from, to, text] = take_args::<3>(name, args)?;
+            if from.is_empty() {
+                return Ok(text);
+            }
+            Ok(text.replace(&from, &to))
+        }
+        "patsubst" => {
+            let [pattern, replacement, text] = take_args::<3>(name, args)?;
+            Ok(map_words(&text, |word| patsubst_word(pattern.trim(), replacement.trim(), word)))
+        }
+        "dir" => {
+            let [names] = take_args::<1>(name, args)?;
+            Ok(map_words(&names, |word| match word.rfind('/') {
+                Some(idx) => word[..idx + 1].to_string(),
+                None => "./".to_string(),
+            }))
+        }
+        "notdir" => {
+            let [names] = take_args::<1>(name, args)?;
+            Ok(map_words(&names, |word| match word.rfind('/') {
+                Some(idx) => word[idx + 1..].to_string(),
+                None => word.to_string(),
+            }))
+        }
+        "suffix" => {
+            let [names] = take_args::<1>(name, args)?;
+            let suffixes: Vec<&str> = names
+                .split_whitespace()
+                .filter_map(|word| suffix_start(word).map(|idx| &word[idx..]))
+                .collect();
+            Ok(suffixes.join(" "))
+        }
+        "basename" => {
+            let [names] = take_args::<1>(name, args)?;
+            Ok(map_words(&names, |word| match suffix_start(word) {
+                Some(idx) => word[..idx].to_string(),
+                None => word.to_string(),
+            }))
+        }
+        "addprefix" => {
+            let [prefix, names] = take_args::<2>(name, args)?;
+            Ok(map_words(&names, |word| format!("{}{}", prefix, word)))
+        }
+        "addsuffix" => {
+            let [suffix, names] = take_args::<2>(name, args)?;
+            Ok(map_words(&names, |word| format!("{}{}", word, suffix)))
+        }
+        "filter" | "filter-out" => {
+            let [patterns, text] = take_args::<2>(name, args)?;
+            let keep = name == "filter";
+            let matches =
+                |word: &str| patterns.split_whitespace().any(|p| matches_pattern(p, word));
+            let words: Vec<&str> = text.split_whitespace().filter(|w| matches(w) == keep).collect();
+            Ok(words.join(" "))
+        }
+        "sort" => {
+            let [list] = take_args::<1>(name, args)?;
+            let mut words: Vec<&str> = list.split_whitespace().collect();
+            words.sort_unstable();
+            words.dedup();
+            Ok(words.join(" "))
+        }
+        "word" => {
+            let [n, text] = take_args::<2>(name, args)?;
+            let n = word_index(name, &n, 1)?;
+            Ok(text.split_whitespace().nth(n - 1).unwrap_or_default().to_string())
+        }
+        "wordlist" => {
+            let [start, end, text] = take_args::<3>(name, args)?;
+            let start = word_index(name, &start, 1)?;
+            let end = word_index(name, &end, 0)?;
+            let count = (end + 1).saturating_sub(start);
+            let words: Vec<&str> = text.split_whitespace().skip(start - 1).take(count).collect();
+            Ok(words.join(" "))
+        }
+        "words" => {
+            let [text] = take_args::<1>(name, args)?;
+            Ok(text.split_whitespace().count().to_string())
+        }
+        "firstword" => {
+            let [names] = take_args::<1>(name, args)?;
+            Ok(names.split_whitespace().next().unwrap_or_default().to_string())
+        }
+        "lastword" => {
+            let [names] = take_args::<1>(name, args)?;
+            Ok(names.split_whitespace().last().unwrap_or_default().to_string())
+        }
+        _ => Err(format!("Unsupported function '{}'", name)),
+    }
+}
+
+/// Get the part of a name matched by the `%` in a pattern, if the name matches it
+pub fn pattern_stem<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    let (prefix, suffix) = pattern.split_once('%')?;
+    if name.len() < prefix.len() + suffix.len() {
+        return None;
+    }
+    if name.starts_with(prefix) && name.ends_with(suffix) {
+        Some(&name[prefix.len()..name.len() - suffix.len()])
+    } else {
+        None
+    }
+}
+
+/// Whether a word matches a `%` pattern, or is equal to a pattern without one
+fn matches_pattern(pattern: &str, word: &str) -> bool {
+    match pattern_stem(pattern, word) {
+        Some(_) => true,
+        None => !pattern.contains('%') && pattern == word,
+    }
+}
+
+/// Parse the number of a word for `word` or `wordlist`, which can't be less than `min`
+fn word_index(name: &str, text: &str, min: usize) -> Result<usize, String> {
+    match text.trim().parse::<usize>() {
+        Ok(n) if n >= min => Ok(n),
+        _ => Err(format!("Invalid word number '{}' for function '{}'", text.trim(), name)),
+    }
+}
+
+/// Replace a word matching a `%` pattern (or equal to a pattern without one), keeping the
+/// part matched by the `%`
+fn patsubst_word(pattern: &str, replacement: &str, word: &str) -> String {
+    match pattern_stem(pattern, word) {
+        Some(stem) => replacement.replacen('%', stem, 1),
+        None if word == pattern => replacement.to_string(),
+        None => word.to_string(),
+    }
+}
+
+/// Get the index of the `.` starting a word's suffix, which has to be in its last component
+pub fn suffix_start(word: &str) -> Option<usize> {
+    let idx = word.rfind('.')?;
+    match word.rfind('/') {
+        Some(slash) if slash > idx => None,
+        _ => Some(idx),
+    }
+}
+
+/// Apply a function to each whitespace-separated word, joining the results with spaces
+pub fn map_words<F: Fn(&str) -> String>(text: &str, f: F) -> String {
+    text.split_whitespace().map(f).collect::<Vec<String>>().join(" ")
+}
+
+/// Get the `N` arguments a function takes, joining any extra ones onto the last
+fn take_args<const N: usize>(name: &str, args: &[String]) -> Result<[String; N], String> {
+    if args.len() < N {
+        return Err(format!("Function '{}' needs {} arguments, but got {}", name, N, args.len()));
+    }
+    let mut taken: [String; N] = std::array::from_fn(|i| args[i].clone());
+    taken[N - 1] = args[N - 1..].join(",");
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Call a function with arguments given as string slices
+    fn eval(name: &str, args: &[&str]) -> Result<String, String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        call(name, &args)
+    }
+
+    #[test]
+    fn substitutes_text() {
+        let text = eval("subst", &["ee", "EE", "feet on the street"]).unwrap();
+        assert_eq!(text, "fEEt on the strEEt");
+        assert_eq!(eval("subst", &["", "x", "abc"]).unwrap(), "abc");
+        // commas past the last argument are part of it
+        assert_eq!(eval("subst", &[" ", ",", "a b", "c"]).unwrap(), "a,b,c");
+    }
+
+    #[test]
+    fn substitutes_patterns_in_each_word() {
+        assert_eq!(
+            eval("patsubst", &["%.c", "obj/%.o", "main.c util.c README"]).unwrap(),
+            "obj/main.o obj/util.o README"
+        );
+        // a pattern without a `%` only replaces whole words
+        let text = eval("patsubst", &["main.c", "app.c", "main.c main.cc"]).unwrap();
+        assert_eq!(text, "app.c main.cc");
+        assert_eq!(eval("patsubst", &[" %.c", "%.o ", "a.c"]).unwrap(), "a.o");
+        assert!(eval("patsubst", &["%.c", "%.o"]).is_err());
+    }
+
+    #[test]
+    fn splits_paths() {
+        let names = "src/main.c lib/util.tar.gz README src.d/file";
+        assert_eq!(eval("dir", &[names]).unwrap(), "src/ lib/ ./ src.d/");
+        assert_eq!(eval("notdir", &[names]).unwrap(), "main.c util.tar.gz README file");
+        // a suffix has to be in the last part of the path
+        assert_eq!(eval("suffix", &[names]).unwrap(), ".c .gz");
+        assert_eq!(eval("basename", &[names]).unwrap(), "src/main lib/util.tar README src.d/file");
+    }
+
+    #[test]
+    fn adds_prefixes_and_suffixes() {
+        assert_eq!(eval("addprefix", &["obj/", "a.o  b.o"]).unwrap(), "obj/a.o obj/b.o");
+        assert_eq!(eval("addsuffix", &[".c", "main util"]).unwrap(), "main.c util.c");
+        assert_eq!(eval("addsuffix", &[".c", ""]).unwrap(), "");
+    }
+
+    #[test]
+    fn filters_words_by_pattern() {
+        let words = "main.c util.h app.o main.c";
+        assert_eq!(eval("filter", &["%.c %.h", words]).unwrap(), "main.c util.h main.c");
+        assert_eq!(eval("filter-out", &["%.c app.o", words]).unwrap(), "util.h");
+    }
+
+    #[test]
+    fn sorts_and_picks_words() {
+        assert_eq!(eval("sort", &["c a b a"]).unwrap(), "a b c");
+        assert_eq!(eval("word", &["2", "a b c"]).unwrap(), "b");
+        assert_eq!(eval("word", &["4", "a b c"]).unwrap(), "");
+        assert!(eval("word", &["0", "a b c"]).is_err());
+        assert_eq!(eval("wordlist", &["2", "3", "a b c d"]).unwrap(), "b c");
+        assert_eq!(eval("wordlist", &["3", "2", "a b c d"]).unwrap(), "");
+        assert_eq!(eval("words", &["a  b c "]).unwrap(), "3");
+        assert_eq!(eval("firstword", &[" a b"]).unwrap(), "a");
+        assert_eq!(eval("lastword", &["a b "]).unwrap(), "b");
+        assert_eq!(eval("lastword", &[""]).unwrap(), "");
+    }
+}
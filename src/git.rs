@@ -0,0 +1,127 @@
+//!
+//! Finds the parts of a makefile that changed in git
+//!
+
+use std::collections::HashMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+use std::process::Command;
+
+use crate::types::Target;
+
+/// Keep only the targets with a rule whose lines have changed since the git revision `rev`,
+/// including uncommitted changes
+pub fn changed_since(targets: Vec<Target>, rev: &str) -> Result<Vec<Target>, String> {
+    // each file is only diffed once, however many targets it defines
+    let mut changes: HashMap<String, Vec<RangeInclusive<usize>>> = HashMap::new();
+    for span in targets.iter().flat_map(|t| t.spans.iter()) {
+        if !changes.contains_key(&span.file) {
+            let lines = changed_lines(Path::new(&span.file), rev)?;
+            changes.insert(span.file.clone(), lines);
+        }
+    }
+
+    Ok(targets
+        .into_iter()
+        .filter(|t| {
+            t.spans.iter().any(|span| {
+                changes[&span.file]
+                    .iter()
+                    .any(|r| *r.start() <= span.line_end && span.line_start <= *r.end())
+            })
+        })
+        .collect())
+}
+
+/// Get the ranges of lines in the current version of a file that are different at the git
+/// revision `rev`. A deletion is reported as a change to the line before it.
+pub fn changed_lines(path: &Path, rev: &str) -> Result<Vec<RangeInclusive<usize>>, String> {
+    let dir = match path.parent() {
+        Some(d) if !d.as_os_str().is_empty() => d,
+        _ => Path::new("."),
+    };
+    let name = path.file_name().unwrap_or_default();
+
+    let output = Command::new("git")
+        .args(["diff", "--no-color", "--no-ext-diff", "--unified=0", rev, "--"])
+        .arg(name)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| format!("Couldn't run git: {}", e))?;
+
+    if !output.status.success() {
+        return Err(format!(
+            "git diff against {} failed: {}",
+            rev,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    let diff = String::from_utf8_lossy(&output.stdout);
+    Ok(diff.lines().filter_map(hunk_range).collect())
+}
+
+/// Get the new-file line range from a hunk header like `@@ -10,2 +12,3 @@`
+fn hunk_range(line: &str) -> Option<RangeInclusive<usize>> {
+    let new = line
+        .strip_prefix("@@ ")?
+        .split_whitespace()
+        .find(|part| part.starts_with('+'))?
+        .trim_start_matches('+');
+
+    let (start, count) = match new.split_once(',') {
+        Some((start, count)) => (start.parse::<usize>().ok()?, count.parse::<usize>().ok()?),
+        None => (new.parse::<usize>().ok()?, 1),
+    };
+
+    if count == 0 {
+        // nothing was added, so mark the line the removed lines followed
+        Some(start.max(1)..=start.max(1))
+    } else {
+        Some(start..=start + count - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parser::Parser;
+
+    fn git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .output()
+            .unwrap()
+            .status;
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    #[test]
+    fn keeps_targets_whose_rules_changed() {
+        let dir = tempfile::tempdir().unwrap();
+        let makefile = dir.path().join("Makefile");
+        let text = "app:\n\tgcc -o app main.c\n\ntest:\n\t./run-tests\n\ndocs:\n";
+        std::fs::write(&makefile, text).unwrap();
+        git(dir.path(), &["init", "-q"]);
+        git(dir.path(), &["add", "Makefile"]);
+        git(dir.path(), &["commit", "-q", "-m", "Add a makefile"]);
+
+        std::fs::write(&makefile, text.replace("run-tests", "run-tests -v")).unwrap();
+        let targets = Parser::new().parse_file(&makefile, false).unwrap();
+        let changed = changed_since(targets, "HEAD").unwrap();
+
+        let names: Vec<&str> = changed.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["test"]);
+    }
+
+    #[test]
+    fn reads_hunk_ranges() {
+        assert_eq!(hunk_range("@@ -10,2 +12,3 @@ all:"), Some(12..=14));
+        assert_eq!(hunk_range("@@ -4 +4 @@"), Some(4..=4));
+        assert_eq!(hunk_range("@@ -5,2 +4,0 @@"), Some(4..=4));
+        assert_eq!(hunk_range("+added line"), None);
+    }
+}
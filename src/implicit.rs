@@ -0,0 +1,169 @@
+//!
+//! Make's built-in implicit rules, which build common files (like `.o` files from C sources)
+//! without a rule in the makefile
+//!
+
+use crate::functions::pattern_stem;
+
+/// One of make's built-in rules, with the recipe it runs
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImplicitRule {
+    // the `%` pattern of the files the rule makes
+    pub target: &'static str,
+    // the `%` pattern of the file it makes them from
+    pub prerequisite: &'static str,
+    // the recipe, with make's automatic variables still in it
+    pub recipe: &'static [&'static str],
+}
+
+/// The built-in rules for C, C++, assembly, Fortran, yacc, and lex, in the order make tries
+/// them in
+pub const RULES: &[ImplicitRule] = &[
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.c",
+        recipe: &["$(CC) $(CFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.cc",
+        recipe: &["$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.cpp",
+        recipe: &["$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.C",
+        recipe: &["$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.s",
+        recipe: &["$(AS) $(ASFLAGS) $(TARGET_MACH) -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.S",
+        recipe: &["$(CC) $(ASFLAGS) $(CPPFLAGS) $(TARGET_MACH) -c -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%.o",
+        prerequisite: "%.f",
+        recipe: &["$(FC) $(FFLAGS) $(TARGET_ARCH) -c -o $@ $<"],
+    },
+    ImplicitRule {
+        target: "%",
+        prerequisite: "%.o",
+        recipe: &["$(CC) $(LDFLAGS) $(TARGET_ARCH) $^ $(LOADLIBES) $(LDLIBS) -o $@"],
+    },
+    ImplicitRule {
+        target: "%",
+        prerequisite: "%.c",
+        recipe: &["$(CC) $(CFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH) $^ $(LOADLIBES) $(LDLIBS) -o $@"],
+    },
+    ImplicitRule {
+        target: "%",
+        prerequisite: "%.cc",
+        recipe: &["$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH) $^ $(LOADLIBES) $(LDLIBS) -o $@"],
+    },
+    ImplicitRule {
+        target: "%",
+        prerequisite: "%.cpp",
+        recipe: &["$(CXX) $(CXXFLAGS) $(CPPFLAGS) $(LDFLAGS) $(TARGET_ARCH) $^ $(LOADLIBES) $(LDLIBS) -o $@"],
+    },
+    ImplicitRule {
+        target: "%.c",
+        prerequisite: "%.y",
+        recipe: &["$(YACC) $(YFLAGS) $<", "mv -f y.tab.c $@"],
+    },
+    ImplicitRule {
+        target: "%.c",
+        prerequisite: "%.l",
+        recipe: &["@$(RM) $@", "$(LEX) $(LFLAGS) -t $< > $@"],
+    },
+];
+
+/// Flags that take an argument, after which the rest of a word of single-letter flags is the
+/// argument rather than more flags
+const FLAGS_WITH_ARGUMENTS: &str = "CfIjloW";
+
+/// Whether a `MAKEFLAGS` value turns off the built-in rules, with `-r` or `-R` (which turns off
+/// the built-in variables too). Single-letter flags can be written together in one word, which
+/// make writes first and without a `-`, like `rR`.
+pub fn disabled_by(flags: &str) -> bool {
+    for (i, word) in flags.split_whitespace().enumerate() {
+        match word {
+            "--" => break,
+            "--no-builtin-rules" | "--no-builtin-variables" => return true,
+            _ if word.starts_with("--") => continue,
+            _ => (),
+        }
+        let letters = match word.strip_prefix('-') {
+            Some(letters) => letters,
+            // other words are arguments of flags, or variable overrides
+            None if i == 0 => word,
+            None => continue,
+        };
+        let letters = letters.split(|c| FLAGS_WITH_ARGUMENTS.contains(c)).next().unwrap();
+        if letters.chars().all(|c| c.is_ascii_alphabetic()) && letters.contains(['r', 'R']) {
+            return true;
+        }
+    }
+    false
+}
+
+impl ImplicitRule {
+    /// Get the prerequisite the rule would make `name` from, if the rule can make it
+    pub fn source_for(&self, name: &str) -> Option<String> {
+        match pattern_stem(self.target, name) {
+            Some(stem) if !stem.is_empty() => Some(self.prerequisite.replacen('%', stem, 1)),
+            _ => None,
+        }
+    }
+
+    /// Get the rule's recipe for making `name` from `source`, with `$@`, `$<`, and `$^`
+    /// replaced. `prerequisites` are all of the target's prerequisites, for `$^`.
+    pub fn recipe_for(&self, name: &str, source: &str, prerequisites: &[String]) -> Vec<String> {
+        self.recipe
+            .iter()
+            .map(|line| {
+                line.replace("$@", name)
+                    .replace("$<", source)
+                    .replace("$^", &prerequisites.join(" "))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn makes_objects_from_c_sources() {
+        let rule = RULES.iter().find(|r| r.source_for("foo.o").is_some()).unwrap();
+        assert_eq!(rule.prerequisite, "%.c");
+        let recipe = rule.recipe_for("foo.o", "foo.c", &["foo.c".to_string()]);
+        assert_eq!(recipe, ["$(CC) $(CFLAGS) $(CPPFLAGS) $(TARGET_ARCH) -c -o foo.o foo.c"]);
+
+        // a program is linked from its object
+        let rule = RULES.iter().find(|r| r.source_for("foo").is_some()).unwrap();
+        assert_eq!(rule.source_for("foo").unwrap(), "foo.o");
+        // the `%` has to match something
+        assert_eq!(rule.source_for(""), None);
+    }
+
+    #[test]
+    fn finds_flags_that_disable_the_rules() {
+        assert!(disabled_by("-r"));
+        assert!(disabled_by("rR -- CC=clang"));
+        assert!(disabled_by("-k -R"));
+        assert!(disabled_by("--no-builtin-rules"));
+        assert!(!disabled_by(""));
+        assert!(!disabled_by("-k -I src -- DIR=r"));
+        assert!(!disabled_by("-Isrc --jobserver-auth=3,4"));
+    }
+}
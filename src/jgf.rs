@@ -0,0 +1,155 @@
+//!
+//! Converts the dependency graph to the JSON Graph Format (https://jsongraphformat.info)
+//!
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::types::Target;
+
+/// The top level of a JGF document, holding a single graph
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct JsonGraph {
+    pub graph: Graph,
+}
+
+/// A directed graph of targets and the files they depend on
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Graph {
+    pub directed: bool,
+    // nodes keyed by their id, which is the target or file name
+    pub nodes: BTreeMap<String, Node>,
+    pub edges: Vec<Edge>,
+}
+
+/// A target or file in the graph
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Node {
+    pub label: String,
+    pub metadata: NodeMetadata,
+}
+
+/// Extra information about a node, beyond what JGF itself defines
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeMetadata {
+    // whether the node is a target, or a prerequisite without a rule of its own
+    pub kind: String,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub default: bool,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub outputs: Vec<String>,
+}
+
+/// A dependency of the `source` target on the `target` node
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Edge {
+    pub source: String,
+    pub target: String,
+    pub relation: String,
+}
+
+/// Build a JGF graph with a node for every target and prerequisite, and an edge from each
+/// target to each of its prerequisites. Edges to order-only prerequisites have their own
+/// relation.
+pub fn to_jgf(targets: &[Target]) -> JsonGraph {
+    let mut nodes = BTreeMap::new();
+    let mut edges = Vec::new();
+
+    for target in targets {
+        nodes.insert(
+            target.name.clone(),
+            Node {
+                label: target.name.clone(),
+                metadata: NodeMetadata {
+                    kind: "target".to_string(),
+                    default: target.default,
+                    outputs: target.output.clone().unwrap_or_default(),
+                },
+            },
+        );
+
+        for prereq in &target.prerequisites {
+            edges.push(Edge {
+                source: target.name.clone(),
+                target: prereq.clone(),
+                relation: "depends_on".to_string(),
+            });
+        }
+        for prereq in &target.order_only {
+            edges.push(Edge {
+                source: target.name.clone(),
+                target: prereq.clone(),
+                relation: "order_only".to_string(),
+            });
+        }
+    }
+
+    // every edge has to refer to a node, so add the prerequisites that aren't targets
+    for edge in &edges {
+        nodes.entry(edge.target.clone()).or_insert_with(|| Node {
+            label: edge.target.clone(),
+            metadata: NodeMetadata {
+                kind: "file".to_string(),
+                default: false,
+                outputs: Vec::new(),
+            },
+        });
+    }
+
+    JsonGraph {
+        graph: Graph {
+            directed: true,
+            nodes,
+            edges,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emits_nodes_and_edges_that_refer_to_them() {
+        let mut app = Target::new("app".to_string());
+        app.default = true;
+        app.output = Some(vec!["bin/app".to_string()]);
+        app.prerequisites = vec!["main.o".to_string()];
+        app.order_only = vec!["bin".to_string()];
+        let mut main = Target::new("main.o".to_string());
+        main.prerequisites = vec!["main.c".to_string()];
+
+        let value = serde_json::to_value(to_jgf(&[app, main])).unwrap();
+        let graph = &value["graph"];
+        assert_eq!(graph["directed"], true);
+
+        let nodes = graph["nodes"].as_object().unwrap();
+        let ids: Vec<&str> = nodes.keys().map(|k| k.as_str()).collect();
+        assert_eq!(ids, ["app", "bin", "main.c", "main.o"]);
+        assert_eq!(nodes["app"]["metadata"]["outputs"][0], "bin/app");
+        assert_eq!(nodes["main.c"]["metadata"]["kind"], "file");
+
+        let edges = graph["edges"].as_array().unwrap();
+        let edges: Vec<(&str, &str, &str)> = edges
+            .iter()
+            .map(|e| {
+                assert!(nodes.contains_key(e["source"].as_str().unwrap()));
+                assert!(nodes.contains_key(e["target"].as_str().unwrap()));
+                (
+                    e["source"].as_str().unwrap(),
+                    e["target"].as_str().unwrap(),
+                    e["relation"].as_str().unwrap(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            edges,
+            [
+                ("app", "main.o", "depends_on"),
+                ("app", "bin", "order_only"),
+                ("main.o", "main.c", "depends_on"),
+            ]
+        );
+    }
+}
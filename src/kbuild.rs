@@ -0,0 +1,174 @@
+//!
+//! Conventions of the Linux kernel's build system (kbuild), whose makefiles list the objects
+//! to build in variables like `obj-y` rather than writing rules for them
+//!
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use crate::types::{Flavor, Origin, Target, Variable};
+
+/// Extensions of the sources an object can be compiled from, in the order they're looked for
+const SOURCE_EXTENSIONS: &[&str] = &[".c", ".S", ".rs"];
+
+/// Create the targets that kbuild would build from a makefile's goal variables: `obj-y`
+/// objects linked into the directory's `built-in.a`, `obj-m` objects linked into modules,
+/// `lib-y` objects archived into `lib.a`, and `extra-y` files. `value` gets the words of a
+/// variable, which are empty if it isn't defined. Outputs are put in `obj`, and objects are
+/// compiled from a source in `src`, which is only looked for on disk if `look_on_disk` is set.
+pub fn infer_targets(
+    value: &mut dyn FnMut(&str) -> Vec<String>,
+    obj: &str,
+    src: &str,
+    look_on_disk: bool,
+) -> Vec<Target> {
+    let mut targets = Vec::new();
+    let mut objects = Vec::new();
+
+    // objects built into the kernel are linked into one archive per directory, along with
+    // those of the subdirectories listed with a trailing `/`
+    let builtin = value("obj-y");
+    if !builtin.is_empty() {
+        let mut archive = made_target(&format!("{}/built-in.a", obj));
+        for entry in builtin {
+            match entry.strip_suffix('/') {
+                Some(dir) => archive.prerequisites.push(format!("{}/{}/built-in.a", obj, dir)),
+                None => {
+                    archive.prerequisites.push(format!("{}/{}", obj, entry));
+                    objects.push(entry);
+                }
+            }
+        }
+        targets.push(archive);
+    }
+
+    // each module is linked from the object with the same name
+    for entry in value("obj-m").into_iter().filter(|e| !e.ends_with('/')) {
+        if let Some(stem) = entry.strip_suffix(".o") {
+            let mut module = made_target(&format!("{}/{}.ko", obj, stem));
+            module.prerequisites.push(format!("{}/{}", obj, entry));
+            targets.push(module);
+        }
+        objects.push(entry);
+    }
+
+    let library = value("lib-y");
+    if !library.is_empty() {
+        let mut archive = made_target(&format!("{}/lib.a", obj));
+        archive.prerequisites = library.iter().map(|o| format!("{}/{}", obj, o)).collect();
+        targets.push(archive);
+        objects.extend(library);
+    }
+    objects.extend(value("extra-y"));
+
+    // a composite object is linked from the objects listed in `<name>-y` (or `<name>-objs`)
+    // instead of being compiled from a source of its own
+    let ccflags = value("ccflags-y").join(" ");
+    let mut seen = BTreeSet::new();
+    let mut next = 0;
+    while let Some(entry) = objects.get(next).cloned() {
+        next += 1;
+        if !seen.insert(entry.clone()) {
+            continue;
+        }
+        let mut target = made_target(&format!("{}/{}", obj, entry));
+        if let Some(stem) = entry.strip_suffix(".o") {
+            let mut parts = value(&format!("{}-y", stem));
+            parts.extend(value(&format!("{}-objs", stem)));
+            if parts.is_empty() {
+                target.prerequisites.push(source(src, stem, look_on_disk));
+            } else {
+                target.prerequisites = parts.iter().map(|p| format!("{}/{}", obj, p)).collect();
+                objects.extend(parts);
+            }
+
+            // flags for every object in the directory, and for this object alone
+            let object_flags = format!("CFLAGS_{}", entry);
+            let flags = vec![
+                ("ccflags-y".to_string(), ccflags.clone()),
+                (object_flags.clone(), value(&object_flags).join(" ")),
+            ];
+            for (name, flags) in flags.into_iter().filter(|(_, flags)| !flags.is_empty()) {
+                let var = Variable::new(flags, Flavor::Simple, Origin::File);
+                target.variables.insert(name, var);
+            }
+        }
+        targets.push(target);
+    }
+    targets
+}
+
+/// Create a target for a file that kbuild makes, which is its only output
+fn made_target(name: &str) -> Target {
+    let mut target = Target::new(name.to_string());
+    target.output = Some(vec![name.to_string()]);
+    target
+}
+
+/// Get the source an object is compiled from: the first file in `src` with the object's name
+/// and one of the source extensions, or its C source if there isn't one
+fn source(src: &str, stem: &str, look_on_disk: bool) -> String {
+    let candidates = SOURCE_EXTENSIONS.iter().map(|ext| format!("{}/{}{}", src, stem, ext));
+    let mut found = candidates.filter(|path| look_on_disk && Path::new(path).exists());
+    found.next().unwrap_or_else(|| format!("{}/{}.c", src, stem))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    /// Infer the targets from variables given as `(name, value)` pairs, mapped to their
+    /// prerequisites
+    fn infer(vars: &[(&str, &str)]) -> BTreeMap<String, Vec<String>> {
+        let vars: BTreeMap<&str, &str> = vars.iter().cloned().collect();
+        let mut value = |name: &str| {
+            let words = vars.get(name).map(|v| v.split_whitespace()).into_iter().flatten();
+            words.map(String::from).collect()
+        };
+        let targets = infer_targets(&mut value, "drv", "drv", false);
+        for target in &targets {
+            assert_eq!(target.output, Some(vec![target.name.clone()]));
+        }
+        targets.into_iter().map(|t| (t.name, t.prerequisites)).collect()
+    }
+
+    #[test]
+    fn links_builtin_objects_and_modules() {
+        let targets = infer(&[("obj-y", "core.o net/"), ("obj-m", "mod.o")]);
+        let names: Vec<&str> = targets.keys().map(|n| n.as_str()).collect();
+        assert_eq!(names, ["drv/built-in.a", "drv/core.o", "drv/mod.ko", "drv/mod.o"]);
+        assert_eq!(targets["drv/built-in.a"], ["drv/core.o", "drv/net/built-in.a"]);
+        assert_eq!(targets["drv/mod.ko"], ["drv/mod.o"]);
+        assert_eq!(targets["drv/core.o"], ["drv/core.c"]);
+    }
+
+    #[test]
+    fn links_composite_objects_from_their_parts() {
+        let targets = infer(&[
+            ("obj-m", "mod.o"),
+            ("mod-objs", "main.o"),
+            ("mod-y", "util.o"),
+            ("lib-y", "lib.o"),
+        ]);
+        assert_eq!(targets["drv/mod.o"], ["drv/util.o", "drv/main.o"]);
+        assert_eq!(targets["drv/main.o"], ["drv/main.c"]);
+        assert_eq!(targets["drv/util.o"], ["drv/util.c"]);
+        assert_eq!(targets["drv/lib.a"], ["drv/lib.o"]);
+        assert!(!targets.contains_key("drv/built-in.a"));
+    }
+
+    #[test]
+    fn lists_object_flags_as_variables() {
+        let mut value = |name: &str| match name {
+            "obj-y" => vec!["a.o".to_string()],
+            "ccflags-y" => vec!["-DDEBUG".to_string()],
+            "CFLAGS_a.o" => vec!["-O0".to_string()],
+            _ => Vec::new(),
+        };
+        let targets = infer_targets(&mut value, ".", ".", false);
+        let object = targets.iter().find(|t| t.name == "./a.o").unwrap();
+        assert_eq!(object.variables["ccflags-y"].value, "-DDEBUG");
+        assert_eq!(object.variables["CFLAGS_a.o"].value, "-O0");
+    }
+}
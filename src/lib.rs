@@ -5,3 +5,15 @@
 pub mod parser;
 pub mod types;
 pub mod filter;
+pub mod render;
+pub mod analysis;
+pub mod sqlite;
+pub mod query;
+pub mod jgf;
+pub mod git;
+pub mod rules;
+pub mod functions;
+pub mod bsd;
+pub mod nmake;
+pub mod implicit;
+pub mod kbuild;
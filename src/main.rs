@@ -1,22 +1,33 @@
 ///
 /// Parses a Makefile for targets & output information
 ///
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Write;
 use std::path::Path;
 use std::process::exit;
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgGroup};
 use log::*;
+use regex::Regex;
 
-use serde_json::to_string_pretty;
+use serde_json::{to_string_pretty, to_value, Value};
 use simplelog::*;
 
 use makeparse::parser::Parser;
+use makeparse::analysis::{
+    annotation_mismatches, artifact_report, build_plan, clean_gaps, env_usage, glob_pattern,
+    infer_implicit_outputs, output_collisions, outputs_by_extension, provenance, reachable_from,
+    resolve_prerequisites, resolve_search_paths, suggest_phony, variable_usage,
+};
 use makeparse::filter::*;
+use makeparse::query::query;
+use makeparse::render::{render_table, terminal_width};
+use makeparse::sqlite::write_sqlite;
+use makeparse::jgf::to_jgf;
+use makeparse::git::changed_since;
+use makeparse::types::{Analysis, Diagnostic, Dialect, Origin, Severity};
 
-// TODO: resolve ?= with env variables if they exist
-// TODO: handle included makefiles
 // TODO: support cargo somehow?
 // TODO: have an option to condense outputs if they all fall into an output folder/have a common parent
 // TODO: possibly look at dependency targets and get their outputs as well
@@ -40,7 +51,106 @@ fn main() {
     }
 
     // parse the input file
-    let mut parser = Parser::new();
+    let mut parser = Parser::new()
+        .include_declared_only(matches.is_present("include-declared-only"))
+        .fold_case_targets(matches.is_present("fold-case-targets"))
+        .detect_delegation(matches.is_present("detect-delegation"))
+        .track_assignments(matches.is_present("track-assignments"))
+        .conditional_from_env(matches.is_present("use-env"))
+        .relative_includes_only(matches.is_present("relative-includes-only"))
+        .allow_eval(matches.is_present("allow-eval"))
+        .allow_shell_assign(matches.is_present("allow-shell-assign"))
+        .eval_shell(matches.is_present("eval-shell"))
+        .graph_only(matches.is_present("graph-only"))
+        .normalize_separators(matches.is_present("normalize-separators"))
+        .group_double_colon(matches.is_present("group-double-colon"))
+        .expand_in_recipes(!matches.is_present("no-expand-in-recipes"))
+        .break_recursion(matches.is_present("break-recursion"))
+        .skip_long_lines(matches.value_of("long-lines") == Some("skip"))
+        .instantiate_patterns(matches.is_present("instantiate-patterns"))
+        .filesystem_access(!matches.is_present("no-filesystem"))
+        .fatal_error_calls(matches.is_present("fatal-error-calls"))
+        .kbuild(matches.is_present("kbuild"))
+        .dialect(match matches.value_of("dialect") {
+            Some("bsd") => Dialect::Bsd,
+            Some("nmake") => Dialect::Nmake,
+            _ => Dialect::Gnu,
+        });
+
+    // the goals are what make would be given on the command line
+    if let Some(goals) = matches.value_of("goals") {
+        parser.set_variable("MAKECMDGOALS", goals, Origin::Default);
+    }
+
+    if let Some(depth) = matches.value_of("max-include-depth") {
+        match depth.parse::<usize>() {
+            Ok(depth) => parser = parser.max_include_depth(depth),
+            Err(_) => {
+                error!("Invalid include depth '{}'", depth);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(prefix) = matches.value_of("recipe-prefix") {
+        let mut chars = prefix.chars();
+        match (chars.next(), chars.next()) {
+            (Some(c), None) => parser = parser.recipe_prefix(c),
+            _ => {
+                error!("Invalid recipe prefix '{}'; expected a single character", prefix);
+                exit(1);
+            }
+        }
+    }
+
+    if let Some(length) = matches.value_of("max-line-length") {
+        match length.parse::<usize>() {
+            Ok(length) => parser = parser.max_line_length(length),
+            Err(_) => {
+                error!("Invalid line length '{}'", length);
+                exit(1);
+            }
+        }
+    }
+
+    // add any custom rules before parsing, so that env file variables override them
+    if let Some(path) = matches.value_of("rules-file") {
+        if let Err(e) = parser.load_rules_file(path) {
+            error!("Failed to load rules file: {}", e);
+            exit(1);
+        }
+    }
+
+    // seed any variables from an env file before parsing
+    if let Some(path) = matches.value_of("env-file") {
+        if let Err(e) = parser.load_env_file(path) {
+            error!("Failed to load env file: {}", e);
+            exit(1);
+        }
+    }
+
+    // a profile is a set of overrides, like variables given to make on the command line
+    let mut profile = BTreeMap::new();
+    for setting in matches.values_of("profile").into_iter().flatten() {
+        match setting.split_once('=') {
+            Some((name, value)) => {
+                parser.set_variable(name, value, Origin::CommandLine);
+                profile.insert(name.to_string(), value.to_string());
+            }
+            None => {
+                error!("Invalid profile setting '{}'; expected KEY=VAL", setting);
+                exit(1);
+            }
+        }
+    }
+
+    // pick up include dirs and overrides the way make itself would
+    if matches.is_present("honor-makeflags") {
+        if let Ok(flags) = std::env::var("MAKEFLAGS") {
+            parser.apply_makeflags(&flags);
+        }
+    }
+
     let targets = match parser.parse_file(filepath, strict_mode) {
         Ok(t) => t,
         Err(e) => {
@@ -49,14 +159,194 @@ fn main() {
         }
     };
 
+    // add the outputs of targets that make's built-in rules would build, like objects from C
+    let mut targets = targets;
+    if matches.is_present("implicit-rules") && !parser.builtin_rules_disabled() {
+        let dir = filepath.parent().unwrap_or_else(|| Path::new("."));
+        infer_implicit_outputs(&mut targets, parser.pattern_rules(), dir);
+    }
+
+    let mut diagnostics = parser.diagnostics().to_vec();
+
+    // only analyze the subtrees of any goals given, as if they were passed to make
+    let goals: Vec<String> = matches
+        .value_of("goals")
+        .map(|g| g.split_whitespace().map(|g| g.to_string()).collect())
+        .unwrap_or_default();
+    let targets = if goals.is_empty() {
+        targets
+    } else {
+        for goal in goals.iter().filter(|g| !targets.iter().any(|t| &t.name == *g)) {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                message: format!("Requested goal '{}' isn't defined", goal),
+                file: None,
+                line: None,
+            });
+        }
+        reachable_from(targets, &goals)
+    };
+
     // apply any user filters to remove unwanted targets
-    let targets = filter_targets(
+    let mut targets = filter_targets(
         targets, 
         strict_mode, 
         matches.values_of("filter"), 
-        matches.values_of("include"));
+        matches.values_of("include"),
+        matches.values_of("depends-on"),
+        matches.values_of("not-depends-on"),
+        matches.is_present("depends-regex"));
+
+    // only keep the targets whose rules changed since a git revision
+    if let Some(rev) = matches.value_of("since") {
+        targets = match changed_since(targets, rev) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("Failed to find changes since {}: {}", rev, e);
+                exit(1);
+            }
+        };
+    }
+
+    // find prerequisites and outputs through vpath/VPATH, like make would
+    let search_paths = parser.search_paths();
+    if matches.is_present("resolve-vpath") {
+        let dir = filepath.parent().unwrap_or_else(|| Path::new("."));
+        resolve_search_paths(&mut targets, dir, &search_paths);
+    }
+
+    // sqlite output is written directly to the output file rather than serialized
+    if matches.value_of("format") == Some("sqlite") {
+        let path = match matches.value_of("output") {
+            Some(p) => p,
+            None => {
+                error!("The sqlite format requires an output file");
+                exit(1);
+            }
+        };
+
+        if let Err(e) = write_sqlite(path, &targets, parser.variables()) {
+            error!("{}", e);
+            exit(1);
+        }
+        return;
+    }
 
-    let ser_output = to_string_pretty(&targets).unwrap();
+    // classify prerequisites as targets/files, treating any assumed names as existing files
+    if matches.is_present("resolve-prereqs") || matches.is_present("assume-existing") {
+        let assumed: Vec<Regex> = matches
+            .values_of("assume-existing")
+            .map(|values| {
+                values
+                    .filter_map(|glob| {
+                        let re = glob_pattern(glob);
+                        if re.is_none() {
+                            error!("Invalid --assume-existing pattern '{}'", glob);
+                            if strict_mode {
+                                exit(1);
+                            }
+                        }
+                        re
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let dir = filepath.parent().unwrap_or_else(|| Path::new("."));
+        resolve_prerequisites(&mut targets, dir, &assumed);
+    }
+
+    // checked before the targets are consumed by the output below
+    let collisions = output_collisions(&targets);
+
+    let ser_output = if matches.is_present("report-clean-gaps") {
+        to_string_pretty(&clean_gaps(&targets)).unwrap()
+    } else if matches.value_of("group-outputs-by") == Some("ext") {
+        to_string_pretty(&outputs_by_extension(&targets)).unwrap()
+    } else if matches.is_present("verify-annotations") {
+        to_string_pretty(&annotation_mismatches(&targets)).unwrap()
+    } else if matches.is_present("provenance") {
+        to_string_pretty(&provenance(&targets)).unwrap()
+    } else if matches.is_present("report-output-collisions") {
+        to_string_pretty(&collisions).unwrap()
+    } else if matches.is_present("report-env-usage") {
+        to_string_pretty(&env_usage(&targets)).unwrap()
+    } else if matches.is_present("report-artifacts") {
+        let dir = filepath.parent().unwrap_or_else(|| Path::new("."));
+        to_string_pretty(&artifact_report(&targets, dir)).unwrap()
+    } else if let Some(goal) = matches.value_of("build-plan") {
+        match build_plan(&targets, goal) {
+            Ok(plan) => plan.join("\n"),
+            Err(e) => {
+                error!("Couldn't plan the build of {}: {}", goal, e);
+                exit(1);
+            }
+        }
+    } else if let Some(name) = matches.value_of("uses-var") {
+        to_string_pretty(&variable_usage(&targets, name)).unwrap()
+    } else if matches.is_present("suggest-phony") {
+        // print a line that can be pasted straight into the makefile
+        let dir = filepath.parent().unwrap_or_else(|| Path::new("."));
+        let names = suggest_phony(&targets, parser.declared_phony(), dir);
+        if names.is_empty() {
+            String::new()
+        } else {
+            format!(".PHONY: {}", names.join(" "))
+        }
+    } else if matches.value_of("format") == Some("jgf") {
+        to_string_pretty(&to_jgf(&targets)).unwrap()
+    } else if matches.value_of("format") == Some("table") {
+        render_table(&targets, table_width(matches.value_of("pretty-width")))
+    } else {
+        // the variables a profile selects are part of what it's being compared on
+        let with_variables = matches.is_present("with-variables") || !profile.is_empty();
+
+        // recipes are kept for the analysis, but only output when asked for
+        let mut targets = targets;
+        let mut pattern_rules = parser.pattern_rules().to_vec();
+        if !matches.is_present("with-recipes") {
+            targets.iter_mut().for_each(|t| t.recipe.clear());
+            pattern_rules.iter_mut().for_each(|r| r.recipe.clear());
+        }
+
+        let analysis = Analysis {
+            targets,
+            pattern_rules,
+            pattern_variables: parser.pattern_variables().clone(),
+            shell: parser.shell(),
+            one_shell: parser.one_shell(),
+            special_targets: parser.special_targets().clone(),
+            search_paths,
+            requested_goals: goals,
+            profile,
+            variables: if with_variables {
+                parser.variables().clone()
+            } else {
+                Default::default()
+            },
+            assignments: parser.assignments().clone(),
+            diagnostics,
+        };
+
+        // only the targets are written, unless part of the rest of the analysis is asked for
+        let full = matches.is_present("analysis")
+            || with_variables
+            || matches.is_present("track-assignments");
+
+        match matches.value_of("query") {
+            Some(expr) => match query(&to_value(&analysis).unwrap(), expr) {
+                // print plain strings without quotes so they're easy to use in scripts
+                Ok(Value::String(s)) => s,
+                Ok(v) => to_string_pretty(&v).unwrap(),
+                Err(e) => {
+                    error!("Query '{}' failed: {}", expr, e);
+                    exit(1);
+                }
+            },
+            None if full => to_string_pretty(&analysis).unwrap(),
+            None => to_string_pretty(&analysis.targets).unwrap(),
+        }
+    };
 
     // save the output to a file if specified, otherwise write to stdout
     if let Some(path) = matches.value_of("output") {
@@ -75,6 +365,13 @@ fn main() {
     } else {
         println!("{}", ser_output);
     }
+
+    // fail after writing the results, so the problems can still be inspected
+    let fail_on: Vec<&str> = matches.values_of("fail-on").map(|v| v.collect()).unwrap_or_default();
+    if fail_on.contains(&"collisions") && !collisions.is_empty() {
+        error!("Found {} output(s) produced by more than one target", collisions.len());
+        exit(1);
+    }
 }
 
 /// Set up the CLI argument matching structure
@@ -92,10 +389,66 @@ fn generate_cli<'a, 'b>() -> clap::App<'a, 'b> {
                 .long("output")
                 .value_name("FILE")
                 .takes_value(true))
+        .arg(Arg::with_name("format")
+                .help("Format to write results in")
+                .long("format")
+                .value_name("FORMAT")
+                .takes_value(true)
+                .possible_values(&["json", "table", "sqlite", "jgf"])
+                .default_value("json"))
+        .arg(Arg::with_name("pretty-width")
+                .help("Maximum line width for table output (terminal width by default)")
+                .long("pretty-width")
+                .value_name("N")
+                .takes_value(true))
+        .arg(Arg::with_name("implicit-rules")
+                .help("Infer the outputs of targets built by make's built-in implicit rules, like objects compiled from C sources")
+                .long("implicit-rules"))
+        .arg(Arg::with_name("resolve-prereqs")
+                .help("Classify each prerequisite as a target, an existing file, or unknown")
+                .long("resolve-prereqs"))
+        .arg(Arg::with_name("resolve-vpath")
+                .help("Replace prerequisites and outputs that don't exist with the paths found through vpath and VPATH")
+                .long("resolve-vpath"))
+        .arg(Arg::with_name("assume-existing")
+                .help("Treat prerequisites matching the glob as existing files (implies --resolve-prereqs)")
+                .long("assume-existing")
+                .value_name("PATTERN")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+        .arg(Arg::with_name("profile")
+                .help("Set variables for a build profile and report the targets and variables it selects")
+                .long("profile")
+                .value_name("KEY=VAL")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+        .arg(Arg::with_name("analysis")
+                .help("Write an object with the diagnostics, pattern rules, and the rest of the analysis along with the targets, instead of an array of the targets")
+                .long("analysis"))
+        .arg(Arg::with_name("with-variables")
+                .help("Include the variables defined by the makefile in the results")
+                .long("with-variables"))
+        .arg(Arg::with_name("with-recipes")
+                .help("Include each target's recipe lines in the results")
+                .long("with-recipes"))
+        .arg(Arg::with_name("track-assignments")
+                .help("Include every assignment made to each variable in the results")
+                .long("track-assignments"))
+        .arg(Arg::with_name("use-env")
+                .help("Use environment variables over the makefile's ?= defaults, like make does")
+                .long("use-env"))
         .arg(Arg::with_name("strict")
                 .help("Fail on any parser error")
                 .short("s")
                 .long("strict"))
+        .arg(Arg::with_name("fatal-error-calls")
+                .help("With --strict, stop at $(error) calls in the parsed parts of the makefile")
+                .long("fatal-error-calls"))
+        .arg(Arg::with_name("kbuild")
+                .help("Infer the objects, modules, and archives listed in obj-y, obj-m, and the like by Linux kernel (kbuild) makefiles")
+                .long("kbuild"))
         .arg(Arg::with_name("debug")
                 .help("Enable debug logging")
                 .long("debug"))
@@ -107,6 +460,153 @@ fn generate_cli<'a, 'b>() -> clap::App<'a, 'b> {
                 .long("log")
                 .value_name("FILE")
                 .takes_value(true))
+        .arg(Arg::with_name("include-declared-only")
+                .help("Include targets that are declared .PHONY but have no rule")
+                .long("include-declared-only"))
+        .arg(Arg::with_name("fold-case-targets")
+                .help("Merge targets whose names only differ by case")
+                .long("fold-case-targets"))
+        .arg(Arg::with_name("detect-delegation")
+                .help("Record goals passed to $(MAKE) in recipes as targets each target delegates to")
+                .long("detect-delegation"))
+        .arg(Arg::with_name("max-include-depth")
+                .help("Maximum depth of nested includes to follow (32 by default)")
+                .long("max-include-depth")
+                .value_name("N")
+                .takes_value(true))
+        .arg(Arg::with_name("env-file")
+                .help("Seed variables from a .env-style file of KEY=VALUE lines")
+                .long("env-file")
+                .value_name("PATH")
+                .takes_value(true))
+        .arg(Arg::with_name("dialect")
+                .help("Variant of make the makefile is written for")
+                .long("dialect")
+                .value_name("DIALECT")
+                .takes_value(true)
+                .possible_values(&["gnu", "bsd", "nmake"])
+                .default_value("gnu"))
+        .arg(Arg::with_name("recipe-prefix")
+                .help("Character that starts recipe lines, like setting .RECIPEPREFIX")
+                .long("recipe-prefix")
+                .value_name("CHAR")
+                .takes_value(true))
+        .arg(Arg::with_name("max-line-length")
+                .help("Longest line, in bytes, to parse in full")
+                .long("max-line-length")
+                .value_name("N")
+                .takes_value(true))
+        .arg(Arg::with_name("long-lines")
+                .help("What to do with lines longer than --max-line-length")
+                .long("long-lines")
+                .value_name("ACTION")
+                .takes_value(true)
+                .possible_values(&["truncate", "skip"])
+                .default_value("truncate"))
+        .arg(Arg::with_name("break-recursion")
+                .help("Expand recursive variable references to nothing instead of skipping the line")
+                .long("break-recursion"))
+        .arg(Arg::with_name("no-expand-in-recipes")
+                .help("Keep recipe lines as written, only expanding variables in the outputs found in them")
+                .long("no-expand-in-recipes"))
+        .arg(Arg::with_name("group-double-colon")
+                .help("Merge :: rules for the same target into one target with a variant per rule")
+                .long("group-double-colon"))
+        .arg(Arg::with_name("normalize-separators")
+                .help("Convert backslash path separators in outputs and prerequisites to forward slashes")
+                .long("normalize-separators"))
+        .arg(Arg::with_name("no-filesystem")
+                .help("Don't look at the filesystem in functions like $(wildcard), or for wildcards in included paths")
+                .long("no-filesystem"))
+        .arg(Arg::with_name("instantiate-patterns")
+                .help("Create targets from pattern rules for the matching files on disk")
+                .long("instantiate-patterns"))
+        .arg(Arg::with_name("graph-only")
+                .help("Only find targets and prerequisites, skipping recipes entirely")
+                .long("graph-only"))
+        .arg(Arg::with_name("allow-eval")
+                .help("Parse the text passed to $(eval), to find the rules it generates")
+                .long("allow-eval"))
+        .arg(Arg::with_name("allow-shell-assign")
+                .help("Run the commands of != assignments to get the variables' values")
+                .long("allow-shell-assign"))
+        .arg(Arg::with_name("eval-shell")
+                .help("Run the commands passed to $(shell) and use their output")
+                .long("eval-shell"))
+        .arg(Arg::with_name("relative-includes-only")
+                .help("Refuse to follow includes outside of the makefile's directory")
+                .long("relative-includes-only"))
+        .arg(Arg::with_name("rules-file")
+                .help("Load extra output detection rules and variables from a JSON file")
+                .long("rules-file")
+                .value_name("PATH")
+                .takes_value(true))
+        .arg(Arg::with_name("honor-makeflags")
+                .help("Apply include dirs and variable overrides from the MAKEFLAGS environment variable")
+                .long("honor-makeflags"))
+        .arg(Arg::with_name("report-clean-gaps")
+                .help("Report outputs that aren't removed by any rm command in the makefile")
+                .long("report-clean-gaps"))
+        .arg(Arg::with_name("group-outputs-by")
+                .help("Report the outputs of all targets grouped into categories")
+                .long("group-outputs-by")
+                .value_name("CATEGORY")
+                .takes_value(true)
+                .possible_values(&["ext"]))
+        .arg(Arg::with_name("verify-annotations")
+                .help("Report # Output: annotations that don't match the outputs found in the recipe")
+                .long("verify-annotations"))
+        .arg(Arg::with_name("provenance")
+                .help("Report the command, inputs, and source location that produce each output")
+                .long("provenance"))
+        .arg(Arg::with_name("report-output-collisions")
+                .help("Report output paths produced by more than one target")
+                .long("report-output-collisions"))
+        .arg(Arg::with_name("fail-on")
+                .help("Exit with an error if any of the given problems are found")
+                .long("fail-on")
+                .value_name("PROBLEMS")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .possible_values(&["collisions"]))
+        .arg(Arg::with_name("report-env-usage")
+                .help("Report the environment variables read by each target's recipe")
+                .long("report-env-usage"))
+        .arg(Arg::with_name("report-artifacts")
+                .help("Report the number of distinct outputs and the total size of those that exist")
+                .long("report-artifacts"))
+        .arg(Arg::with_name("goals")
+                .help("Restrict analysis to the given goals and their prerequisites")
+                .long_help(
+                    "Restrict analysis to the given goals and their prerequisites, \
+                    as if they were passed to make on the command line")
+                .long("goals")
+                .value_name("GOALS")
+                .takes_value(true))
+        .arg(Arg::with_name("since")
+                .help("Only report targets whose rules changed since a git revision")
+                .long("since")
+                .value_name("GITREV")
+                .takes_value(true))
+        .arg(Arg::with_name("build-plan")
+                .help("Print the order that a target and its prerequisites would be built in")
+                .long("build-plan")
+                .value_name("TARGET")
+                .takes_value(true))
+        .arg(Arg::with_name("uses-var")
+                .help("Report the targets whose name, prerequisites, or outputs refer to a variable")
+                .long("uses-var")
+                .value_name("NAME")
+                .takes_value(true))
+        .arg(Arg::with_name("suggest-phony")
+                .help("Print a .PHONY line for targets that look phony but aren't declared so")
+                .long("suggest-phony"))
+        .arg(Arg::with_name("query")
+                .help("Print only the part of the results selected by a path like targets[name=all].output")
+                .long("query")
+                .value_name("EXPR")
+                .takes_value(true))
         .arg(Arg::with_name("filter")
                 .help("Filter out targets that match the regex specified")
                 .short("f")
@@ -123,6 +623,53 @@ fn generate_cli<'a, 'b>() -> clap::App<'a, 'b> {
                 .takes_value(true)
                 .multiple(true)
                 .number_of_values(1))
+        .arg(Arg::with_name("depends-on")
+                .help("Only include targets with a prerequisite named NAME")
+                .long("depends-on")
+                .value_name("NAME")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+        .arg(Arg::with_name("not-depends-on")
+                .help("Filter out targets with a prerequisite named NAME")
+                .long("not-depends-on")
+                .value_name("NAME")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+        .arg(Arg::with_name("depends-regex")
+                .help("Treat --depends-on and --not-depends-on values as regexes")
+                .long("depends-regex"))
+        // each report replaces the usual results, so only one can be given, and not with options
+        // for how those results are written
+        .group(ArgGroup::with_name("report")
+                .args(&[
+                    "report-clean-gaps",
+                    "group-outputs-by",
+                    "verify-annotations",
+                    "provenance",
+                    "report-output-collisions",
+                    "report-env-usage",
+                    "report-artifacts",
+                    "build-plan",
+                    "uses-var",
+                    "suggest-phony",
+                ])
+                .conflicts_with_all(&["format", "query", "analysis"]))
+}
+
+/// Get the width to render tables at, using the terminal width unless the user gave one
+fn table_width(user_width: Option<&str>) -> usize {
+    match user_width {
+        Some(w) => match w.parse::<usize>() {
+            Ok(w) if w > 0 => w,
+            _ => {
+                error!("Invalid table width '{}'", w);
+                exit(1);
+            }
+        },
+        None => terminal_width(),
+    }
 }
 
 /// Set up the logger, dependent on user selection. If specified, the logger will attempt
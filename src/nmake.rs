@@ -0,0 +1,146 @@
+//!
+//! Syntax specific to Microsoft's NMAKE: `!` preprocessing directives, whose conditions use
+//! the same expressions as BSD make's
+//!
+
+/// Directives as they're written (in any case) after the `!`, and the names they're handled
+/// under, which match BSD make's directives with the same meaning
+const DIRECTIVES: &[(&str, &str)] = &[
+    ("if", "if"),
+    ("ifdef", "ifdef"),
+    ("ifndef", "ifndef"),
+    ("elseif", "elif"),
+    ("elseifdef", "elifdef"),
+    ("elseifndef", "elifndef"),
+    ("else", "else"),
+    ("endif", "endif"),
+    ("include", "include"),
+    ("undef", "undef"),
+    ("error", "error"),
+    ("message", "message"),
+    ("cmdswitches", "cmdswitches"),
+];
+
+/// Suffixes that NMAKE's inference rules (like `.c.obj:`) can use without being listed in
+/// .SUFFIXES, besides those GNU make also knows
+pub const SUFFIXES: &[&str] = &[
+    ".exe", ".obj", ".asm", ".cpp", ".cxx", ".bas", ".cbl", ".for", ".pas", ".res", ".rc", ".f90",
+];
+
+/// Split a directive line like `!INCLUDE <win32.mak>` into the name its keyword is handled
+/// under and the rest of the line, if it's a directive. `!ELSE IF` is the same as `!ELSEIF`.
+pub fn directive(line: &str) -> Option<(&'static str, &str)> {
+    let (word, mut rest) = split_word(line.strip_prefix('!')?.trim_start());
+    let mut word = word.to_ascii_lowercase();
+    if word == "else" {
+        let (next, after) = split_word(rest);
+        let next = next.to_ascii_lowercase();
+        if ["if", "ifdef", "ifndef"].contains(&next.as_str()) {
+            word.push_str(&next);
+            rest = after;
+        }
+    }
+
+    let (_, keyword) = DIRECTIVES.iter().find(|(written, _)| *written == word)?;
+    Some((keyword, rest.trim()))
+}
+
+/// Get the part of an automatic variable's path given by a modifier like the `B` in `$(@B)`:
+/// the base name (`B`), or the path without its extension (`R`)
+pub fn path_part(word: &str, part: char) -> &str {
+    let name_start = word.rfind(['/', '\\']).map_or(0, |idx| idx + 1);
+    let end = match word[name_start..].rfind('.') {
+        Some(idx) => name_start + idx,
+        None => word.len(),
+    };
+    match part {
+        'B' => &word[name_start..end],
+        _ => &word[..end],
+    }
+}
+
+/// Take the search paths out of an inference rule like `{src}.c{obj}.obj:`, giving the rule
+/// without them (`.c.obj:`) along with the directory of its sources and of its targets, if the
+/// rule has any paths. `{}` is the current directory, and only the first of several paths (like
+/// `{src;lib}`) is kept.
+pub fn strip_inference_paths(line: &str) -> Option<(String, Option<&str>, Option<&str>)> {
+    let (from, rest) = match take_path(line) {
+        Some((path, rest)) => (Some(path), rest),
+        None => (None, line),
+    };
+    if !rest.starts_with('.') {
+        return None;
+    }
+    let (from_suffix, rest) = rest.split_at(rest.find(['{', ':'])?);
+    let (to, rest) = match take_path(rest) {
+        Some((path, rest)) => (Some(path), rest),
+        None if from.is_some() => (None, rest),
+        None => return None,
+    };
+    Some((format!("{}{}", from_suffix, rest), first_dir(from), first_dir(to)))
+}
+
+/// Get the first directory of an inference rule's path, unless it's the current directory
+fn first_dir(path: Option<&str>) -> Option<&str> {
+    path.and_then(|p| p.split(';').next()).filter(|p| !p.is_empty())
+}
+
+/// Put a pattern in the directory an inference rule's path gives, if it has one
+pub fn in_dir(dir: Option<&str>, pattern: String) -> String {
+    match dir {
+        Some(dir) => format!("{}/{}", dir.trim_end_matches(['/', '\\']), pattern),
+        None => pattern,
+    }
+}
+
+/// Split the `{path}` at the start of part of an inference rule from the rest
+fn take_path(text: &str) -> Option<(&str, &str)> {
+    let end = text.strip_prefix('{')?.find('}')?;
+    Some((text[1..end + 1].trim(), &text[end + 2..]))
+}
+
+/// Split a line into its first word and the rest
+fn split_word(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_directives_in_any_case() {
+        assert_eq!(directive("!IFDEF DEBUG"), Some(("ifdef", "DEBUG")));
+        assert_eq!(directive("! include <win32.mak>"), Some(("include", "<win32.mak>")));
+        assert_eq!(directive("!Else If $(X) == 1"), Some(("elif", "$(X) == 1")));
+        assert_eq!(directive("!ELSE"), Some(("else", "")));
+        assert_eq!(directive("!pragma"), None);
+        assert_eq!(directive("IFDEF DEBUG"), None);
+    }
+
+    #[test]
+    fn gets_parts_of_paths() {
+        assert_eq!(path_part("out\\lib\\app.exe", 'B'), "app");
+        assert_eq!(path_part("out/app.exe", 'R'), "out/app");
+        assert_eq!(path_part("out.d/app", 'R'), "out.d/app");
+    }
+
+    #[test]
+    fn strips_the_paths_of_inference_rules() {
+        let strip = strip_inference_paths;
+        assert_eq!(
+            strip("{src}.c{obj}.obj:"),
+            Some((".c.obj:".to_string(), Some("src"), Some("obj")))
+        );
+        assert_eq!(strip("{src;lib}.c.obj:"), Some((".c.obj:".to_string(), Some("src"), None)));
+        assert_eq!(strip(".c{$(OUT)}.obj:"), Some((".c.obj:".to_string(), None, Some("$(OUT)"))));
+        assert_eq!(strip("{}.c.obj:"), Some((".c.obj:".to_string(), None, None)));
+        // rules without paths are left to be read as suffix rules
+        assert_eq!(strip(".c.obj:"), None);
+        assert_eq!(strip("{src}app.exe:"), None);
+        assert_eq!(in_dir(Some("obj\\"), "%.obj".to_string()), "obj/%.obj");
+    }
+}
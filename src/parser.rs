@@ -2,24 +2,271 @@
 //! Handles parsing a Makefile, line by line
 //!
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
 
 use lazy_static::lazy_static;
 use log::*;
 use regex::Regex;
 
-use crate::types::Target;
+use crate::analysis::glob_to_regex;
+use crate::bsd::{self, Condition};
+use crate::functions::{self, pattern_stem};
+use crate::implicit;
+use crate::kbuild;
+use crate::nmake;
+use crate::rules::RulesFile;
+use crate::types::{
+    Assignment, Diagnostic, Dialect, Flavor, LineKind, Origin, OutputSource, PatternRule,
+    RecipeLine, SearchPath, Severity, Span, Target, Variable, Variant,
+};
+
+/// Optional parser behaviours, set through the builder methods on `Parser`
+#[derive(Clone, Debug)]
+struct ParseOptions {
+    // emit targets that are only declared in .PHONY, without a rule of their own
+    include_declared_only: bool,
+    // how many levels of nested includes to follow
+    max_include_depth: usize,
+    // treat target names that only differ by case as the same target
+    fold_case_targets: bool,
+    // record goals passed to recursive $(MAKE) calls in recipes
+    detect_delegation: bool,
+    // record every assignment made to each variable
+    track_assignments: bool,
+    // directories searched for included files that aren't found relative to the includer
+    include_dirs: Vec<PathBuf>,
+    // refuse to follow includes outside of the root makefile's directory
+    relative_includes_only: bool,
+    // parse the text passed to $(eval) as part of the makefile
+    allow_eval: bool,
+    // run the commands of `!=` assignments, rather than storing the command as the value
+    allow_shell_assign: bool,
+    // run the commands passed to $(shell), rather than substituting a marker
+    eval_shell: bool,
+    // only find targets and prerequisites, skipping recipes and output comments entirely
+    graph_only: bool,
+    // merge `::` rules for the same target into one target with a variant for each rule
+    group_double_colon: bool,
+    // convert backslash path separators in outputs and prerequisites to forward slashes
+    normalize_separators: bool,
+    // expand variables in recipe lines, rather than keeping them as written
+    expand_in_recipes: bool,
+    // expand a recursive variable reference to nothing instead of failing the expansion
+    break_recursion: bool,
+    // longest logical line, in bytes, that's parsed in full
+    max_line_length: Option<usize>,
+    // skip lines longer than the limit, rather than truncating them
+    skip_long_lines: bool,
+    // create concrete targets from pattern rules for the matching files on disk
+    instantiate_patterns: bool,
+    // give `?=` assignments the value of an environment variable with the same name
+    conditional_from_env: bool,
+    // let functions like $(wildcard) look at the filesystem
+    filesystem_access: bool,
+    // character starting recipe lines until .RECIPEPREFIX is set, instead of a tab (or 4 spaces)
+    recipe_prefix: Option<char>,
+    // the variant of make whose directives and variable syntax are parsed
+    dialect: Dialect,
+    // in strict mode, stop parsing when an $(error) call is expanded
+    fatal_error_calls: bool,
+    // understand the Linux kernel's kbuild conventions, like lists of objects in obj-y
+    kbuild: bool,
+    // make's built-in rules were turned off by `-r` in the MAKEFLAGS given before parsing
+    no_builtin_rules: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        ParseOptions {
+            include_declared_only: false,
+            max_include_depth: 32,
+            fold_case_targets: false,
+            detect_delegation: false,
+            track_assignments: false,
+            include_dirs: Vec::new(),
+            relative_includes_only: false,
+            allow_eval: false,
+            allow_shell_assign: false,
+            eval_shell: false,
+            graph_only: false,
+            normalize_separators: false,
+            group_double_colon: false,
+            expand_in_recipes: true,
+            break_recursion: false,
+            max_line_length: None,
+            skip_long_lines: false,
+            instantiate_patterns: false,
+            conditional_from_env: false,
+            filesystem_access: true,
+            recipe_prefix: None,
+            dialect: Dialect::Gnu,
+            fatal_error_calls: false,
+            kbuild: false,
+            no_builtin_rules: false,
+        }
+    }
+}
+
+/// State of one conditional block (`ifeq` ... `endif`) that's being parsed
+#[derive(Clone, Debug)]
+struct Conditional {
+    // whether lines in the current branch are parsed
+    active: bool,
+    // whether any branch so far has been taken, so later `else` branches are skipped
+    taken: bool,
+    // whether the block is inside an active branch of its enclosing block
+    parent_active: bool,
+    // whether a plain `else` has been seen, after which no other branch can follow
+    seen_else: bool,
+}
+
+/// A `define` block whose body is still being read
+#[derive(Clone, Debug)]
+struct Define {
+    name: String,
+    flavor: Flavor,
+    // whether the define was prefixed with `export` or `override`
+    exported: bool,
+    overridden: bool,
+    // the lines of the body so far, as written
+    lines: Vec<String>,
+    // how many nested define blocks the body is currently inside of
+    depth: usize,
+    // whether the block is in an active conditional branch, so the variable is kept
+    active: bool,
+    // line number of the `define` line
+    line: usize,
+}
+
+/// A BSD `.for` loop whose body is still being read
+#[derive(Clone, Debug)]
+struct ForLoop {
+    // the loop variables, which take the values in turn
+    names: Vec<String>,
+    // the list of values as written, expanded once the loop ends
+    values: String,
+    // the lines of the body so far, as written, with their first and last line numbers
+    lines: Vec<(usize, usize, String)>,
+    // how many nested loops the body is currently inside of
+    depth: usize,
+    // whether the loop is in an active conditional branch, so its body is parsed
+    active: bool,
+}
+
+/// A line of a rule kept as written until the whole makefile has been read, since make
+/// expands a recipe only when it runs it, after any variables it uses have been defined
+#[derive(Clone, Debug)]
+struct DeferredLine {
+    // index of the target whose rule the line is part of
+    target: usize,
+    // the line with any custom recipe prefix replaced by a tab, but variables unexpanded
+    line: String,
+    // the rule's own line (whose prerequisites are expanded again), a recipe line, or an
+    // `# Output:` comment
+    kind: LineKind,
+    // where the line is, and the line as written, for spans and diagnostics
+    file: PathBuf,
+    line_number: usize,
+    line_end: usize,
+    written: String,
+}
+
+/// What the shell running a recipe has done that affects its later commands
+#[derive(Clone, Debug, Default)]
+struct ShellState {
+    // directory changed to with `cd`, relative to the makefile's directory unless it's absolute
+    dir: Option<String>,
+    // variables exported with `export NAME=value`, in the order they were exported
+    exports: Vec<(String, String)>,
+}
+
+/// Suffixes known to make without being listed in .SUFFIXES
+const DEFAULT_SUFFIXES: &[&str] = &[
+    ".out", ".a", ".ln", ".o", ".c", ".cc", ".C", ".cpp", ".p", ".f", ".F", ".m", ".r", ".y",
+    ".l", ".ym", ".yl", ".s", ".S", ".mod", ".sym", ".def", ".h", ".info", ".dvi", ".tex",
+    ".texinfo", ".texi", ".txinfo", ".w", ".ch", ".web", ".sh", ".elc", ".el",
+];
+
+/// Callback for each parsed line, taking its line number, text, and kind
+type LineCallback = Box<dyn FnMut(usize, &str, &LineKind)>;
 
 pub struct Parser {
     targets: Vec<Target>,
-    vars: HashMap<String, String>,
+    // rules with a `%` target, split out of the targets once parsing is done
+    pattern_rules: Vec<PatternRule>,
+    // kept sorted so that serialized variables are always in the same order
+    vars: BTreeMap<String, Variable>,
+    // names declared as prerequisites of .PHONY
+    phony: Vec<String>,
+    // special targets like .PRECIOUS, with the prerequisites from all of their rules
+    special_targets: BTreeMap<String, Vec<String>>,
+    // search paths from vpath directives, in the order they were given
+    vpaths: Vec<SearchPath>,
+    // target-specific variables for each target name, which may be set before the target's rule
+    target_vars: HashMap<String, BTreeMap<String, Variable>>,
+    // pattern-specific variables for each `%` pattern, like `%.o: CFLAGS += -fPIC`, which
+    // apply to every target matching the pattern
+    pattern_vars: BTreeMap<String, BTreeMap<String, Variable>>,
+    // suffixes that suffix rules like `.c.o:` can be made from, as listed in .SUFFIXES
+    suffixes: Vec<String>,
+    // files currently being parsed, with the outermost first
+    file_stack: Vec<PathBuf>,
+    // line number of the line being parsed in the innermost file
+    line_number: usize,
+    // line number of the last physical line of the line being parsed
+    line_end: usize,
+    // the line being parsed as written, for finding the columns of what's parsed from it
+    line_text: String,
+    diagnostics: Vec<Diagnostic>,
+    assignments: BTreeMap<String, Vec<Assignment>>,
+    // text passed to $(eval) while expanding the current line, waiting to be parsed
+    pending_eval: Vec<String>,
+    // lines of rules that are expanded once the whole makefile has been read, in order
+    deferred: Vec<DeferredLine>,
+    // text of the comment lines right before the current line, which can describe a rule
+    doc_comments: Vec<String>,
+    // message of an $(error) call expanded while parsing the current line
+    error_call: Option<String>,
+    // whether a recipe line is being expanded, where $(error) and the like are only called
+    // when the recipe is run
+    in_recipe: bool,
+    // delimiter ending the heredoc that the current recipe is in the middle of, which only
+    // continues onto the next line when the whole recipe is run by one shell
+    heredoc: Option<String>,
+    // the shell running the current recipe line, or the whole recipe with .ONESHELL
+    shell: ShellState,
+    // conditional blocks that the current line is inside of, with the innermost last
+    conditionals: Vec<Conditional>,
+    // the define block that the current line is part of
+    define: Option<Define>,
+    // the BSD `.for` loop that the current line is part of
+    for_loop: Option<ForLoop>,
+    // expand undefined variables to nothing rather than failing, as make does in conditions
+    undefined_is_empty: bool,
+    // names of the variables exported with `export`, whether or not they're defined yet
+    exported: HashSet<String>,
+    // whether a bare `export` has exported every variable
+    export_all: bool,
+    options: ParseOptions,
+    // called with the number, text, and kind of every line as it's parsed
+    on_line: Option<LineCallback>,
     match_var_def: Regex,
     match_target_def: Regex,
-    match_output: Vec<Regex>,
+    // output regexes, each with the name reported as the way its outputs were found
+    match_output: Vec<(String, Regex)>,
     match_comment: Regex,
+    match_recipe: Regex,
+    match_directive: Regex,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Parser {
@@ -27,31 +274,608 @@ impl Parser {
     pub fn new() -> Self {
         Parser {
             targets: Vec::<Target>::new(),
-            vars: HashMap::<String, String>::new(),
+            pattern_rules: Vec::new(),
+            vars: BTreeMap::<String, Variable>::new(),
+            phony: Vec::<String>::new(),
+            target_vars: HashMap::new(),
+            pattern_vars: BTreeMap::new(),
+            special_targets: BTreeMap::new(),
+            vpaths: Vec::new(),
+            suffixes: DEFAULT_SUFFIXES.iter().map(|s| s.to_string()).collect(),
+            file_stack: Vec::<PathBuf>::new(),
+            line_number: 0,
+            line_end: 0,
+            line_text: String::new(),
+            diagnostics: Vec::<Diagnostic>::new(),
+            assignments: BTreeMap::<String, Vec<Assignment>>::new(),
+            pending_eval: Vec::<String>::new(),
+            deferred: Vec::new(),
+            doc_comments: Vec::new(),
+            error_call: None,
+            in_recipe: false,
+            heredoc: None,
+            shell: ShellState::default(),
+            conditionals: Vec::<Conditional>::new(),
+            define: None,
+            for_loop: None,
+            undefined_is_empty: false,
+            exported: HashSet::new(),
+            export_all: false,
+            options: ParseOptions::default(),
+            on_line: None,
             // assume that variables have no whitespace in front of them. while this isn't strictly
             // required by Make, in reality it's often an error otherwise.
-            // a make variable name can't contain whitespace, :, #, or =
-            match_var_def: Regex::new(r"^(?P<name>[^\s:#=]+)(\s)*[?:]?=(\s)*(?P<value>[^\n\r#]+)")
-                .unwrap(),
-            // search for lines starting with a word followed by ':'
-            // we specifically need to prevent the .PHONY keyword from being recognized
-            match_target_def: Regex::new(r"^(?P<target>[^.PHONY][^\s:#=]+):").unwrap(),
+            // a make variable name can't contain whitespace, :, #, or =, and is matched lazily
+            // so that a trailing `+` or `?` is taken as part of the operator
+            match_var_def: Regex::new(
+                r"^(?P<name>[^\s:#=]+?)(\s)*(?P<op>[?:+!]?)=[ \t]*(?P<value>[^\n\r]*)",
+            )
+            .unwrap(),
+            // search for lines starting with one or more words followed by ':', and capture
+            // everything up to a recipe separator or comment as the prerequisite list.
+            // names can be paths or contain dashes and dots, but `:=` or `::=` assignments
+            // mustn't be taken as rules (special targets like .PHONY are directives, which are
+            // matched first). `&:` ends a grouped target list
+            match_target_def: Regex::new(
+                r"^(?P<target>([^\s:#=&]|&[^\s:#=])+([ \t]+([^\s:#=&]|&[^\s:#=])+)*)[ \t]*(?P<colons>&?::?)(?P<prereqs>([^:=\n\r#;][^\n\r;]*)?)([#;\r\n]|$)",
+            )
+            .unwrap(),
             // a list of recognized output types
             // requires indentation under a target
             match_output: vec![
                 // match a specific comment with output location specifies
                 // (since comments in Makefiles shouldn't have whitespace before them, this doesn't require it)
                 // NOTE: this regex is reference by index, keep it as the first element of the vector
-                Regex::new(r"( {4}|\t)*#[ \t]*Output[ \t]*:[ \t]*(?P<path>[^\s]+)").unwrap(),
+                (
+                    "annotation".to_string(),
+                    Regex::new(r"( {4}|\t)*#[ \t]*Output[ \t]*:[ \t]*(?P<path>[^\s]+)").unwrap(),
+                ),
                 // match a mkdir command and get the last arg passed to it
-                Regex::new(r"( {4}|\t)+(mkdir)([^\n\r])*\b(?P<path>[^\n\r]+)\b").unwrap(),
+                (
+                    "mkdir".to_string(),
+                    Regex::new(r"( {4}|\t)+(mkdir)([^\n\r])*\b(?P<path>[^\n\r]+)\b").unwrap(),
+                ),
                 // match arbitrary stuff until -o is found
-                Regex::new(r"( {4}|\t)+[^\n\r#]*-o(\s)+(?P<path>[^\s]+)").unwrap(),
+                (
+                    "-o".to_string(),
+                    Regex::new(r"( {4}|\t)+[^\n\r#]*-o(\s)+(?P<path>[^\s]+)").unwrap(),
+                ),
                 // match copied files
-                Regex::new(r"( {4}|\t)+(cp)[^\n\r#]*(\s)+(?P<path>[^\s]+)").unwrap(),
+                (
+                    "cp".to_string(),
+                    Regex::new(r"( {4}|\t)+(cp)[^\n\r#]*(\s)+(?P<path>[^\s]+)").unwrap(),
+                ),
+                // match dependency files written by the compiler with -MF
+                (
+                    "-MF".to_string(),
+                    Regex::new(r"( {4}|\t)+[^\n\r#]*\s-MF\s*(?P<path>[^\s]+)").unwrap(),
+                ),
+                // match the last positional arg of objcopy (`objcopy [flags] in out`), which
+                // also covers cross toolchain prefixes like arm-none-eabi-objcopy.
+                // `strip -o out in` is already covered by the -o regex
+                (
+                    "objcopy".to_string(),
+                    Regex::new(
+                        r"( {4}|\t)+[^\n\r#]*\bobjcopy\b[^\n\r#;&|]*\s(?P<path>[^\s\-;&|][^\s;&|]*)",
+                    )
+                    .unwrap(),
+                ),
+                // match the first file that stdout is redirected to with > or >>, skipping
+                // redirects of other file descriptors like 2> and >&2
+                (
+                    "redirect".to_string(),
+                    Regex::new(r"( {4}|\t)+[^\n\r#]*?(^|[^0-9&>])>>?[ \t]*(?P<path>[^\s;&|<>()]+)")
+                        .unwrap(),
+                ),
             ],
             match_comment: Regex::new(r"^( {4}|\t)*#").unwrap(),
+            // recipe lines are indented under a target
+            match_recipe: Regex::new(r"^( {4}|\t)").unwrap(),
+            // lines handled by make itself rather than defining targets or variables,
+            // including special dot-targets like .PHONY
+            match_directive: Regex::new(
+                r"^(-?include|sinclude|ifeq|ifneq|ifdef|ifndef|else|endif|define|endef|export|unexport|override|undefine|vpath)(\s|$)|^\.[A-Z_]+\s*:([^=]|$)",
+            )
+            .unwrap(),
+        }
+    }
+
+    /// Include targets that are declared in .PHONY but never get a rule of their own.
+    /// By default only targets with a rule definition are returned.
+    pub fn include_declared_only(mut self, enabled: bool) -> Self {
+        self.options.include_declared_only = enabled;
+        self
+    }
+
+    /// Set how many levels of nested includes are followed. Includes past the limit are
+    /// skipped with a diagnostic.
+    pub fn max_include_depth(mut self, depth: usize) -> Self {
+        self.options.max_include_depth = depth;
+        self
+    }
+
+    /// Merge targets whose names only differ by case, keeping the first name's casing
+    pub fn fold_case_targets(mut self, enabled: bool) -> Self {
+        self.options.fold_case_targets = enabled;
+        self
+    }
+
+    /// Record the goals passed to `$(MAKE)` in a target's recipe as targets it delegates to.
+    /// Calls that change directory or makefile with `-C` or `-f` are ignored.
+    pub fn detect_delegation(mut self, enabled: bool) -> Self {
+        self.options.detect_delegation = enabled;
+        self
+    }
+
+    /// Record every assignment made to each variable, not just the final value
+    pub fn track_assignments(mut self, enabled: bool) -> Self {
+        self.options.track_assignments = enabled;
+        self
+    }
+
+    /// Set whether a `?=` assignment to an unset variable first checks the environment, like
+    /// make does, so the environment variable's value is used over the makefile's default
+    pub fn conditional_from_env(mut self, enabled: bool) -> Self {
+        self.options.conditional_from_env = enabled;
+        self
+    }
+
+    /// Set whether includes must stay within the root makefile's directory. When enabled,
+    /// absolute include paths and paths that escape the directory with `..` are refused.
+    pub fn relative_includes_only(mut self, enabled: bool) -> Self {
+        self.options.relative_includes_only = enabled;
+        self
+    }
+
+    /// Set whether text passed to `$(eval)` is parsed like the rest of the makefile, so that
+    /// rules it generates are found. When disabled, `$(eval)` calls are left unexpanded.
+    pub fn allow_eval(mut self, enabled: bool) -> Self {
+        self.options.allow_eval = enabled;
+        self
+    }
+
+    /// Set whether the commands of `!=` assignments are run to get the variable's value. When
+    /// disabled, the command itself is stored as the value.
+    pub fn allow_shell_assign(mut self, enabled: bool) -> Self {
+        self.options.allow_shell_assign = enabled;
+        self
+    }
+
+    /// Set whether the commands passed to `$(shell)` are run to get their output. When
+    /// disabled, a call expands to a marker like `<shell:git describe>` instead.
+    pub fn eval_shell(mut self, enabled: bool) -> Self {
+        self.options.eval_shell = enabled;
+        self
+    }
+
+    /// Set whether to only find targets and their prerequisites. Recipes aren't read at all,
+    /// so no outputs, removals, or delegated goals are found.
+    pub fn graph_only(mut self, enabled: bool) -> Self {
+        self.options.graph_only = enabled;
+        self
+    }
+
+    /// Set whether `::` rules for the same target are merged into a single target, with the
+    /// prerequisites, outputs, and recipe of each rule kept as a separate variant. Otherwise
+    /// each `::` rule is reported as its own target.
+    pub fn group_double_colon(mut self, enabled: bool) -> Self {
+        self.options.group_double_colon = enabled;
+        self
+    }
+
+    /// Set whether variables are expanded in recipe lines. When disabled, recipes are kept as
+    /// written and only the outputs found in them are expanded.
+    pub fn expand_in_recipes(mut self, enabled: bool) -> Self {
+        self.options.expand_in_recipes = enabled;
+        self
+    }
+
+    /// Set whether a variable that refers to itself (directly or through other variables)
+    /// expands the recursive reference to nothing. Otherwise the line it's used in isn't
+    /// expanded at all, which can lose the targets defined on it.
+    pub fn break_recursion(mut self, enabled: bool) -> Self {
+        self.options.break_recursion = enabled;
+        self
+    }
+
+    /// Set the longest logical line, in bytes, that's parsed in full. Longer lines are
+    /// truncated to the limit (or skipped, with `skip_long_lines`) and a diagnostic is recorded.
+    pub fn max_line_length(mut self, length: usize) -> Self {
+        self.options.max_line_length = Some(length);
+        self
+    }
+
+    /// Set whether lines longer than `max_line_length` are skipped instead of truncated
+    pub fn skip_long_lines(mut self, enabled: bool) -> Self {
+        self.options.skip_long_lines = enabled;
+        self
+    }
+
+    /// Set whether pattern rules are instantiated for the files on disk that match their first
+    /// `%` prerequisite, creating a concrete target for each one
+    pub fn instantiate_patterns(mut self, enabled: bool) -> Self {
+        self.options.instantiate_patterns = enabled;
+        self
+    }
+
+    /// Set whether functions like `$(wildcard)` can look at the filesystem. When disabled, they
+    /// are left unexpanded, and so are wildcards in included paths.
+    pub fn filesystem_access(mut self, enabled: bool) -> Self {
+        self.options.filesystem_access = enabled;
+        self
+    }
+
+    /// Set the character that starts recipe lines, like setting .RECIPEPREFIX at the top of the
+    /// makefile. By default a recipe line starts with a tab or four spaces.
+    pub fn recipe_prefix(mut self, prefix: char) -> Self {
+        self.options.recipe_prefix = Some(prefix);
+        self
+    }
+
+    /// Set the variant of make that the makefile is written for, which decides the syntax of
+    /// its directives and variable references. GNU make is the default.
+    pub fn dialect(mut self, dialect: Dialect) -> Self {
+        self.options.dialect = dialect;
+        if dialect == Dialect::Nmake {
+            self.suffixes.extend(nmake::SUFFIXES.iter().map(|s| s.to_string()));
+        }
+        self
+    }
+
+    /// Set whether an `$(error)` call stops parsing in strict mode. Otherwise it's reported as
+    /// a diagnostic, like `$(warning)` and `$(info)`.
+    pub fn fatal_error_calls(mut self, enabled: bool) -> Self {
+        self.options.fatal_error_calls = enabled;
+        self
+    }
+
+    /// Set whether the makefile is read as a kbuild makefile from the Linux kernel, where the
+    /// objects, modules, and archives listed in variables like `obj-y` and `obj-m` are targets
+    /// and undefined `CONFIG_` options are empty
+    pub fn kbuild(mut self, enabled: bool) -> Self {
+        self.options.kbuild = enabled;
+        self
+    }
+
+    /// Set whether Windows-style backslash separators in outputs and prerequisites are
+    /// converted to forward slashes, so the same path is always written the same way
+    pub fn normalize_separators(mut self, enabled: bool) -> Self {
+        self.options.normalize_separators = enabled;
+        self
+    }
+
+    /// Set a callback that's called for every line as it's parsed, with its line number,
+    /// its text as written, and how it was classified
+    pub fn on_line<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(usize, &str, &LineKind) + 'static,
+    {
+        self.on_line = Some(Box::new(callback));
+        self
+    }
+
+    /// Determine what kind of line `line` is, given the current state of the parser.
+    /// Indented lines are only considered part of a recipe once a target has been found.
+    /// No variable expansion is done on the line.
+    pub fn classify_line(&mut self, line: &str) -> LineKind {
+        if line.trim().is_empty() {
+            return LineKind::Blank;
+        }
+
+        if self.match_comment.is_match(line) {
+            return LineKind::Comment;
+        }
+
+        if !self.targets.is_empty() && self.is_recipe(line) {
+            return LineKind::Recipe;
+        }
+
+        // checked before directives, since a rule like `.S:` looks like a special target
+        if let Some(kind) = self.classify_suffix_rule(line) {
+            return kind;
+        }
+
+        if self.match_directive.is_match(line) {
+            return LineKind::Directive;
+        }
+        let dialect_directive = match self.options.dialect {
+            Dialect::Gnu => false,
+            Dialect::Bsd => bsd::directive(line).is_some(),
+            Dialect::Nmake => nmake::directive(line).is_some(),
+        };
+        if dialect_directive {
+            return LineKind::Directive;
+        }
+
+        if let Some(kind) = self.classify_target_variable(line) {
+            return kind;
+        }
+
+        // a static pattern rule is classified as its target pattern
+        if let Some((_, pattern, prereqs)) = split_static_pattern(line) {
+            return LineKind::Target { name: pattern, prereqs, double_colon: false };
+        }
+
+        if let Some(matches) = self.match_target_def.captures(line) {
+            // a rule for several targets is classified by its first one
+            return LineKind::Target {
+                name: matches["target"].split_whitespace().next().unwrap().to_string(),
+                prereqs: matches["prereqs"]
+                    .split_whitespace()
+                    .map(|p| p.to_string())
+                    .collect(),
+                double_colon: matches["colons"].ends_with("::"),
+            };
+        }
+
+        if let Some(matches) = self.match_var_def.captures(line) {
+            let flavor = match &matches["op"] {
+                ":" => Flavor::Simple,
+                "?" => Flavor::Conditional,
+                "+" => Flavor::Append,
+                "!" => Flavor::Shell,
+                _ => Flavor::Recursive,
+            };
+            return LineKind::Variable {
+                name: matches["name"].to_string(),
+                value: matches["value"].to_string(),
+                flavor,
+            };
+        }
+
+        LineKind::Other
+    }
+
+    /// Get the character starting recipe lines, if it's been changed from the default with
+    /// .RECIPEPREFIX (or the parser's option). An empty .RECIPEPREFIX restores the default.
+    fn current_recipe_prefix(&self) -> Option<char> {
+        match self.vars.get(".RECIPEPREFIX") {
+            Some(var) => var.value.chars().next(),
+            None => self.options.recipe_prefix,
+        }
+    }
+
+    /// Whether a line is part of a recipe, based on how it's indented. A tab is still accepted
+    /// with a custom prefix, since recipe lines are given a tab in place of the prefix.
+    fn is_recipe(&self, line: &str) -> bool {
+        // NMAKE's commands can be indented by any amount
+        if self.options.dialect == Dialect::Nmake {
+            return line.starts_with([' ', '\t']);
+        }
+        match self.current_recipe_prefix() {
+            Some(prefix) if prefix != '\t' => line.starts_with([prefix, '\t']),
+            _ => self.match_recipe.is_match(line),
+        }
+    }
+
+    /// Classify a target-specific variable assignment, like `release: CFLAGS += -O3`
+    fn classify_target_variable(&mut self, line: &str) -> Option<LineKind> {
+        lazy_static! {
+            static ref TARGET_VARIABLE: Regex = Regex::new(
+                r"^(?P<targets>[^:#=;\s][^:#=;]*?)[ \t]*::?[ \t]*(?P<assign>[^\s:#=;].*)"
+            )
+            .unwrap();
+        }
+
+        let matches = TARGET_VARIABLE.captures(line)?;
+        match self.classify_line(&matches["assign"]) {
+            LineKind::Variable { name, value, flavor } => Some(LineKind::TargetVariable {
+                targets: matches["targets"].split_whitespace().map(|t| t.to_string()).collect(),
+                name,
+                value,
+                flavor,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Classify an old-style suffix rule, like `.c.o:` or `.c:`, as the equivalent pattern rule
+    /// (`%.o: %.c` or `%: %.c`). Only suffixes listed in .SUFFIXES are recognized. NMAKE's
+    /// inference rules can also give the directories of the files, like `{src}.c{obj}.obj:`
+    /// for `obj/%.obj: src/%.c`.
+    fn classify_suffix_rule(&self, line: &str) -> Option<LineKind> {
+        lazy_static! {
+            static ref SUFFIX_RULE: Regex =
+                Regex::new(r"^(?P<name>\.[^\s:#=%/]+)\s*:\s*(;|#|$)").unwrap();
+        }
+
+        let stripped;
+        let (line, from_dir, to_dir) = match self.options.dialect {
+            Dialect::Nmake => match nmake::strip_inference_paths(line) {
+                Some((rule, from_dir, to_dir)) => {
+                    stripped = rule;
+                    (stripped.as_str(), from_dir, to_dir)
+                }
+                None => (line, None, None),
+            },
+            _ => (line, None, None),
+        };
+        let name = &SUFFIX_RULE.captures(line)?["name"];
+        let (from, to) = self.suffixes.iter().find_map(|from| {
+            let to = name.strip_prefix(from.as_str())?;
+            if to.is_empty() || self.suffixes.iter().any(|s| s == to) {
+                Some((from, to))
+            } else {
+                None
+            }
+        })?;
+
+        debug!("Found suffix rule '{}'", name);
+        Some(LineKind::Target {
+            name: nmake::in_dir(to_dir, format!("%{}", to)),
+            prereqs: vec![nmake::in_dir(from_dir, format!("%{}", from))],
+            double_colon: false,
+        })
+    }
+
+    /// Get the problems found while parsing that didn't stop the analysis
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+
+    /// Get the assignments made to each variable, if they were tracked
+    pub fn assignments(&self) -> &BTreeMap<String, Vec<Assignment>> {
+        &self.assignments
+    }
+
+    /// Get the pattern-specific variables found, for each pattern they apply to
+    pub fn pattern_variables(&self) -> &BTreeMap<String, BTreeMap<String, Variable>> {
+        &self.pattern_vars
+    }
+
+    /// Get the pattern rules found, which aren't included in the targets
+    pub fn pattern_rules(&self) -> &[PatternRule] {
+        &self.pattern_rules
+    }
+
+    /// Get the shell that recipes are run with, if the makefile sets `SHELL`. Like make, a
+    /// `SHELL` from the environment is ignored.
+    pub fn shell(&self) -> Option<String> {
+        match self.vars.get("SHELL") {
+            Some(var) if var.origin != Origin::Environment => Some(var.value.trim().to_string()),
+            _ => None,
+        }
+    }
+
+    /// Whether .ONESHELL has been given, so each recipe is run by a single shell rather than a
+    /// new shell for each line
+    pub fn one_shell(&self) -> bool {
+        self.special_targets.contains_key(".ONESHELL")
+    }
+
+    /// Whether recipes are run by a shell that expands `{a,b}` in words, like bash, rather than
+    /// by make's default of `/bin/sh`
+    fn shell_expands_braces(&self) -> bool {
+        let shell = self.shell().unwrap_or_default();
+        let name = shell
+            .split_whitespace()
+            .map(|word| word.rsplit('/').next().unwrap_or(word))
+            .find(|name| *name != "env");
+        name.is_some_and(|name| ["bash", "zsh", "ksh"].iter().any(|s| name.starts_with(s)))
+    }
+
+    /// Get the special targets found (other than .PHONY), with their prerequisites
+    pub fn special_targets(&self) -> &BTreeMap<String, Vec<String>> {
+        &self.special_targets
+    }
+
+    /// Get the search paths from vpath directives, followed by the directories in `VPATH`
+    pub fn search_paths(&self) -> Vec<SearchPath> {
+        let mut paths = self.vpaths.clone();
+        if let Some(var) = self.vars.get("VPATH") {
+            let directories = split_search_path(&var.value);
+            if !directories.is_empty() {
+                paths.push(SearchPath { pattern: None, directories });
+            }
+        }
+        paths
+    }
+
+    /// Get the names declared as .PHONY
+    pub fn declared_phony(&self) -> &[String] {
+        &self.phony
+    }
+
+    /// Get all variables known to the parser
+    pub fn variables(&self) -> &BTreeMap<String, Variable> {
+        &self.vars
+    }
+
+    /// Apply the options from a `MAKEFLAGS` value that affect parsing: `-I` include
+    /// directories, `NAME=VALUE` variable overrides, and `-r` to turn off the built-in rules.
+    /// Anything else is ignored.
+    pub fn apply_makeflags(&mut self, flags: &str) {
+        self.options.no_builtin_rules |= implicit::disabled_by(flags);
+        let mut words = flags.split_whitespace();
+        while let Some(word) = words.next() {
+            if word == "-I" || word == "--include-dir" {
+                match words.next() {
+                    Some(dir) => self.options.include_dirs.push(PathBuf::from(dir)),
+                    None => debug!("MAKEFLAGS has {} without a directory", word),
+                }
+            } else if let Some(dir) = word.strip_prefix("--include-dir=") {
+                self.options.include_dirs.push(PathBuf::from(dir));
+            } else if let Some(dir) = word.strip_prefix("-I") {
+                self.options.include_dirs.push(PathBuf::from(dir));
+            } else if word == "--" {
+                // separates the flags from the variable overrides
+            } else if !word.starts_with('-') && word.contains('=') {
+                let (name, value) = word.split_once('=').unwrap();
+                debug!("MAKEFLAGS override: {}={}", name, value);
+                self.set_variable(name, value, Origin::CommandLine);
+            } else {
+                debug!("Ignoring MAKEFLAGS option '{}'", word);
+            }
+        }
+    }
+
+    /// Whether make's built-in rules are turned off, by `-r` in the flags given to
+    /// `apply_makeflags` or in a `MAKEFLAGS` value set by the makefile
+    pub fn builtin_rules_disabled(&self) -> bool {
+        self.options.no_builtin_rules
+            || self.vars.get("MAKEFLAGS").is_some_and(|v| implicit::disabled_by(&v.value))
+    }
+
+    /// Set a variable's value directly, as if it had been defined before parsing
+    pub fn set_variable(&mut self, name: &str, value: &str, origin: Origin) {
+        self.vars.insert(
+            name.to_string(),
+            Variable::new(value.to_string(), Flavor::Recursive, origin),
+        );
+    }
+
+    /// Load extra output detection rules and default variables from a rules file
+    pub fn load_rules_file<P: AsRef<Path>>(&mut self, filepath: P) -> Result<(), String> {
+        let rules = RulesFile::load(filepath)?;
+
+        for rule in &rules.outputs {
+            debug!("Adding output rule for '{}'", rule.tool);
+            self.match_output.push((format!("rule:{}", rule.tool), rule.to_regex()?));
+        }
+        for (name, value) in &rules.variables {
+            self.set_variable(name, value, Origin::Default);
+        }
+
+        Ok(())
+    }
+
+    /// Seed the variable map from a `.env`-style file of `KEY=VALUE` lines. Blank lines and
+    /// `#` comments are ignored, and values may optionally be quoted.
+    pub fn load_env_file<P: AsRef<Path>>(&mut self, filepath: P) -> Result<(), String> {
+        let filepath = filepath.as_ref();
+
+        let contents = match std::fs::read_to_string(filepath) {
+            Ok(c) => c,
+            Err(e) => return Err(format!("Couldn't read {}: {}", filepath.display(), e)),
+        };
+
+        // `lines` also takes off the `\r` of Windows line endings, but not a byte order mark
+        for line in contents.trim_start_matches('\u{feff}').lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            // allow lines written for the shell, like `export KEY=VALUE`
+            let line = line.strip_prefix("export ").unwrap_or(line);
+
+            match line.split_once('=') {
+                Some((key, value)) => {
+                    let value = value.trim();
+                    let value = value
+                        .strip_prefix('"')
+                        .and_then(|v| v.strip_suffix('"'))
+                        .or_else(|| value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')))
+                        .unwrap_or(value);
+
+                    debug!("env file variable {}={}", key.trim(), value);
+                    self.set_variable(key.trim(), value, Origin::Environment);
+                }
+                None => debug!("Skipping env file line '{}'", line),
+            }
         }
+
+        Ok(())
     }
 
     pub fn parse_file<P: AsRef<Path>>(
@@ -59,176 +883,5066 @@ impl Parser {
         filepath: P,
         strict: bool,
     ) -> Result<Vec<Target>, String> {
-        let filepath = filepath.as_ref();
+        self.set_builtin_variables(filepath.as_ref());
+        self.parse_path(filepath.as_ref(), strict)?;
 
-        // open the file for line-by-line reading
-        let file = match File::open(filepath) {
-            Ok(f) => f,
-            Err(e) => return Err(format!("Couldn't open {}: {}", filepath.display(), e)),
-        };
-        let mut reader = BufReader::new(file);
+        // recipes (and rules using variables that weren't defined yet) are expanded now that
+        // every variable has its final value
+        self.expand_deferred(strict)?;
 
-        // check each line in the file to see if it matches
-        loop {
-            let mut line = String::new();
-            match reader.read_line(&mut line) {
-                Ok(len) => {
-                    // eof
-                    if len == 0 {
-                        break;
-                    }
+        // kbuild lists the objects to build in variables rather than writing rules for them
+        if self.options.kbuild {
+            self.infer_kbuild_targets(filepath.as_ref());
+        }
 
-                    debug!("line: '{}'", line.trim_end());
+        // add any targets that were only ever declared phony
+        if self.options.include_declared_only {
+            for name in &self.phony {
+                if !self.targets.iter().any(|t| &t.name == name) {
+                    debug!("Adding declared-only target '{}'", name);
+                    self.targets.push(Target::new(name.clone()));
+                }
+            }
+        }
 
-                    // match against comments that aren't the special Output commment
-                    if self.match_comment.is_match(&line) && !self.match_output[0].is_match(&line) {
-                        continue;
-                    }
+        // a rule with several targets (or a static pattern rule) defines each one separately
+        self.expand_rule_targets();
 
-                    // resolve any variables in the line
-                    match self.eval_variable(&line, vec![]) {
-                        Ok(evald) => line = evald,
-                        Err(e) => {
-                            // if strict mode is enabled, failing to eval a variable is an error
-                            if strict {
-                                return Err(format!("Line variable expansion failed: {}", e));
-                            }
-                            // otherwise, continue with non-evaluated line
-                            else {
-                                debug!("Eval of {} failed; skipping evaluation", line.trim_end());
-                                // continue;
-                            }
-                        }
-                    };
+        // pattern rules aren't targets themselves, but can be instantiated for files on disk
+        let (patterns, targets) = std::mem::take(&mut self.targets)
+            .into_iter()
+            .partition(|t| PatternRule::is_pattern(&t.name));
+        self.targets = targets;
+        self.pattern_rules = patterns.iter().map(PatternRule::of).collect();
+        if self.options.instantiate_patterns {
+            for rule in &self.pattern_rules {
+                self.targets.extend(instantiate_pattern(rule));
+            }
+        }
 
-                    // match against makefile targets
-                    if let Some(matches) = self.match_target_def.captures(&line) {
-                        debug!("Found target '{}'", &matches["target"]);
+        // prerequisites escaped for .SECONDEXPANSION are expanded once everything is defined
+        self.expand_second(strict)?;
 
-                        let mut t = Target::new(matches["target"].to_string());
+        // merge any duplicates
+        self.merge_duplicates();
 
-                        if self.targets.is_empty() {
-                            t.default = true;
-                        }
-                        self.targets.push(t);
-
-                        // add a variable with the name `@` that will resolve to the current target
-                        self.vars
-                            .insert("@".to_string(), matches["target"].to_string());
-                    }
-                    // match against variables
-                    else if let Some(matches) = self.match_var_def.captures(&line) {
-                        // add the new variable to the variable map
-                        self.vars
-                            .insert(matches["name"].to_string(), matches["value"].to_string());
-                    }
-                    // match against output types
-                    else if !self.targets.is_empty()
-                    {
-                        // match the first output type found
-                        for (i, output) in self.match_output.iter().enumerate() {
-                            if let Some(matches) = output.captures(&line) {
-                                debug!("Found output match on output regex {}", i);
-                                // get the value of the output
-                                let val = matches["path"].to_string();
-                                debug!("output: '{}'", val);
-
-                                let idx = self.targets.len() - 1;
-                                self.targets[idx].output
-                                    .get_or_insert(Vec::<String>::new())
-                                    .push(val);
-                            }
-                        }
-                    }
-                }
-                Err(e) => return Err(format!("Failed to read from file: {:?}", e)),
+        // .DEFAULT_GOAL replaces the first target as the default, if it names a target
+        let goal = self.vars.get(".DEFAULT_GOAL").map(|v| v.value.trim().to_string());
+        if let Some(goal) = goal.filter(|g| self.targets.iter().any(|t| &t.name == g)) {
+            debug!("Default goal is '{}'", goal);
+            for target in &mut self.targets {
+                target.default = target.name == goal;
             }
         }
-
-        // remove any duplicates
-        // self.targets.dedup_by_key(|target| &target.name);
-        self.targets.dedup();
         for target in &mut self.targets {
+            target.phony = self.phony.contains(&target.name);
+            target.attributes = special_attributes(&self.special_targets, &target.name);
+            if let Some(scope) = self.target_vars.get(&target.name) {
+                target.variables = scope.clone();
+            }
+
+            if self.options.normalize_separators {
+                for path in target.output.iter_mut().flatten() {
+                    *path = normalize_separators(path);
+                }
+                for source in &mut target.output_sources {
+                    source.output = normalize_separators(&source.output);
+                }
+                for path in &mut target.prerequisites {
+                    *path = normalize_separators(path);
+                }
+                for path in &mut target.order_only {
+                    *path = normalize_separators(path);
+                }
+            }
+
             if let Some(outputs) = &mut target.output {
-                outputs.dedup();
+                dedup_in_order(outputs);
             }
+            dedup_in_order(&mut target.prerequisites);
+            dedup_in_order(&mut target.order_only);
+            dedup_in_order(&mut target.templated_outputs);
+            dedup_in_order(&mut target.removes);
+            dedup_in_order(&mut target.delegates_to);
         }
 
         Ok(self.targets.clone())
     }
 
-    /// Evaluate a variable recursively until the actual value is determined, using other
-    ///  variables as necessary
-    fn eval_variable(&mut self, value: &str, deps: Vec<&str>) -> Result<String, String> {
-        // look for variable matches, and if found recursively resolve them
-        lazy_static! {
-            // only match $@:
-            //  non-enclosed variable names can only be a single character in make,
-            //  so just accept the ones we want to resolve
-            static ref SELFVAR: Regex = Regex::new(r"\$(?P<value>@)").unwrap();
-            // match $(varname) (parenthesis var)
-            static ref PVAR: Regex = Regex::new(r"\$\((?P<value>[^\s:#={}()\[\]/\\]+)\)").unwrap();
-            // match ${varname} (curly brace var)
-            static ref CVAR: Regex = Regex::new(r"\$\{(?P<value>[^\s:#={}()\[\]/\\]+)\}").unwrap();
-        }
-
-        let mut new = value.to_string();
-        debug!("running eval on '{}'", new.trim_end());
-
-        // try matching against different variable types
-        while let Some(range) = SELFVAR
-            .find(&new)
-            .or_else(|| PVAR.find(&new))
-            .or_else(|| CVAR.find(&new))
-        {
-            // convert the regex lib's range to a rust range
-            let range = range.start()..range.end();
-
-            // get the relevant section of the value
-            let wrapped_var = &new[range.clone()];
-            debug!("wrapped var: '{}'", wrapped_var);
-
-            // unwrap the variable name
-            let varname = if vec!["${", "$("].contains(&&wrapped_var[0..2]) {
-                &wrapped_var[2..(wrapped_var.len() - 1)]
-            } else {
-                &wrapped_var[1..wrapped_var.len()]
+    /// Define the variables that make sets itself, unless they've already been given a value.
+    /// CURDIR is the makefile's directory, as if make was run from there.
+    fn set_builtin_variables(&mut self, filepath: &Path) {
+        let dir = filepath.parent().unwrap_or_else(|| Path::new(""));
+        let curdir = match std::env::current_dir() {
+            Ok(cwd) => lexical_normalize(&cwd.join(dir)),
+            Err(_) => dir.to_path_buf(),
+        };
+        let make = match self.options.dialect {
+            Dialect::Nmake => "nmake",
+            _ => "make",
+        };
+
+        let builtins = [
+            ("CURDIR", curdir.display().to_string()),
+            ("MAKE", make.to_string()),
+            ("MAKECMDGOALS", String::new()),
+            ("MAKEFILE_LIST", String::new()),
+            (".DEFAULT_GOAL", String::new()),
+        ];
+        for (name, value) in builtins {
+            if !self.vars.contains_key(name) {
+                self.set_variable(name, &value, Origin::Default);
+            }
+        }
+
+        // kbuild makefiles refer to their own directory as `$(obj)` and `$(src)`
+        if self.options.kbuild {
+            let dir = match dir.display().to_string() {
+                dir if dir.is_empty() => ".".to_string(),
+                dir => dir,
             };
-            debug!("found variable named {}", varname);
+            for name in &["obj", "src"] {
+                if !self.vars.contains_key(*name) {
+                    self.set_variable(name, &dir, Origin::Default);
+                }
+            }
+        }
+    }
+
+    /// Add the targets that kbuild would build from the makefile's `obj-y`, `obj-m`, `lib-y`,
+    /// and `extra-y` lists. They're merged with any rules the makefile has for the same files.
+    fn infer_kbuild_targets(&mut self, filepath: &Path) {
+        let dir_of = |parser: &Self, name: &str| {
+            parser.vars.get(name).map_or_else(|| ".".to_string(), |v| v.value.clone())
+        };
+        let (obj, src) = (dir_of(self, "obj"), dir_of(self, "src"));
+        let look_on_disk = self.options.filesystem_access;
+
+        let undefined_is_empty = std::mem::replace(&mut self.undefined_is_empty, true);
+        let mut value = |name: &str| match self.eval_variable(&format!("$({})", name), &[]) {
+            Ok(value) => value.split_whitespace().map(String::from).collect(),
+            Err(e) => {
+                debug!("Couldn't expand kbuild variable '{}': {}", name, e);
+                Vec::new()
+            }
+        };
+        let mut targets = kbuild::infer_targets(&mut value, &obj, &src, look_on_disk);
+        self.undefined_is_empty = undefined_is_empty;
 
-            // make sure the variable doesn't already exist up the dependency chain
-            if deps.contains(&varname) {
-                return Err(format!("Variable {} has a recursive dependency", varname));
+        for target in &mut targets {
+            target.file = Some(filepath.display().to_string());
+        }
+        self.targets.extend(targets);
+    }
+
+    /// Replace each rule that defines several targets with a target for each one. A rule like
+    /// `a b: c` gives targets that share the same prerequisites, recipe, and outputs, while a
+    /// static pattern rule has the `%` in its prerequisites and outputs replaced by the part of
+    /// each target's name that matched the target pattern.
+    fn expand_rule_targets(&mut self) {
+        let mut expanded = Vec::with_capacity(self.targets.len());
+        for mut rule in std::mem::take(&mut self.targets) {
+            if rule.static_targets.is_empty() {
+                // each target of a group is an output of the recipe they share
+                if !rule.group.is_empty() {
+                    rule.output.get_or_insert_with(Vec::new).extend(rule.group.clone());
+                }
+                let others = std::mem::take(&mut rule.other_names);
+                let copies: Vec<Target> = others
+                    .into_iter()
+                    .map(|name| Target { name, default: false, ..rule.clone() })
+                    .collect();
+                expanded.push(rule);
+                expanded.extend(copies);
+                continue;
+            }
+
+            for (i, name) in rule.static_targets.iter().enumerate() {
+                let stem = match pattern_stem(&rule.name, name) {
+                    Some(stem) => stem,
+                    None => {
+                        let span = rule.spans.first();
+                        self.diagnostics.push(Diagnostic {
+                            severity: Severity::Warning,
+                            message: format!(
+                                "Target '{}' doesn't match the target pattern '{}'",
+                                name, rule.name
+                            ),
+                            file: span.map(|s| s.file.clone()),
+                            line: span.map(|s| s.line_start),
+                        });
+                        continue;
+                    }
+                };
+                debug!("Expanding static pattern {} for {}", rule.name, name);
+                let mut target = substitute_stem(&rule, stem);
+                target.name = name.clone();
+                target.default = rule.default && i == 0;
+                target.static_targets.clear();
+                expanded.push(target);
             }
+        }
+        self.targets = expanded;
+    }
 
-            // get the variable value from the value map
-            let value = if self.vars.contains_key(varname) {
-                self.vars[varname].clone()
+    /// Merge targets defined by more than one rule into the first definition, combining their
+    /// prerequisites and outputs
+    fn merge_duplicates(&mut self) {
+        let fold_case = self.options.fold_case_targets;
+        let key = |name: &str| {
+            if fold_case {
+                name.to_lowercase()
             } else {
-                return Err(format!("No variable '{}'", varname));
-            };
-            debug!("variable value {}", value);
+                name.to_string()
+            }
+        };
+
+        let group_double_colon = self.options.group_double_colon;
+
+        let mut merged: Vec<Target> = Vec::with_capacity(self.targets.len());
+        let mut indices: HashMap<String, usize> = HashMap::new();
+
+        for mut target in self.targets.drain(..) {
+            // each :: rule is a separate target unless they're being grouped
+            if target.double_colon && !group_double_colon {
+                merged.push(target);
+                continue;
+            }
 
-            // recusrively evaluate variable values
-            match self.eval_variable(&value, {
-                let mut newdeps = deps.clone();
-                newdeps.push(&varname);
-                newdeps
-            }) {
-                Ok(evald) => {
-                    debug!("replacing '{}' with '{}'", &new[range.clone()], &evald);
-                    // replace the variable with its value in the value string
-                    new.replace_range(range, &evald);
+            match indices.get(&key(&target.name)) {
+                Some(&idx) => {
+                    debug!("Merging duplicate target '{}'", target.name);
+                    let first = &mut merged[idx];
+                    if target.double_colon {
+                        first.variants.push(Variant::of(&target));
+                    }
+                    first.default |= target.default;
+                    if first.description.is_none() {
+                        first.description = target.description;
+                    }
+                    first.prerequisites.extend(target.prerequisites);
+                    first.order_only.extend(target.order_only);
+                    first.removes.extend(target.removes);
+                    first.recipe_exports.extend(target.recipe_exports);
+                    first.delegates_to.extend(target.delegates_to);
+                    first.raw.prerequisites.extend(target.raw.prerequisites);
+                    first.raw.outputs.extend(target.raw.outputs);
+                    first.spans.extend(target.spans);
+                    first.recipe.extend(target.recipe);
+                    first.templated_outputs.extend(target.templated_outputs);
+                    first.output_sources.extend(target.output_sources);
+                    if let Some(outputs) = target.output {
+                        first.output.get_or_insert(Vec::new()).extend(outputs);
+                    }
                 }
-                Err(e) => {
-                    return Err(format!("Failure to parse variable: {}", e));
+                None => {
+                    if target.double_colon {
+                        target.variants.push(Variant::of(&target));
+                    }
+                    indices.insert(key(&target.name), merged.len());
+                    merged.push(target);
                 }
             }
         }
 
-        debug!("eval'd line: '{}'", new.trim_end());
+        self.targets = merged;
+    }
 
-        // return the new value
-        Ok(new)
+    /// Parse the lines of a single file (which may be included from another)
+    fn parse_path(&mut self, filepath: &Path, strict: bool) -> Result<(), String> {
+        // open the file for line-by-line reading
+        let file = match File::open(filepath) {
+            Ok(f) => f,
+            Err(e) => return Err(format!("Couldn't open {}: {}", filepath.display(), e)),
+        };
+        let mut reader = BufReader::new(file);
+
+        self.file_stack.push(filepath.to_path_buf());
+        // each file is added to MAKEFILE_LIST as it's read
+        if let Some(var) = self.vars.get_mut("MAKEFILE_LIST") {
+            if !var.value.is_empty() {
+                var.value.push(' ');
+            }
+            var.value.push_str(&filepath.display().to_string());
+        }
+        // conditionals can't span files, so any opened in this file must be closed in it
+        let conditional_depth = self.conditionals.len();
+
+        // check each line in the file to see if it matches
+        let mut line_number = 1;
+        let result = loop {
+            let logical = match read_logical_line(&mut reader, self.options.max_line_length) {
+                Ok(Some(l)) => l,
+                Ok(None) => break Ok(()),
+                Err(e) => break Err(e),
+            };
+            let (mut line, count) = (logical.text, logical.count);
+
+            // a byte order mark (as written by some Windows editors) isn't part of the first line
+            if line_number == 1 && line.starts_with('\u{feff}') {
+                line.remove(0);
+            }
+
+            // restore the line number in case an include changed it
+            self.line_number = line_number;
+            self.line_end = line_number + count - 1;
+            line_number += count;
+
+            // a file in some other encoding is still parsed, as well as it can be
+            if logical.invalid_utf8 {
+                let message = "Line isn't valid UTF-8; invalid bytes were replaced".to_string();
+                self.diagnose(Severity::Warning, message);
+            }
+
+            // keep pathological lines from reaching the regexes
+            match self.options.max_line_length {
+                Some(max) if logical.length > max => {
+                    if self.options.skip_long_lines {
+                        let length = logical.length;
+                        let message =
+                            format!("Skipping line of {} bytes (limit is {})", length, max);
+                        self.diagnose(Severity::Warning, message);
+                        continue;
+                    }
+
+                    let message =
+                        format!("Truncating line of {} bytes to {}", logical.length, max);
+                    self.diagnose(Severity::Warning, message);
+                    let mut end = max.min(line.len());
+                    while !line.is_char_boundary(end) {
+                        end -= 1;
+                    }
+                    line.truncate(end);
+                }
+                _ => (),
+            }
+
+            if let Err(e) = self.parse_line(line, strict) {
+                break Err(e);
+            }
+
+            // an $(error) call in the line only stops parsing once the line has been parsed
+            if let Some(message) = self.error_call.take() {
+                if strict && self.options.fatal_error_calls {
+                    break Err(format!("$(error) called: {}", message));
+                }
+            }
+        };
+
+        let result = result.and_then(|_| {
+            if let Some(block) = self.define.take() {
+                let message = format!("Missing endef for '{}'", block.name);
+                if strict {
+                    return Err(message);
+                }
+                self.diagnose(Severity::Warning, message);
+            }
+            if self.conditionals.len() > conditional_depth {
+                let message = format!("Missing endif at the end of {}", filepath.display());
+                if strict {
+                    return Err(message);
+                }
+                self.diagnose(Severity::Warning, message);
+            }
+            Ok(())
+        });
+        self.conditionals.truncate(conditional_depth);
+
+        self.file_stack.pop();
+        result
+    }
+
+    /// Parse a single logical line, updating the targets & variables found so far
+    fn parse_line(&mut self, mut line: String, strict: bool) -> Result<(), String> {
+        debug!("line: '{}'", line.trim_end());
+        self.line_text = line.clone();
+
+        // the body of a define is kept as-is, rather than parsed as makefile lines
+        if self.options.dialect == Dialect::Gnu && self.parse_define(&line) {
+            if let Some(callback) = self.on_line.as_mut() {
+                callback(self.line_number, &line, &LineKind::Directive);
+            }
+            return Ok(());
+        }
+
+        // the body of a `.for` loop is parsed once the whole loop has been read
+        if self.options.dialect == Dialect::Bsd && self.parse_for_loop(&line, strict)? {
+            return Ok(());
+        }
+
+        // a trailing comment isn't part of the line, though the callback gets it as written
+        let written = line.clone();
+        let recipe = !self.targets.is_empty() && self.is_recipe(&line);
+        line = strip_comment(&line, recipe);
+
+        let kind = self.classify_line(&line);
+        if let Some(callback) = self.on_line.as_mut() {
+            callback(self.line_number, &written, &kind);
+        }
+
+        // a block of unindented comments describes the rule right after it, if there is one
+        let doc_comments = match kind {
+            LineKind::Comment
+                if !line.starts_with(char::is_whitespace)
+                    && !self.match_output[0].1.is_match(&line) =>
+            {
+                self.doc_comments.push(line.trim_start_matches('#').trim().to_string());
+                Vec::new()
+            }
+            _ => std::mem::take(&mut self.doc_comments),
+        };
+
+        // a recipe line can't be a conditional, but is still skipped in an inactive branch
+        if kind != LineKind::Recipe && self.parse_conditional(&line, strict)? {
+            return Ok(());
+        }
+        if !self.in_active_branch() {
+            debug!("Skipping line in inactive conditional branch");
+            return Ok(());
+        }
+
+        // a recipe extends the span of the rule it belongs to, and any custom prefix is replaced
+        // with a tab, which the output regexes expect
+        if kind == LineKind::Recipe {
+            if let Some(prefix) = self.current_recipe_prefix().filter(|c| *c != '\t') {
+                line = format!("\t{}", &line[prefix.len_utf8()..]);
+            }
+            if let Some(span) = self.targets.last_mut().and_then(|t| t.spans.last_mut()) {
+                span.line_end = self.line_end;
+            }
+        }
+
+        // skip blank lines & comments that aren't the special Output comment
+        match kind {
+            LineKind::Blank => return Ok(()),
+            LineKind::Comment if !self.match_output[0].1.is_match(&line) => return Ok(()),
+            LineKind::Recipe | LineKind::Comment if self.options.graph_only => return Ok(()),
+            _ => (),
+        }
+
+        // keep the line as written, before variables are expanded
+        let raw = line.clone();
+
+        // an assignment's value is expanded based on its flavor when it's assigned, rather than
+        // along with the rest of the line
+        let assignment = match &kind {
+            LineKind::Variable { .. } | LineKind::TargetVariable { .. } => true,
+            LineKind::Directive => match split_modifiers(&line) {
+                Some((_, rest)) => matches!(self.classify_line(&rest), LineKind::Variable { .. }),
+                None => false,
+            },
+            _ => false,
+        };
+
+        // a recipe is expanded once the whole makefile has been read, like make does when it runs
+        // the recipe, so it can use variables defined after its rule
+        if matches!(kind, LineKind::Recipe | LineKind::Comment) && !self.targets.is_empty() {
+            self.defer(kind, line);
+            return Ok(());
+        }
+
+        // resolve any variables in the line
+        let mut deferred_rule = false;
+        if !assignment && (kind != LineKind::Recipe || self.options.expand_in_recipes) {
+            match self.expand_line(&line, &kind) {
+                Ok(evald) => line = evald,
+                // a rule can use variables defined later in the makefile, so its prerequisites
+                // are expanded again once it's all been read
+                Err(e) if matches!(kind, LineKind::Target { .. }) => {
+                    debug!("Deferring expansion of {}: {}", line.trim_end(), e);
+                    deferred_rule = true;
+                    let undefined_is_empty = std::mem::replace(&mut self.undefined_is_empty, true);
+                    if let Ok(evald) = self.eval_variable(&line, &[]) {
+                        line = evald;
+                    }
+                    self.undefined_is_empty = undefined_is_empty;
+                }
+                Err(e) => {
+                    // if strict mode is enabled, failing to eval a variable is an error
+                    if strict {
+                        return Err(format!("Line variable expansion failed: {}", e));
+                    }
+                    // otherwise, continue with non-evaluated line
+                    else {
+                        debug!("Eval of {} failed; skipping evaluation", line.trim_end());
+                    }
+                }
+            };
+        }
+
+        // rules generated by $(eval) are defined before the rest of the line is parsed
+        for text in std::mem::take(&mut self.pending_eval) {
+            for eval_line in text.lines() {
+                self.parse_line(eval_line.to_string(), strict)?;
+            }
+        }
+
+        // parsing the lines generated by $(eval) replaced the line being parsed
+        self.line_text = written;
+
+        match self.classify_line(&line) {
+            // match against makefile targets
+            LineKind::Target { name, prereqs, double_colon } => {
+                debug!("Found target '{}'", name);
+
+                // a heredoc or a shell can't continue past the end of a recipe
+                self.heredoc = None;
+                self.shell = ShellState::default();
+
+                let mut t = Target::new(name.clone());
+                t.file = Some(self.current_file());
+                (t.prerequisites, t.order_only) = split_order_only(prereqs);
+                if !self.special_targets.contains_key(".SECONDEXPANSION") {
+                    unescape_prerequisites(&mut t);
+                }
+                t.double_colon = double_colon;
+                // a trailing `##` comment takes precedence over the comments before the rule
+                t.description = match doc_comment(&self.line_text) {
+                    Some(text) => Some(text),
+                    None if !doc_comments.is_empty() => Some(doc_comments.join(" ")),
+                    None => None,
+                };
+                if let Some((targets, _, _)) = split_static_pattern(&line) {
+                    t.static_targets = targets;
+                } else if let Some(matches) = self.match_target_def.captures(&line) {
+                    let names = matches["target"].split_whitespace().skip(1);
+                    t.other_names = names.map(|n| n.to_string()).collect();
+                    // the targets of a grouped rule are all made by one run of its recipe
+                    if matches["colons"].starts_with('&') {
+                        let names = matches["target"].split_whitespace();
+                        t.group = names.map(|n| n.to_string()).collect();
+                    }
+                }
+                t.spans.push(self.current_span(&name));
+                if let LineKind::Target { name, prereqs, .. } = self.classify_line(&raw) {
+                    t.raw.name = name;
+                    t.raw.prerequisites = split_order_only(prereqs).0;
+                }
+
+                // pattern rules can't be the default goal, but a static pattern rule's first
+                // target can be
+                let pattern = PatternRule::is_pattern;
+                let first = t.static_targets.first().unwrap_or(&name);
+                if !pattern(first) && self.targets.iter().all(|t| pattern(&t.name)) {
+                    t.default = true;
+                    // .DEFAULT_GOAL is the first target, unless the makefile has already set it
+                    if self.vars.get(".DEFAULT_GOAL").is_none_or(|v| v.value.trim().is_empty()) {
+                        self.set_variable(".DEFAULT_GOAL", first, Origin::Default);
+                    }
+                }
+                self.targets.push(t);
+                if deferred_rule {
+                    let prereqs = Vec::new();
+                    let kind = LineKind::Target { name: name.clone(), prereqs, double_colon };
+                    self.defer(kind, raw.clone());
+                }
+
+                // anything after a ';' is the first line of the recipe
+                let recipe = inline_recipe(&raw).filter(|_| !self.options.graph_only);
+                if let Some(recipe) = recipe {
+                    self.defer(LineKind::Recipe, format!("\t{}", recipe));
+                }
+            }
+            // match against variables
+            LineKind::Variable { name, value, flavor } => {
+                let name = self.expand_line_part(&name, strict)?;
+                let value = self.expand_assignment(&name, value, flavor, strict)?;
+                self.assign_variable(name, value, flavor, false);
+            }
+            LineKind::TargetVariable { targets, name, value, flavor } => {
+                let targets = self.expand_line_part(&targets.join(" "), strict)?;
+                let name = self.expand_line_part(&name, strict)?;
+                let value = self.expand_assignment(&name, value, flavor, strict)?;
+                for target in targets.split_whitespace() {
+                    self.assign_target_variable(target.to_string(), &name, &value, flavor);
+                }
+            }
+            LineKind::Directive => {
+                self.parse_directive(&line, strict)?;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    /// Expand part of a line that's parsed before it's expanded, like an assignment's name.
+    /// Outside of strict mode, text that fails to expand is kept as written.
+    fn expand_line_part(&mut self, text: &str, strict: bool) -> Result<String, String> {
+        if !text.contains('$') {
+            return Ok(text.to_string());
+        }
+        match self.eval_variable(text, &[]) {
+            Ok(evald) => Ok(evald),
+            Err(e) if strict => Err(format!("Line variable expansion failed: {}", e)),
+            Err(e) => {
+                debug!("Eval of {} failed: {}; skipping evaluation", text, e);
+                Ok(text.to_string())
+            }
+        }
+    }
+
+    /// Expand the value of an assignment if its flavor expands it when it's assigned: `:=`,
+    /// `!=`, and `+=` to a simply expanded variable. Any other value is kept as written, and
+    /// expanded each time the variable is used.
+    fn expand_assignment(
+        &mut self,
+        name: &str,
+        value: String,
+        flavor: Flavor,
+        strict: bool,
+    ) -> Result<String, String> {
+        let immediate = match flavor {
+            Flavor::Simple | Flavor::Shell => true,
+            Flavor::Append => {
+                matches!(self.vars.get(name), Some(var) if var.flavor == Flavor::Simple)
+            }
+            Flavor::Recursive | Flavor::Conditional => false,
+        };
+        if immediate {
+            self.expand_line_part(&value, strict)
+        } else {
+            Ok(value)
+        }
+    }
+
+    /// Assign a variable defined in the makefile, unless it's been overridden. An `override`
+    /// assignment replaces variables set on the command line.
+    fn assign_variable(
+        &mut self,
+        name: String,
+        mut value: String,
+        flavor: Flavor,
+        overridden: bool,
+    ) {
+        if self.options.track_assignments {
+            self.record_assignment(&name, flavor.operator(), &value);
+        }
+
+        // variables set on the command line (or with override) take precedence over the makefile
+        if !overridden {
+            match self.vars.get(&name) {
+                Some(var) if var.origin == Origin::CommandLine => {
+                    debug!("'{}' was set on the command line; skipping assignment", name);
+                    return;
+                }
+                Some(var) if var.overridden => {
+                    debug!("'{}' was set with override; skipping assignment", name);
+                    return;
+                }
+                _ => (),
+            }
+        }
+
+        // conditional assignments don't replace an existing value
+        if flavor == Flavor::Conditional && self.vars.contains_key(&name) {
+            debug!("'{}' is already set; skipping ?= assignment", name);
+            return;
+        }
+        if flavor == Flavor::Conditional && self.options.conditional_from_env {
+            if let Ok(env_value) = std::env::var(&name) {
+                debug!("'{}' is set in the environment; skipping ?= assignment", name);
+                self.vars.insert(
+                    name,
+                    Variable::new(env_value, Flavor::Recursive, Origin::Environment),
+                );
+                return;
+            }
+        }
+
+        if flavor == Flavor::Shell && self.options.allow_shell_assign {
+            value = self.run_shell(&value);
+        }
+
+        // appending keeps the flavor of the existing variable, or creates a recursive one
+        if flavor == Flavor::Append {
+            match self.vars.get_mut(&name) {
+                Some(var) if var.value.is_empty() => var.value = value,
+                Some(var) => {
+                    var.value.push(' ');
+                    var.value.push_str(&value);
+                }
+                None => self.insert_file_variable(name, value, Flavor::Recursive, overridden),
+            }
+            return;
+        }
+
+        self.insert_file_variable(name, value, flavor, overridden);
+    }
+
+    /// Set the automatic variables for the most recent rule, so they resolve in its recipe.
+    /// Every prerequisite is taken to be newer than the target for `$?`, and a pattern rule's
+    /// stem is left as `%` so it's filled in when the rule is instantiated.
+    fn set_automatic_variables(&mut self) {
+        let automatic = match self.targets.last() {
+            Some(t) => self.automatic_values(t),
+            None => return,
+        };
+        for (name, value) in automatic {
+            self.set_variable(name, &value, Origin::Automatic);
+        }
+    }
+
+    /// Get the value of each automatic variable for a rule
+    fn automatic_values(&self, target: &Target) -> [(&'static str, String); 7] {
+        let mut unique = target.prerequisites.clone();
+        dedup_in_order(&mut unique);
+        let stem = if PatternRule::is_pattern(&target.name) {
+            "%".to_string()
+        } else {
+            // an explicit rule's stem is its name without a known suffix
+            self.suffixes
+                .iter()
+                .find_map(|s| target.name.strip_suffix(s.as_str()))
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        [
+            ("@", target.name.clone()),
+            ("<", target.prerequisites.first().cloned().unwrap_or_default()),
+            ("^", unique.join(" ")),
+            ("+", target.prerequisites.join(" ")),
+            ("?", unique.join(" ")),
+            ("|", target.order_only.join(" ")),
+            ("*", stem),
+        ]
+    }
+
+    /// Expand the prerequisites left escaped with `$$` after .SECONDEXPANSION again, now that
+    /// every variable is defined. Each rule's automatic variables are set while its own
+    /// prerequisites are expanded, so references like `$$@` or `$$(@:.o=.c)` resolve.
+    fn expand_second(&mut self, strict: bool) -> Result<(), String> {
+        if !self.special_targets.contains_key(".SECONDEXPANSION") {
+            return Ok(());
+        }
+        for i in 0..self.targets.len() {
+            let target = &self.targets[i];
+            if !target.prerequisites.iter().chain(&target.order_only).any(|p| p.contains("$$")) {
+                continue;
+            }
+            let lists = [target.prerequisites.join(" "), target.order_only.join(" ")];
+
+            let mut saved = Vec::new();
+            for (name, value) in self.automatic_values(target) {
+                saved.push((name.to_string(), self.vars.get(name).cloned()));
+                self.set_variable(name, &value, Origin::Automatic);
+            }
+
+            let mut expanded = Vec::new();
+            for text in lists {
+                let text = text.replace("$$", "$");
+                match self.eval_variable(&text, &[]) {
+                    // what's still escaped is unescaped by this expansion, like by the first
+                    Ok(evald) => expanded.push(evald.replace("$$", "$")),
+                    Err(e) if strict => {
+                        self.leave_target_scope(saved);
+                        return Err(format!("Second expansion failed: {}", e));
+                    }
+                    Err(e) => {
+                        debug!("Second expansion of '{}' failed: {}", text, e);
+                        expanded.push(text);
+                    }
+                }
+            }
+            self.leave_target_scope(saved);
+
+            let words = |s: &str| s.split_whitespace().map(|w| w.to_string()).collect();
+            let target = &mut self.targets[i];
+            target.prerequisites = words(&expanded[0]);
+            target.order_only = words(&expanded[1]);
+        }
+        Ok(())
+    }
+
+    /// Expand the variables in a line, using the current target's own variables if the line is
+    /// part of its recipe
+    fn expand_line(&mut self, line: &str, kind: &LineKind) -> Result<String, String> {
+        let scope = match kind {
+            LineKind::Recipe => self.enter_target_scope(),
+            _ => Vec::new(),
+        };
+        self.in_recipe = *kind == LineKind::Recipe;
+        let evald = self.eval_variable(line, &[]);
+        self.in_recipe = false;
+        self.leave_target_scope(scope);
+        evald
+    }
+
+    /// Keep a line of the most recent target's rule to be expanded once the whole makefile has
+    /// been read
+    fn defer(&mut self, kind: LineKind, line: String) {
+        self.deferred.push(DeferredLine {
+            target: self.targets.len() - 1,
+            line,
+            kind,
+            file: self.file_stack.last().cloned().unwrap_or_default(),
+            line_number: self.line_number,
+            line_end: self.line_end,
+            written: self.line_text.clone(),
+        });
+    }
+
+    /// Expand the lines of rules kept while reading, now that every variable they could use
+    /// has its final value. The targets are put back one at a time, so each one's lines are
+    /// handled as if its rule had just been read.
+    fn expand_deferred(&mut self, strict: bool) -> Result<(), String> {
+        let mut deferred = std::mem::take(&mut self.deferred).into_iter().peekable();
+        for target in std::mem::take(&mut self.targets) {
+            self.targets.push(target);
+            let idx = self.targets.len() - 1;
+            self.heredoc = None;
+            self.shell = ShellState::default();
+            self.set_automatic_variables();
+
+            while let Some(deferred) = deferred.next_if(|d| d.target == idx) {
+                self.file_stack.push(deferred.file);
+                self.line_number = deferred.line_number;
+                self.line_end = deferred.line_end;
+                self.line_text = deferred.written;
+                let result = self.expand_deferred_line(deferred.kind, deferred.line, strict);
+                self.file_stack.pop();
+                result?;
+            }
+        }
+
+        // automatic variables only have values inside of a rule's recipe
+        self.vars.retain(|_, var| var.origin != Origin::Automatic);
+        Ok(())
+    }
+
+    /// Expand a line kept by `defer` and handle it like a line that was just read
+    fn expand_deferred_line(
+        &mut self,
+        kind: LineKind,
+        raw: String,
+        strict: bool,
+    ) -> Result<(), String> {
+        let expanded = if kind == LineKind::Recipe && !self.options.expand_in_recipes {
+            Ok(raw.clone())
+        } else {
+            self.expand_line(&raw, &kind)
+        };
+        let line = match expanded {
+            Ok(line) => line,
+            Err(e) if strict => return Err(format!("Line variable expansion failed: {}", e)),
+            Err(e) => {
+                debug!("Eval of {} failed; skipping evaluation: {}", raw.trim_end(), e);
+                raw.clone()
+            }
+        };
+        // rules generated by $(eval) in a recipe would only be defined when it's run
+        self.pending_eval.clear();
+
+        match kind {
+            LineKind::Target { .. } => {
+                let second_expansion = self.special_targets.contains_key(".SECONDEXPANSION");
+                if let (LineKind::Target { prereqs, .. }, Some(target)) =
+                    (self.classify_line(&line), self.targets.last_mut())
+                {
+                    (target.prerequisites, target.order_only) = split_order_only(prereqs);
+                    if !second_expansion {
+                        unescape_prerequisites(target);
+                    }
+                }
+                self.set_automatic_variables();
+            }
+            // a line that expands to nothing isn't run
+            LineKind::Recipe if line.trim().is_empty() => (),
+            LineKind::Recipe => self.parse_recipe(&line, &raw),
+            _ => self.detect_outputs(&line, &raw, None),
+        }
+        Ok(())
+    }
+
+    /// Set a variable in a target's scope, or a pattern's if the target is a `%` pattern.
+    /// Repeated appends are combined, so the scoped value is still appended to the global value
+    /// when the recipe is expanded.
+    fn assign_target_variable(&mut self, target: String, name: &str, value: &str, flavor: Flavor) {
+        debug!("Variable for {}: {} {} {}", target, name, flavor.operator(), value);
+        let scope = if PatternRule::is_pattern(&target) {
+            self.pattern_vars.entry(target).or_default()
+        } else {
+            self.target_vars.entry(target).or_default()
+        };
+        match scope.get_mut(name) {
+            Some(var) if flavor == Flavor::Append => {
+                var.value.push(' ');
+                var.value.push_str(value);
+            }
+            Some(_) if flavor == Flavor::Conditional => {
+                debug!("'{}' is already set for the target; skipping ?= assignment", name);
+            }
+            _ => {
+                let var = Variable::new(value.to_string(), flavor, Origin::File);
+                scope.insert(name.to_string(), var);
+            }
+        }
+    }
+
+    /// Make the current target's variables visible while expanding one of its recipe lines,
+    /// returning the values they replaced so they can be restored afterwards. The variables of
+    /// patterns the target matches come first, with those of longer stems first like in make,
+    /// so the more specific ones take precedence.
+    fn enter_target_scope(&mut self) -> Vec<(String, Option<Variable>)> {
+        let target = match self.targets.last() {
+            Some(target) => target.name.clone(),
+            None => return Vec::new(),
+        };
+        let mut patterns: Vec<(usize, &BTreeMap<String, Variable>)> = self
+            .pattern_vars
+            .iter()
+            .filter_map(|(pattern, scope)| Some((pattern_stem(pattern, &target)?.len(), scope)))
+            .collect();
+        patterns.sort_by_key(|(stem_len, _)| std::cmp::Reverse(*stem_len));
+        let scopes: Vec<BTreeMap<String, Variable>> = patterns
+            .into_iter()
+            .map(|(_, scope)| scope)
+            .chain(self.target_vars.get(&target))
+            .cloned()
+            .collect();
+
+        let mut saved = Vec::new();
+        for (name, var) in scopes.into_iter().flatten() {
+            let previous = self.vars.get(&name).cloned();
+            let value = match (&previous, var.flavor) {
+                (Some(prev), Flavor::Append) => format!("{} {}", prev.value, var.value),
+                (Some(_), Flavor::Conditional) => continue,
+                _ => var.value,
+            };
+            self.vars.insert(name.clone(), Variable::new(value, Flavor::Recursive, Origin::File));
+            saved.push((name, previous));
+        }
+        saved
+    }
+
+    /// Restore the variables replaced by `enter_target_scope`
+    fn leave_target_scope(&mut self, saved: Vec<(String, Option<Variable>)>) {
+        for (name, previous) in saved.into_iter().rev() {
+            match previous {
+                Some(var) => self.vars.insert(name, var),
+                None => self.vars.remove(&name),
+            };
+        }
+    }
+
+    /// Add a variable defined in the makefile to the variable map, exporting it if its name
+    /// has been exported
+    fn insert_file_variable(
+        &mut self,
+        name: String,
+        value: String,
+        flavor: Flavor,
+        overridden: bool,
+    ) {
+        let mut var = Variable::new(value, flavor, Origin::File);
+        var.exported = self.export_all || self.exported.contains(&name);
+        var.overridden = overridden;
+        var.span = Some(self.current_span(&name));
+        self.vars.insert(name, var);
+    }
+
+    /// Handle an `export`, `unexport`, or `override` directive, either before an assignment or
+    /// with a list of variable names to export or unexport. A bare `export` exports everything.
+    /// An assignment is passed in as written, while a list of names has already been expanded.
+    fn parse_modifiers(&mut self, modifiers: &str, rest: &str, strict: bool) -> Result<(), String> {
+        let modifiers: Vec<&str> = modifiers.split_whitespace().collect();
+        let export = !modifiers.contains(&"unexport") && modifiers.contains(&"export");
+        let overridden = modifiers.contains(&"override");
+
+        if let LineKind::Variable { name, value, flavor } = self.classify_line(rest) {
+            let name = self.expand_line_part(&name, strict)?;
+            let value = self.expand_assignment(&name, value, flavor, strict)?;
+            if modifiers.iter().any(|m| *m != "override") {
+                self.set_exported(&name, export);
+            }
+            self.assign_variable(name, value, flavor, overridden);
+            return Ok(());
+        }
+
+        if self.parse_undefine(rest, overridden) {
+            return Ok(());
+        }
+
+        // `override` only applies to assignments
+        if modifiers.iter().all(|m| *m == "override") {
+            debug!("Ignoring override without an assignment");
+            return Ok(());
+        }
+
+        if rest.trim().is_empty() {
+            debug!("Setting export of all variables to {}", export);
+            self.export_all = export;
+            for var in self.vars.values_mut() {
+                var.exported = export;
+            }
+            return Ok(());
+        }
+
+        for name in rest.split_whitespace() {
+            self.set_exported(name, export);
+        }
+        Ok(())
+    }
+
+    /// Handle an `undefine` directive, which removes a variable as if it had never been set.
+    /// Like an assignment, it only removes a variable set on the command line or with
+    /// `override` if it's given with `override` too. Returns whether the line was one.
+    fn parse_undefine(&mut self, line: &str, overridden: bool) -> bool {
+        lazy_static! {
+            static ref UNDEFINE: Regex =
+                Regex::new(r"^undefine\s+(?P<name>[^#\r\n]*)").unwrap();
+        }
+
+        let name = match UNDEFINE.captures(line) {
+            Some(matches) => matches["name"].trim().to_string(),
+            None => return false,
+        };
+        if self.options.track_assignments {
+            self.record_assignment(&name, "undefine", "");
+        }
+
+        if !overridden {
+            match self.vars.get(&name) {
+                Some(var) if var.origin == Origin::CommandLine || var.overridden => {
+                    debug!("'{}' was set on the command line or with override; keeping it", name);
+                    return true;
+                }
+                _ => (),
+            }
+        }
+
+        debug!("Undefining '{}'", name);
+        self.vars.remove(&name);
+        true
+    }
+
+    /// Mark a variable as exported (or not), including when it's defined later
+    fn set_exported(&mut self, name: &str, export: bool) {
+        debug!("Setting export of '{}' to {}", name, export);
+        if export {
+            self.exported.insert(name.to_string());
+        } else {
+            self.exported.remove(name);
+        }
+        if let Some(var) = self.vars.get_mut(name) {
+            var.exported = export;
+        }
+    }
+
+    /// Run a command for `$(shell)` or a `!=` assignment, returning its output with newlines
+    /// replaced by spaces like Make does. A command that can't be run gives an empty value.
+    fn run_shell(&mut self, command: &str) -> String {
+        debug!("Running shell command: {}", command);
+        match Command::new("sh").arg("-c").arg(command).output() {
+            Ok(output) => {
+                if !output.status.success() {
+                    self.diagnose(
+                        Severity::Warning,
+                        format!("Shell command '{}' exited with {}", command, output.status),
+                    );
+                }
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                stdout.trim_end_matches('\n').replace('\n', " ")
+            }
+            Err(e) => {
+                self.diagnose(
+                    Severity::Warning,
+                    format!("Couldn't run shell command '{}': {}", command, e),
+                );
+                String::new()
+            }
+        }
+    }
+
+    /// Handle the lines of a `define` block, collecting the body until the matching `endef`
+    /// into a single multi-line variable. Returns whether the line was part of a block.
+    fn parse_define(&mut self, line: &str) -> bool {
+        lazy_static! {
+            static ref DEFINE: Regex = Regex::new(
+                r"^\s*(?P<mods>((export|override)\s+)*)define\s+(?P<name>[^\s:#=?+]+)\s*(?P<op>[?:+]?=)?\s*(#.*)?$"
+            )
+            .unwrap();
+            static ref ENDEF: Regex = Regex::new(r"^\s*endef\s*(#.*)?$").unwrap();
+        }
+
+        let block = match &mut self.define {
+            Some(block) => block,
+            None => {
+                let matches = match DEFINE.captures(line) {
+                    Some(m) => m,
+                    None => return false,
+                };
+                let flavor = match matches.name("op").map(|m| m.as_str()) {
+                    Some(":=") => Flavor::Simple,
+                    Some("?=") => Flavor::Conditional,
+                    Some("+=") => Flavor::Append,
+                    _ => Flavor::Recursive,
+                };
+                debug!("Start of define for '{}'", &matches["name"]);
+                let modifiers = &matches["mods"];
+                self.define = Some(Define {
+                    name: matches["name"].to_string(),
+                    flavor,
+                    exported: modifiers.contains("export"),
+                    overridden: modifiers.contains("override"),
+                    lines: Vec::new(),
+                    depth: 0,
+                    active: self.in_active_branch(),
+                    line: self.line_number,
+                });
+                return true;
+            }
+        };
+
+        // nested defines are part of the body, and end with their own endef
+        if DEFINE.is_match(line) {
+            block.depth += 1;
+        } else if ENDEF.is_match(line) {
+            if block.depth == 0 {
+                let block = self.define.take().unwrap();
+                if block.active {
+                    self.end_define(block);
+                }
+                return true;
+            }
+            block.depth -= 1;
+        }
+
+        block.lines.push(line.trim_end_matches(['\r', '\n']).to_string());
+        true
+    }
+
+    /// Store the variable defined by a finished `define` block
+    fn end_define(&mut self, block: Define) {
+        let mut value = block.lines.join("\n");
+        debug!("define {} = '{}'", block.name, value);
+
+        // a simply-expanded definition is expanded once, when it's defined
+        if block.flavor == Flavor::Simple {
+            if let Ok(expanded) = self.eval_variable(&value, &[]) {
+                value = expanded;
+            }
+        }
+
+        if block.exported {
+            self.set_exported(&block.name, true);
+        }
+        let name = block.name.clone();
+        self.assign_variable(block.name, value, block.flavor, block.overridden);
+
+        // the definition started at the `define` line, rather than the `endef`
+        if let Some(span) = self.vars.get_mut(&name).and_then(|v| v.span.as_mut()) {
+            if span.line_end == self.line_end {
+                span.line_start = block.line;
+            }
+        }
+    }
+
+    /// Handle the lines of a BSD `.for` loop, collecting the body until the matching `.endfor`
+    /// and then parsing it for each value. Returns whether the line was part of a loop.
+    fn parse_for_loop(&mut self, line: &str, strict: bool) -> Result<bool, String> {
+        lazy_static! {
+            static ref FOR: Regex =
+                Regex::new(r"^(?P<names>\S+(\s+\S+)*?)\s+in(\s+(?P<values>.*))?$").unwrap();
+        }
+
+        let stripped = strip_comment(line, false);
+        let directive = bsd::directive(&stripped);
+        let block = match &mut self.for_loop {
+            Some(block) => block,
+            None => {
+                let rest = match directive {
+                    Some(("for", rest)) => rest,
+                    _ => return Ok(false),
+                };
+                let matches = match FOR.captures(rest) {
+                    Some(m) => m,
+                    None => {
+                        let message = format!("Invalid .for loop '{}'", rest);
+                        if strict {
+                            return Err(message);
+                        }
+                        self.diagnose(Severity::Warning, message);
+                        return Ok(true);
+                    }
+                };
+                debug!("Start of .for loop over '{}'", &matches["names"]);
+                self.for_loop = Some(ForLoop {
+                    names: matches["names"].split_whitespace().map(|n| n.to_string()).collect(),
+                    values: matches.name("values").map_or("", |v| v.as_str()).to_string(),
+                    lines: Vec::new(),
+                    depth: 0,
+                    active: self.in_active_branch(),
+                });
+                if let Some(callback) = self.on_line.as_mut() {
+                    callback(self.line_number, line, &LineKind::Directive);
+                }
+                return Ok(true);
+            }
+        };
+
+        // nested loops are part of the body, and end with their own .endfor
+        match directive.map(|(keyword, _)| keyword) {
+            Some("for") => block.depth += 1,
+            Some("endfor") if block.depth == 0 => {
+                let block = self.for_loop.take().unwrap();
+                if let Some(callback) = self.on_line.as_mut() {
+                    callback(self.line_number, line, &LineKind::Directive);
+                }
+                if block.active {
+                    self.run_for_loop(block, strict)?;
+                }
+                return Ok(true);
+            }
+            Some("endfor") => block.depth -= 1,
+            _ => (),
+        }
+
+        block.lines.push((self.line_number, self.line_end, line.to_string()));
+        Ok(true)
+    }
+
+    /// Parse the body of a finished `.for` loop once for each group of values, with the loop
+    /// variables replaced by the values
+    fn run_for_loop(&mut self, block: ForLoop, strict: bool) -> Result<(), String> {
+        let values = self.expand_line_part(&block.values, strict)?;
+        let values: Vec<&str> = values.split_whitespace().collect();
+        let (line_number, line_end) = (self.line_number, self.line_end);
+
+        for group in values.chunks(block.names.len()) {
+            for (start, end, line) in &block.lines {
+                let mut line = line.clone();
+                for (name, value) in block.names.iter().zip(group) {
+                    line = substitute_loop_variable(&line, name, value);
+                }
+                // lines in the body are reported where they're written
+                self.line_number = *start;
+                self.line_end = *end;
+                self.parse_line(line, strict)?;
+            }
+        }
+
+        self.line_number = line_number;
+        self.line_end = line_end;
+        Ok(())
+    }
+
+    /// Handle a conditional directive (`ifeq`, `ifneq`, `ifdef`, `ifndef`, `else`, or
+    /// `endif`), updating which branches are active. Returns whether the line was one.
+    /// In BSD make they're `.if`, `.ifdef`, `.ifndef`, `.ifmake`, and `.ifnmake`, with `.elif`
+    /// (and the like) in place of `else if`, and NMAKE's `!IF` directives are handled the same.
+    fn parse_conditional(&mut self, line: &str, strict: bool) -> Result<bool, String> {
+        let dialect = self.options.dialect;
+        let line = line.trim();
+        let directive = match dialect {
+            Dialect::Gnu => Some(split_keyword(line)),
+            Dialect::Bsd => bsd::directive(line),
+            Dialect::Nmake => nmake::directive(line),
+        };
+        let (keyword, rest) = match directive {
+            Some(directive) => directive,
+            None => return Ok(false),
+        };
+        let is_condition = |keyword: &str| match dialect {
+            Dialect::Gnu => ["ifeq", "ifneq", "ifdef", "ifndef"].contains(&keyword),
+            Dialect::Bsd => ["if", "ifdef", "ifndef", "ifmake", "ifnmake"].contains(&keyword),
+            Dialect::Nmake => ["if", "ifdef", "ifndef"].contains(&keyword),
+        };
+
+        // a branch after the first can have a condition of its own, like `else ifdef NAME`
+        let gnu = dialect == Dialect::Gnu;
+        let branch = match keyword {
+            "else" if !gnu => Some(None),
+            "else" => {
+                let (cond_keyword, cond) = split_keyword(rest);
+                Some(Some((cond_keyword, cond)).filter(|(k, _)| is_condition(k)))
+            }
+            _ if !gnu => {
+                let cond_keyword = keyword.strip_prefix("el").filter(|k| is_condition(k));
+                cond_keyword.map(|k| Some((k, rest)))
+            }
+            _ => None,
+        };
+
+        let enclosing_active = self.in_active_branch();
+        if is_condition(keyword) {
+            // conditions inside a skipped branch aren't evaluated at all
+            let result = enclosing_active && self.eval_condition(keyword, rest, strict)?;
+            self.conditionals.push(Conditional {
+                active: result,
+                taken: result,
+                parent_active: enclosing_active,
+                seen_else: false,
+            });
+        } else if let Some(condition) = branch {
+            let top = match self.conditionals.last() {
+                Some(top) if !top.seen_else => top.clone(),
+                _ => return self.unbalanced_conditional(keyword, strict).map(|_| true),
+            };
+
+            let active = if !top.parent_active || top.taken {
+                false
+            } else if let Some((cond_keyword, cond)) = condition {
+                self.eval_condition(cond_keyword, cond, strict)?
+            } else {
+                true
+            };
+
+            let top = self.conditionals.last_mut().unwrap();
+            top.active = active;
+            top.taken |= active;
+            top.seen_else = condition.is_none();
+        } else if keyword == "endif" {
+            if self.conditionals.pop().is_none() {
+                self.unbalanced_conditional("endif", strict)?;
+            }
+        } else {
+            return Ok(false);
+        }
+
+        debug!("Conditional '{}'; active: {}", line, self.in_active_branch());
+        Ok(true)
+    }
+
+    /// Check whether lines are currently being parsed, rather than skipped as part of a
+    /// conditional branch that isn't taken
+    fn in_active_branch(&self) -> bool {
+        self.conditionals.last().is_none_or(|c| c.active)
+    }
+
+    /// Report an `else` or `endif` that doesn't belong to a conditional
+    fn unbalanced_conditional(&mut self, keyword: &str, strict: bool) -> Result<(), String> {
+        let message = format!("Found {} without a matching conditional", keyword);
+        if strict {
+            return Err(message);
+        }
+        self.diagnose(Severity::Warning, message);
+        Ok(())
+    }
+
+    /// Evaluate the condition of a conditional directive against the current variables
+    fn eval_condition(&mut self, keyword: &str, args: &str, strict: bool) -> Result<bool, String> {
+        if self.options.dialect != Dialect::Gnu {
+            return self.eval_bsd_condition(keyword, args, strict);
+        }
+
+        // comments can follow a condition, but can't be inside one
+        let args = match (keyword, args.find('#')) {
+            ("ifdef" | "ifndef", Some(idx)) => args[..idx].trim(),
+            _ => args,
+        };
+
+        match keyword {
+            "ifdef" | "ifndef" => {
+                // the variable is defined if it has a non-empty value
+                let name = self.expand_condition(args.trim());
+                let defined = self.vars.get(&name).is_some_and(|v| !v.value.is_empty());
+                Ok(defined == (keyword == "ifdef"))
+            }
+            _ => {
+                let (left, right) = match split_condition(args) {
+                    Some(pair) => pair,
+                    None => {
+                        let message = format!("Invalid condition '{} {}'", keyword, args);
+                        if strict {
+                            return Err(message);
+                        }
+                        self.diagnose(Severity::Warning, message);
+                        return Ok(false);
+                    }
+                };
+                let equal = self.expand_condition(&left) == self.expand_condition(&right);
+                Ok(equal == (keyword == "ifeq"))
+            }
+        }
+    }
+
+    /// Evaluate the expression of a BSD conditional, like `.if defined(DEBUG) && ${CC} == "gcc"`.
+    /// A bare word is given to the directive's function, which is `make` for `.ifmake` and
+    /// `defined` for the rest, and `.ifndef` and `.ifnmake` negate the result. NMAKE's `!IF`
+    /// expressions have the same syntax.
+    fn eval_bsd_condition(
+        &mut self,
+        keyword: &str,
+        args: &str,
+        strict: bool,
+    ) -> Result<bool, String> {
+        let default = if keyword.ends_with("make") { "make" } else { "defined" };
+        let negate = keyword == "ifndef" || keyword == "ifnmake";
+        let result = match bsd::parse_condition(args, default) {
+            Ok(condition) => self.eval_bsd_expression(&condition),
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(result) => Ok(result != negate),
+            Err(e) => {
+                let message = format!("Invalid condition '{} {}': {}", keyword, args, e);
+                if strict {
+                    return Err(message);
+                }
+                self.diagnose(Severity::Warning, message);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Evaluate a parsed BSD conditional expression
+    fn eval_bsd_expression(&mut self, condition: &Condition) -> Result<bool, String> {
+        // a quoted value is compared without its quotes
+        let value = |parser: &mut Self, text: &str| {
+            let text = text.strip_prefix('"').and_then(|t| t.strip_suffix('"')).unwrap_or(text);
+            parser.expand_condition(text)
+        };
+
+        match condition {
+            Condition::Not(inner) => Ok(!self.eval_bsd_expression(inner)?),
+            Condition::And(left, right) => {
+                Ok(self.eval_bsd_expression(left)? && self.eval_bsd_expression(right)?)
+            }
+            Condition::Or(left, right) => {
+                Ok(self.eval_bsd_expression(left)? || self.eval_bsd_expression(right)?)
+            }
+            Condition::Call(function, arg) => {
+                let arg = self.expand_condition(arg);
+                // NMAKE's function names can be in any case, and it has `exist` for `exists`
+                match function.to_ascii_lowercase().as_str() {
+                    "defined" => Ok(self.is_defined(&arg)),
+                    "empty" => {
+                        let value = self.expand_condition(&format!("${{{}}}", arg));
+                        Ok(value.trim().is_empty())
+                    }
+                    // goals can only be given through MAKECMDGOALS, since make isn't being run
+                    "make" => Ok(self
+                        .vars
+                        .get("MAKECMDGOALS")
+                        .is_some_and(|v| v.value.split_whitespace().any(|g| g == arg))),
+                    "target" => Ok(self.targets.iter().any(|t| t.name == arg)),
+                    "commands" => {
+                        Ok(self.targets.iter().any(|t| t.name == arg && !t.recipe.is_empty()))
+                    }
+                    "exists" | "exist" => {
+                        let dir = self.file_stack.last().and_then(|f| f.parent());
+                        let path = dir.map_or(PathBuf::from(&arg), |d| d.join(&arg));
+                        Ok(self.options.filesystem_access && path.exists())
+                    }
+                    _ => Err(format!("Unknown function '{}'", function)),
+                }
+            }
+            Condition::Value(text) => {
+                let text = value(self, text);
+                match text.trim().parse::<f64>() {
+                    Ok(number) => Ok(number != 0.0),
+                    Err(_) => Ok(!text.trim().is_empty()),
+                }
+            }
+            Condition::Compare(left, op, right) => {
+                let (left, right) = (value(self, left), value(self, right));
+                let numbers = (left.trim().parse::<f64>(), right.trim().parse::<f64>());
+                match (numbers, op.as_str()) {
+                    ((Ok(l), Ok(r)), op) => Ok(match op {
+                        "==" => l == r,
+                        "!=" => l != r,
+                        "<" => l < r,
+                        "<=" => l <= r,
+                        ">" => l > r,
+                        _ => l >= r,
+                    }),
+                    (_, "==") => Ok(left == right),
+                    (_, "!=") => Ok(left != right),
+                    _ => Err(format!("Can't compare '{}' {} '{}'", left, op, right)),
+                }
+            }
+        }
+    }
+
+    /// Whether a variable is defined, even if its value is empty
+    fn is_defined(&self, name: &str) -> bool {
+        let name = match bsd::local_variable(name) {
+            Some(local) if self.options.dialect == Dialect::Bsd => local,
+            _ => name,
+        };
+        self.vars.contains_key(name)
+            || self.automatic_part(name).is_some()
+            || self.bsd_builtin(name).is_some()
+    }
+
+    /// Get the value of one of BSD make's variables describing the makefile being read:
+    /// `.CURDIR` (the root makefile's directory), `.PARSEDIR`, or `.PARSEFILE`
+    fn bsd_builtin(&self, name: &str) -> Option<String> {
+        if self.options.dialect != Dialect::Bsd {
+            return None;
+        }
+        let dir = |file: &PathBuf| match file.parent()?.display().to_string() {
+            dir if dir.is_empty() => Some(".".to_string()),
+            dir => Some(dir),
+        };
+        match name {
+            ".CURDIR" => self.file_stack.first().and_then(dir),
+            ".PARSEDIR" => self.file_stack.last().and_then(dir),
+            ".PARSEFILE" => {
+                let file = self.file_stack.last()?.file_name()?;
+                Some(file.to_string_lossy().to_string())
+            }
+            _ => None,
+        }
+    }
+
+    /// Expand part of a condition, where undefined variables are empty. If the text can't be
+    /// expanded, it's compared as written.
+    fn expand_condition(&mut self, text: &str) -> String {
+        self.undefined_is_empty = true;
+        let expanded = self.eval_variable(text, &[]);
+        self.undefined_is_empty = false;
+        expanded.unwrap_or_else(|_| text.to_string())
+    }
+
+    /// Handle a directive line, recording any information relevant to the targets
+    fn parse_directive(&mut self, line: &str, strict: bool) -> Result<(), String> {
+        lazy_static! {
+            static ref PHONY: Regex = Regex::new(r"^\.PHONY\s*:(?P<names>[^#;\r\n]*)").unwrap();
+            static ref SUFFIXES: Regex =
+                Regex::new(r"^\.SUFFIXES\s*:(?P<names>[^#;\r\n]*)").unwrap();
+            static ref SPECIAL: Regex =
+                Regex::new(r"^(?P<target>\.[A-Z_]+)\s*:(?P<names>[^#;\r\n]*)").unwrap();
+            static ref INCLUDE: Regex =
+                Regex::new(r"^(?P<kind>-?include|sinclude)\s+(?P<paths>[^#\r\n]*)").unwrap();
+            static ref VPATH: Regex =
+                Regex::new(r"^vpath(\s+(?P<pattern>[^\s#]+)(?P<dirs>[^#\r\n]*))?").unwrap();
+        }
+
+        let directive = match self.options.dialect {
+            Dialect::Gnu => None,
+            Dialect::Bsd => bsd::directive(line),
+            Dialect::Nmake => nmake::directive(line),
+        };
+        if let Some((keyword, rest)) = directive {
+            return self.parse_bsd_directive(keyword, rest, strict);
+        }
+
+        if let Some((modifiers, rest)) = split_modifiers(line) {
+            return self.parse_modifiers(&modifiers, &rest, strict);
+        }
+
+        if self.parse_undefine(line, false) {
+            return Ok(());
+        }
+
+        if let Some(matches) = INCLUDE.captures(line) {
+            // missing files are only an error for a plain `include`
+            let optional = &matches["kind"] != "include";
+            for path in matches["paths"].split_whitespace() {
+                for path in self.expand_include_glob(path) {
+                    self.include(&path, optional, strict)?;
+                }
+            }
+        }
+
+        if let Some(matches) = PHONY.captures(line) {
+            for name in matches["names"].split_whitespace() {
+                debug!("Declared phony: '{}'", name);
+                self.phony.push(name.to_string());
+            }
+        }
+
+        if let Some(matches) = SPECIAL.captures(line) {
+            let target = &matches["target"];
+            if target != ".PHONY" {
+                debug!("Found special target {}", target);
+                let names = matches["names"].split_whitespace().map(|n| n.to_string());
+                self.special_targets.entry(target.to_string()).or_default().extend(names);
+            }
+        }
+
+        // a vpath without directories clears the paths for its pattern, or all of them
+        if let Some(matches) = VPATH.captures(line) {
+            let pattern = matches.name("pattern").map(|p| p.as_str().to_string());
+            let directories = split_search_path(matches.name("dirs").map_or("", |d| d.as_str()));
+            if directories.is_empty() {
+                debug!("Clearing search paths for {:?}", pattern);
+                self.vpaths.retain(|p| pattern.is_some() && p.pattern != pattern);
+            } else {
+                debug!("Search path for {:?}: {:?}", pattern, directories);
+                self.vpaths.push(SearchPath { pattern, directories });
+            }
+        }
+
+        // an empty .SUFFIXES clears the list, otherwise the suffixes are added to it
+        if let Some(matches) = SUFFIXES.captures(line) {
+            let names: Vec<String> =
+                matches["names"].split_whitespace().map(|s| s.to_string()).collect();
+            if names.is_empty() {
+                debug!("Clearing suffixes");
+                self.suffixes.clear();
+            }
+            self.suffixes.extend(names);
+        }
+
+        Ok(())
+    }
+
+    /// Handle a BSD directive other than a conditional or `.for` loop, like `.include "config.mk"`,
+    /// or one of NMAKE's, which are named after their BSD equivalents
+    fn parse_bsd_directive(
+        &mut self,
+        keyword: &str,
+        rest: &str,
+        strict: bool,
+    ) -> Result<(), String> {
+        match keyword {
+            // an included path is quoted, or in angle brackets to only look in the include
+            // directories, which are searched for either kind here
+            "include" | "-include" | "sinclude" | "dinclude" => {
+                let optional = keyword != "include";
+                let path = rest.trim_matches(|c| c == '"' || c == '<' || c == '>');
+                for path in self.expand_include_glob(path) {
+                    self.include(&path, optional, strict)?;
+                }
+            }
+            "undef" => {
+                for name in rest.split_whitespace() {
+                    debug!("Undefining '{}'", name);
+                    self.vars.remove(name);
+                }
+            }
+            "export" | "export-env" | "unexport" => {
+                for name in rest.split_whitespace() {
+                    self.set_exported(name, keyword != "unexport");
+                }
+            }
+            "error" if strict => return Err(rest.to_string()),
+            "error" => self.diagnose(Severity::Error, rest.to_string()),
+            "warning" => self.diagnose(Severity::Warning, rest.to_string()),
+            "info" | "message" => self.diagnose(Severity::Info, rest.to_string()),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    /// Get the files matching an include path containing wildcards, in sorted order, with
+    /// the wildcards only allowed in the file name. A path without wildcards (or one that
+    /// doesn't match anything) is returned as-is, as is every path without filesystem access.
+    fn expand_include_glob(&self, path: &str) -> Vec<String> {
+        if !self.options.filesystem_access {
+            return vec![path.to_string()];
+        }
+        let (dir, name) = match path.rfind('/') {
+            Some(idx) => (&path[..idx + 1], &path[idx + 1..]),
+            None => ("", path),
+        };
+        let re = match glob_to_regex(name) {
+            Some(re) => re,
+            None => return vec![path.to_string()],
+        };
+
+        // look in the including file's directory, like the include itself will
+        let mut search = PathBuf::from(dir);
+        if search.is_relative() {
+            if let Some(parent) = self.file_stack.last().and_then(|f| f.parent()) {
+                search = parent.join(search);
+            }
+        }
+
+        let mut matched: Vec<String> = std::fs::read_dir(&search)
+            .map(|entries| {
+                entries
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.file_name().to_string_lossy().to_string())
+                    .filter(|f| re.is_match(f))
+                    .map(|f| format!("{}{}", dir, f))
+                    .collect()
+            })
+            .unwrap_or_default();
+        matched.sort();
+
+        if matched.is_empty() {
+            vec![path.to_string()]
+        } else {
+            matched
+        }
+    }
+
+    /// Get the existing files matching a `$(wildcard)` pattern, with each directory's matches
+    /// sorted. Relative patterns are resolved against the current makefile's directory, but
+    /// the matches are given as written in the pattern.
+    fn wildcard(&self, pattern: &str) -> Vec<String> {
+        let base = match self.file_stack.last().and_then(|f| f.parent()) {
+            _ if Path::new(pattern).is_absolute() => PathBuf::from("/"),
+            Some(parent) if parent.as_os_str().is_empty() => PathBuf::from("."),
+            Some(parent) => parent.to_path_buf(),
+            None => PathBuf::from("."),
+        };
+
+        // each match so far, as written and as found on disk
+        let mut matches = vec![(String::new(), base)];
+        let components: Vec<&str> = pattern.split('/').collect();
+        for (i, component) in components.iter().enumerate() {
+            let sep = if i + 1 < components.len() { "/" } else { "" };
+            let re = match glob_to_regex(component) {
+                Some(re) => re,
+                None => {
+                    for (written, path) in &mut matches {
+                        written.push_str(component);
+                        written.push_str(sep);
+                        path.push(component);
+                    }
+                    continue;
+                }
+            };
+
+            let mut next = Vec::new();
+            for (written, path) in &matches {
+                let mut names: Vec<String> = match std::fs::read_dir(path) {
+                    Ok(entries) => entries
+                        .filter_map(|e| e.ok())
+                        .map(|e| e.file_name().to_string_lossy().to_string())
+                        // like the shell, hidden files are only matched explicitly
+                        .filter(|n| !n.starts_with('.') || component.starts_with('.'))
+                        .filter(|n| re.is_match(n))
+                        .collect(),
+                    Err(_) => continue,
+                };
+                names.sort();
+                for name in names {
+                    next.push((format!("{}{}{}", written, name, sep), path.join(&name)));
+                }
+            }
+            matches = next;
+        }
+
+        matches
+            .into_iter()
+            .filter(|(_, path)| path.exists())
+            .map(|(written, _)| written)
+            .collect()
+    }
+
+    /// Parse an included makefile, resolving relative paths against the including file's
+    /// directory. Include cycles and includes nested past the depth limit are skipped.
+    fn include(&mut self, path: &str, optional: bool, strict: bool) -> Result<(), String> {
+        if self.options.relative_includes_only && Path::new(path).is_absolute() {
+            let message = format!("Refusing to include absolute path {}", path);
+            self.diagnose(Severity::Warning, message);
+            return Ok(());
+        }
+
+        let mut resolved = PathBuf::from(path);
+        if resolved.is_relative() {
+            if let Some(dir) = self.file_stack.last().and_then(|f| f.parent()) {
+                resolved = dir.join(resolved);
+            }
+
+            // fall back to the include directories, like make's -I
+            if !resolved.exists() {
+                if let Some(found) = self
+                    .options
+                    .include_dirs
+                    .iter()
+                    .map(|dir| dir.join(path))
+                    .find(|p| p.exists())
+                {
+                    resolved = found;
+                }
+            }
+        }
+
+        if self.options.relative_includes_only && !self.within_root_dir(&resolved) {
+            let message = format!(
+                "Refusing to include {}, which is outside the makefile's directory",
+                resolved.display()
+            );
+            self.diagnose(Severity::Warning, message);
+            return Ok(());
+        }
+
+        if !resolved.exists() {
+            if optional {
+                debug!("Skipping missing optional include {}", resolved.display());
+                return Ok(());
+            }
+            let message = format!("Included file {} doesn't exist", resolved.display());
+            if strict {
+                return Err(message);
+            }
+            self.diagnose(Severity::Warning, message);
+            return Ok(());
+        }
+
+        // the root file is at depth 0, so the stack length is the depth of the new file
+        if self.file_stack.len() > self.options.max_include_depth {
+            let message = format!(
+                "Include depth limit of {} exceeded; not reading {}",
+                self.options.max_include_depth,
+                resolved.display()
+            );
+            self.diagnose(Severity::Warning, message);
+            return Ok(());
+        }
+
+        let canonical = resolved.canonicalize().ok();
+        if self.file_stack.iter().any(|f| f.canonicalize().ok() == canonical) {
+            let message = format!("Include cycle found; not reading {} again", resolved.display());
+            self.diagnose(Severity::Warning, message);
+            return Ok(());
+        }
+
+        debug!("Including {}", resolved.display());
+        self.parse_path(&resolved, strict)
+    }
+
+    /// Check whether a path is inside the directory of the root makefile, both as written and
+    /// after following any symlinks
+    fn within_root_dir(&self, path: &Path) -> bool {
+        let root = match self.file_stack.first().and_then(|f| f.parent()) {
+            Some(dir) => dir,
+            None => Path::new(""),
+        };
+
+        // compare absolute paths, since a relative root could be empty
+        let cwd = std::env::current_dir().unwrap_or_default();
+        if !lexical_normalize(&cwd.join(path)).starts_with(lexical_normalize(&cwd.join(root))) {
+            return false;
+        }
+
+        match (path.canonicalize(), root.canonicalize()) {
+            (Ok(path), Ok(root)) => path.starts_with(root),
+            // the file doesn't exist, so there's no link to follow out of the directory
+            _ => true,
+        }
+    }
+
+    /// Record an assignment to a variable at the current file & line
+    fn record_assignment(&mut self, name: &str, operator: &str, value: &str) {
+        let assignment = Assignment {
+            line: self.line_number,
+            operator: operator.to_string(),
+            value: value.to_string(),
+            source_file: self.current_file(),
+        };
+        self.assignments
+            .entry(name.to_string())
+            .or_default()
+            .push(assignment);
+    }
+
+    /// Get the span of the line being parsed, starting at the column of `name` in it
+    fn current_span(&self, name: &str) -> Span {
+        Span {
+            file: self.current_file(),
+            line_start: self.line_number,
+            line_end: self.line_end,
+            column: self.column_of(name),
+        }
+    }
+
+    /// Get the column (starting at 1) of some text in the line being parsed, or of the start of
+    /// the line if the text isn't written in it, like a name that came from a variable
+    fn column_of(&self, text: &str) -> usize {
+        let line = &self.line_text;
+        let idx = line
+            .find(text)
+            .filter(|_| !text.is_empty())
+            .unwrap_or_else(|| line.len() - line.trim_start().len());
+        line[..idx].chars().count() + 1
+    }
+
+    /// Get the path of the file currently being parsed
+    fn current_file(&self) -> String {
+        self.file_stack
+            .last()
+            .map(|f| f.display().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Record a diagnostic at the current file & line
+    fn diagnose(&mut self, severity: Severity, message: String) {
+        debug!("Diagnostic: {}", message);
+        self.diagnostics.push(Diagnostic {
+            severity,
+            message,
+            file: Some(self.current_file()),
+            line: Some(self.line_number),
+        });
+    }
+
+    /// Record a diagnostic like `diagnose`, unless the same one was already recorded at the
+    /// current file & line, since a line can be expanded more than once
+    fn diagnose_once(&mut self, severity: Severity, message: String) {
+        let file = Some(self.current_file());
+        let reported = self.diagnostics.iter().any(|d| {
+            d.message == message && d.file == file && d.line == Some(self.line_number)
+        });
+        if !reported {
+            self.diagnose(severity, message);
+        }
+    }
+
+    /// Check a line against each of the output regexes, adding any matches to the outputs of
+    /// the most recent target. Text inside quotes (like an echoed message) is never treated
+    /// as a command, but a quoted path is still captured as an output.
+    /// Relative outputs are taken to be in `dir`, when the recipe has changed to it.
+    fn detect_outputs(&mut self, line: &str, raw: &str, dir: Option<&str>) {
+        let mut found = self.find_outputs(line);
+
+        // keep the outputs as written too, for finding where variables are used
+        let raw_found = if raw == line {
+            found.clone()
+        } else {
+            self.find_outputs(raw)
+        };
+
+        // the line wasn't expanded, but the outputs themselves still should be
+        if !self.options.expand_in_recipes {
+            found = found
+                .into_iter()
+                .map(|(o, via)| (self.eval_variable(&o, &[]).unwrap_or(o), via))
+                .collect();
+        }
+
+        // shells like bash expand `{a,b}` in a path into a path for each
+        if self.shell_expands_braces() {
+            found = found
+                .into_iter()
+                .flat_map(|(o, via)| expand_braces(&o).into_iter().map(move |o| (o, via.clone())))
+                .collect();
+        }
+
+        // outputs that are written out in the line can be pointed to
+        let columns: Vec<usize> = if raw_found.len() == found.len() {
+            raw_found.iter().map(|(o, _)| self.column_of(o)).collect()
+        } else {
+            vec![self.column_of(line.trim()); found.len()]
+        };
+
+        let idx = self.targets.len() - 1;
+        self.targets[idx].raw.outputs.extend(raw_found.into_iter().map(|(o, _)| o));
+
+        for ((val, via), column) in found.into_iter().zip(columns) {
+            let val = match dir {
+                Some(dir) => in_directory(dir, &val),
+                None => val,
+            };
+
+            // paths built from shell variables, like a loop's `$$f.o`, aren't concrete outputs
+            if val.contains("$$") {
+                debug!("templated output: '{}'", val);
+                self.targets[idx].templated_outputs.push(val);
+                continue;
+            }
+
+            debug!("output: '{}'", val);
+
+            let source = OutputSource {
+                output: val.clone(),
+                command: line.trim().to_string(),
+                file: self.current_file(),
+                line: self.line_number,
+                column,
+                via,
+            };
+            self.targets[idx].output_sources.push(source);
+            self.targets[idx]
+                .output
+                .get_or_insert(Vec::<String>::new())
+                .push(val);
+        }
+    }
+
+    /// Get every output found on a line, along with the name of the regex that found it.
+    /// The commands in the body of a shell loop are each checked on their own, since they
+    /// don't start the line.
+    fn find_outputs(&self, line: &str) -> Vec<(String, String)> {
+        lazy_static! {
+            static ref SHELL_LOOP: Regex = Regex::new(r"\b(for|while|until)\b.*\bdo\b").unwrap();
+            static ref LOOP_KEYWORD: Regex = Regex::new(r"^((do|then|else)\s+)+").unwrap();
+        }
+
+        if !SHELL_LOOP.is_match(&mask_quotes(line).0) {
+            return self.match_outputs(line);
+        }
+
+        shell_commands(line)
+            .into_iter()
+            .flat_map(|command| {
+                let command = LOOP_KEYWORD.replace(command, "");
+                self.match_outputs(&format!("\t{}", command))
+            })
+            .collect()
+    }
+
+    /// Get every output found on a line by the output regexes
+    fn match_outputs(&self, line: &str) -> Vec<(String, String)> {
+        // matching is done against the masked line, which has the same byte offsets as the
+        // original, so captured ranges can be used to get the original text back
+        let (masked, _) = mask_quotes(line);
+        let mut found = Vec::new();
+
+        for (name, output) in &self.match_output {
+            if let Some(matches) = output.captures(&masked) {
+                debug!("Found output match on output regex {}", name);
+                // get the value of the output
+                let range = matches.name("path").unwrap().range();
+                let path = line[range].trim_matches(['"', '\'']);
+
+                // writing to a device like /dev/null doesn't produce anything
+                if path.starts_with("/dev/") {
+                    continue;
+                }
+                found.push((path.to_string(), name.clone()));
+            }
+        }
+
+        if let Some(path) = implied_dependency_file(&masked, line) {
+            debug!("Found implied dependency file");
+            found.push((path, "-MD".to_string()));
+        }
+
+        found
+    }
+
+    /// Gather everything of interest from a line of the most recent target's recipe.
+    /// `raw` is the line as written, before variable expansion.
+    fn parse_recipe(&mut self, line: &str, raw: &str) {
+        lazy_static! {
+            static ref HEREDOC: Regex =
+                Regex::new(r#"<<-?[ \t]*['"]?(?P<delim>[A-Za-z_]\w*)['"]?"#).unwrap();
+        }
+
+        // the body of a heredoc is text for a command, not commands of its own
+        if let Some(delim) = &self.heredoc {
+            if line.trim() == delim {
+                self.heredoc = None;
+            }
+            if let Some(target) = self.targets.last_mut() {
+                let text = line.trim().to_string();
+                target.recipe.push(RecipeLine { command: text, ..Default::default() });
+            }
+            return;
+        }
+
+        // the modifiers aren't part of the command, so they're kept out of the output matching,
+        // which still expects the line to be indented
+        let recipe = RecipeLine::parse(line);
+        let line = format!("\t{}", recipe.command);
+        let raw = format!("\t{}", RecipeLine::parse(raw).command);
+        if let Some(target) = self.targets.last_mut() {
+            target.recipe.push(recipe);
+        }
+        if let Some(matches) = HEREDOC.captures(&line) {
+            debug!("heredoc until '{}'", &matches["delim"]);
+            self.heredoc = Some(matches["delim"].to_string());
+        }
+
+        // `cd` and `export` change the shell that runs the rest of the line, rather than being
+        // commands whose arguments could be outputs
+        let exported = self.shell.exports.len();
+        let raw_groups = split_shell_commands(&raw, &mut self.shell.clone());
+        let groups = split_shell_commands(&line, &mut self.shell);
+        if let Some(target) = self.targets.last_mut() {
+            target.recipe_exports.extend(self.shell.exports[exported..].iter().cloned());
+        }
+
+        for (idx, (dir, line)) in groups.iter().enumerate() {
+            // a variable can expand to more commands, so the groups as written may not line up
+            let raw = match raw_groups.get(idx) {
+                Some((_, raw)) if raw_groups.len() == groups.len() => raw,
+                _ => line,
+            };
+            self.detect_outputs(line, raw, dir.as_deref());
+            self.detect_removals(line, dir.as_deref());
+            if self.options.detect_delegation {
+                self.detect_delegation_goals(line);
+            }
+        }
+
+        // each line is run by a new shell, unless the whole recipe is run by one, so the lines
+        // after a heredoc are commands of their own
+        if !self.one_shell() {
+            self.shell = ShellState::default();
+            self.heredoc = None;
+        }
+    }
+
+    /// Record the paths passed to any `rm` commands on a recipe line as removed by the most
+    /// recent target
+    fn detect_removals(&mut self, line: &str, dir: Option<&str>) {
+        for command in shell_commands(line) {
+            let mut words = command.split_whitespace();
+
+            if words.next() != Some("rm") {
+                continue;
+            }
+
+            let idx = self.targets.len() - 1;
+            for path in words.filter(|w| !w.starts_with('-')) {
+                let path = match dir {
+                    Some(dir) => in_directory(dir, path),
+                    None => path.to_string(),
+                };
+                debug!("removes: '{}'", path);
+                self.targets[idx].removes.push(path);
+            }
+        }
+    }
+
+    /// Record the goals passed to any recursive make calls on a recipe line as delegates of
+    /// the most recent target
+    fn detect_delegation_goals(&mut self, line: &str) {
+        for command in shell_commands(line) {
+            let mut words = command.split_whitespace();
+
+            // $(MAKE) may or may not have been expanded
+            match words.next() {
+                Some("$(MAKE)") | Some("${MAKE}") | Some("make") => (),
+                _ => continue,
+            }
+
+            let mut goals = Vec::new();
+            let mut skip_value = false;
+            for word in words {
+                if skip_value {
+                    skip_value = false;
+                    continue;
+                }
+
+                match word {
+                    // goals for a different directory or makefile aren't targets in this one
+                    "-C" | "-f" | "--file" | "--makefile" | "--directory" => {
+                        goals.clear();
+                        break;
+                    }
+                    w if w.starts_with("-C") || w.starts_with("-f") => {
+                        goals.clear();
+                        break;
+                    }
+                    // flags that take a separate value
+                    "-I" | "-o" | "-W" | "-j" | "-l" => skip_value = true,
+                    // other flags and variable overrides aren't goals
+                    w if w.starts_with('-') || w.contains('=') => (),
+                    w => goals.push(w.to_string()),
+                }
+            }
+
+            let idx = self.targets.len() - 1;
+            for goal in goals {
+                debug!("delegates to: '{}'", goal);
+                self.targets[idx].delegates_to.push(goal);
+            }
+        }
+    }
+
+    /// Evaluate a variable recursively until the actual value is determined, using other
+    ///  variables as necessary
+    fn eval_variable(&mut self, value: &str, deps: &[String]) -> Result<String, String> {
+        debug!("running eval on '{}'", value.trim_end());
+
+        let mut new = String::with_capacity(value.len());
+        let mut rest = value;
+
+        // replace each reference in the value with its evaluated text
+        while let Some(idx) = rest.find('$') {
+            new.push_str(&rest[..idx]);
+            rest = &rest[idx..];
+
+            let (reference, len) = find_reference(rest, self.options.dialect);
+            debug!("found reference '{}'", &rest[..len]);
+
+            let evald = match reference {
+                Reference::Literal(text) => text.to_string(),
+                Reference::Variable(name) => self.eval_named(name, deps)?,
+                Reference::Function(name, args) => self.eval_function(name, &args, deps)?,
+                Reference::Substitution(name, from, to) => {
+                    self.eval_substitution(name, from, to, deps)?
+                }
+                Reference::Modified(name, modifiers) => self.eval_modified(name, modifiers, deps)?,
+            };
+            new.push_str(&evald);
+            rest = &rest[len..];
+        }
+        new.push_str(rest);
+
+        debug!("eval'd line: '{}'", new.trim_end());
+
+        // return the new value
+        Ok(new)
+    }
+
+    /// Evaluate a substitution reference like `$(SRCS:.c=.o)` or `$(SRCS:%.c=%.o)`. Without
+    /// a `%`, the text replaced is a suffix of each word, so it's the same as `patsubst` with
+    /// a `%` added to the front of both patterns.
+    fn eval_substitution(
+        &mut self,
+        name: &str,
+        from: &str,
+        to: &str,
+        deps: &[String],
+    ) -> Result<String, String> {
+        let value = self.eval_named(name, deps)?;
+        let mut from = self.eval_variable(from, deps)?;
+        let mut to = self.eval_variable(to, deps)?;
+        // NMAKE replaces the text anywhere in the value, not just at the end of each word
+        if self.options.dialect == Dialect::Nmake {
+            return functions::call("subst", &[from, to, value]);
+        }
+        if !from.contains('%') {
+            from.insert(0, '%');
+            to.insert(0, '%');
+        }
+        functions::call("patsubst", &[from, to, value])
+    }
+
+    /// Evaluate a BSD reference with modifiers, like `${SRCS:M*.c:S/.c/.o/}`. Variables in the
+    /// modifiers are expanded before the modifiers are applied.
+    fn eval_modified(
+        &mut self,
+        name: &str,
+        modifiers: &str,
+        deps: &[String],
+    ) -> Result<String, String> {
+        let name = self.eval_variable(name, deps)?;
+        let modifiers = self.eval_variable(modifiers, deps)?;
+        let value = match self.is_defined(&name) {
+            true => Some(self.eval_named(&name, deps)?),
+            false => None,
+        };
+        match bsd::apply_modifiers(value, &modifiers)? {
+            Some(value) => Ok(value),
+            // a variable that's still undefined is handled like one without modifiers
+            None => self.eval_named(&name, deps),
+        }
+    }
+
+    /// Look up a variable by name and evaluate its value
+    fn eval_named(&mut self, name: &str, deps: &[String]) -> Result<String, String> {
+        // resolve computed names like $($(ARCH)_CC) before looking them up
+        let varname = if name.contains('$') {
+            self.eval_variable(name, deps)?
+        } else {
+            name.to_string()
+        };
+        debug!("found variable named {}", varname);
+
+        // make sure the variable doesn't already exist up the dependency chain
+        if let Some(idx) = deps.iter().position(|d| *d == varname) {
+            let mut cycle = deps[idx..].to_vec();
+            cycle.push(varname.clone());
+            let message = format!(
+                "Variable {} has a recursive dependency: {}",
+                varname,
+                cycle.join(" -> ")
+            );
+            self.diagnose_once(Severity::Warning, message.clone());
+
+            // treat the recursive reference as empty so the rest of the line is kept
+            if self.options.break_recursion {
+                debug!("Breaking recursion on {}", varname);
+                return Ok(String::new());
+            }
+            return Err(message);
+        }
+
+        // a chain of references this long is more likely to be runaway than intended
+        if deps.len() >= MAX_EXPANSION_DEPTH {
+            let message = format!("Variable {} is nested too deeply to expand", varname);
+            self.diagnose_once(Severity::Warning, message.clone());
+            return Err(message);
+        }
+
+        // BSD's local variables like `${.TARGET}` are other names for the automatic variables
+        let varname = match bsd::local_variable(&varname) {
+            Some(local) if self.options.dialect == Dialect::Bsd => local.to_string(),
+            _ => varname,
+        };
+        if let Some(value) = self.bsd_builtin(&varname) {
+            return Ok(value);
+        }
+
+        // `$(@D)` and `$(@F)` are the directory and file parts of an automatic variable
+        if let Some(value) = self.automatic_part(&varname) {
+            return Ok(value);
+        }
+
+        // get the variable value from the value map
+        let value = match self.vars.get(&varname) {
+            // the value of a `!=` assignment that wasn't run is unknown, so keep the reference
+            Some(var) if var.flavor == Flavor::Shell && !self.options.allow_shell_assign => {
+                return Ok(format!("$({})", varname));
+            }
+            Some(var) => var.value.clone(),
+            None if self.undefined_is_empty => return Ok(String::new()),
+            // kbuild configuration options that aren't set are empty, like in the kernel
+            None if self.options.kbuild && varname.starts_with("CONFIG_") => {
+                return Ok(String::new());
+            }
+            // a parameter past those given to $(call) is empty
+            None if varname.chars().all(|c| c.is_ascii_digit()) => return Ok(String::new()),
+            None => return Err(format!("No variable '{}'", varname)),
+        };
+        debug!("variable value {}", value);
+
+        // recusrively evaluate variable values
+        let mut newdeps = deps.to_vec();
+        newdeps.push(varname);
+        self.eval_variable(&value, &newdeps)
+            .map_err(|e| format!("Failure to parse variable: {}", e))
+    }
+
+    /// Get the directory (`D`) or file (`F`) part of each word of an automatic variable, for a
+    /// name like `@D` or `^F`. NMAKE also has the base name (`B`) and the path without its
+    /// extension (`R`).
+    fn automatic_part(&self, name: &str) -> Option<String> {
+        let mut chars = name.chars();
+        let (base, part) = (chars.next()?, chars.next()?);
+        let parts = if self.options.dialect == Dialect::Nmake { "DFBR" } else { "DF" };
+        if chars.next().is_some() || !"@<^+?|*".contains(base) || !parts.contains(part) {
+            return None;
+        }
+        let var = self.vars.get(base.to_string().as_str())?;
+        if var.origin != Origin::Automatic {
+            return None;
+        }
+
+        let parts: Vec<&str> = var
+            .value
+            .split_whitespace()
+            .map(|word| match (word.rfind('/'), part) {
+                (_, 'B' | 'R') => nmake::path_part(word, part),
+                (Some(0), 'D') => "/",
+                (Some(idx), 'D') => &word[..idx],
+                (None, 'D') => ".",
+                (Some(idx), _) => &word[idx + 1..],
+                (None, _) => word,
+            })
+            .collect();
+        Some(parts.join(" "))
+    }
+
+    /// Expand an argument used as a condition by `if`, `or`, or `and`, where undefined
+    /// variables are empty and surrounding whitespace is ignored
+    fn eval_truthy(&mut self, text: &str, deps: &[String]) -> Result<String, String> {
+        let undefined_is_empty = std::mem::replace(&mut self.undefined_is_empty, true);
+        let evald = self.eval_variable(text.trim(), deps);
+        self.undefined_is_empty = undefined_is_empty;
+        evald.map(|e| e.trim().to_string())
+    }
+
+    /// Evaluate a call to one of make's built-in functions. Arguments are expanded by each
+    /// function as needed, so that short-circuiting functions skip unused arguments.
+    fn eval_function(&mut self, name: &str, args: &[&str], deps: &[String]) -> Result<String, String> {
+        debug!("calling function {} with {} args", name, args.len());
+
+        match name {
+            // the first non-empty argument, or empty if there isn't one
+            "or" => {
+                for arg in args {
+                    let evald = self.eval_truthy(arg, deps)?;
+                    if !evald.is_empty() {
+                        return Ok(evald);
+                    }
+                }
+                Ok(String::new())
+            }
+            // empty if any argument is empty, otherwise the last argument
+            "and" => {
+                let mut evald = String::new();
+                for arg in args {
+                    evald = self.eval_truthy(arg, deps)?;
+                    if evald.is_empty() {
+                        return Ok(evald);
+                    }
+                }
+                Ok(evald)
+            }
+            // the second argument if the condition isn't empty, otherwise the rest
+            "if" => {
+                if args.len() < 2 {
+                    let count = args.len();
+                    return Err(format!("Function 'if' needs 2 or 3 arguments, but got {}", count));
+                }
+                if !self.eval_truthy(args[0], deps)?.is_empty() {
+                    self.eval_variable(args[1], deps)
+                } else if args.len() > 2 {
+                    self.eval_variable(&args[2..].join(","), deps)
+                } else {
+                    Ok(String::new())
+                }
+            }
+            // parse the argument as makefile text, and expand to nothing
+            "eval" => {
+                // eval takes a single argument, so any commas are part of the text
+                let text = args.join(",");
+                if !self.options.allow_eval {
+                    debug!("$(eval) isn't allowed; leaving it unexpanded");
+                    // only report it once, since evals are often run in a loop
+                    let message = "Rules generated by $(eval) are skipped unless eval is allowed";
+                    if !self.diagnostics.iter().any(|d| d.message == message) {
+                        self.diagnose(Severity::Info, message.to_string());
+                    }
+                    return Ok(format!("$(eval {})", text));
+                }
+
+                // expanding the text turns each `$$` into the `$` of a reference that's expanded
+                // when the text is parsed
+                let evald = self.eval_variable(text.trim(), deps)?.replace("$$", "$");
+                debug!("eval: '{}'", evald);
+                self.pending_eval.push(evald);
+                Ok(String::new())
+            }
+            // a user-defined function: a variable expanded with its arguments as $(1), $(2), ...
+            "call" => {
+                let function = self.eval_variable(args[0], deps)?.trim().to_string();
+                let params = args[1..]
+                    .iter()
+                    .map(|arg| self.eval_variable(arg, deps))
+                    .collect::<Result<Vec<String>, String>>()?;
+                if deps.len() > MAX_EXPANSION_DEPTH {
+                    return Err(format!("Calls to '{}' are nested too deeply", function));
+                }
+                let body = match self.vars.get(&function) {
+                    Some(var) => var.value.clone(),
+                    None => return Ok(String::new()),
+                };
+                debug!("call {} with {:?}", function, params);
+
+                // the parameters of any outer call are hidden while this one is expanded
+                let outer: Vec<(String, Variable)> = self
+                    .vars
+                    .iter()
+                    .filter(|(name, _)| name.chars().all(|c| c.is_ascii_digit()))
+                    .map(|(name, var)| (name.clone(), var.clone()))
+                    .collect();
+                for (name, _) in &outer {
+                    self.vars.remove(name);
+                }
+                for (i, value) in std::iter::once(&function).chain(&params).enumerate() {
+                    let var = Variable::new(value.clone(), Flavor::Simple, Origin::Automatic);
+                    self.vars.insert(i.to_string(), var);
+                }
+
+                let mut newdeps = deps.to_vec();
+                newdeps.push(function.clone());
+                let evald = self.eval_variable(&body, &newdeps);
+
+                for i in 0..=params.len() {
+                    self.vars.remove(&i.to_string());
+                }
+                self.vars.extend(outer);
+                evald
+            }
+            // the text expanded once for each word of a list, with a variable set to the word
+            "foreach" => {
+                if args.len() < 3 {
+                    let count = args.len();
+                    return Err(format!("Function 'foreach' needs 3 arguments, but got {}", count));
+                }
+                let var = self.eval_variable(args[0], deps)?.trim().to_string();
+                let list = self.eval_variable(args[1], deps)?;
+                let text = args[2..].join(",");
+
+                // the loop variable only exists for the duration of the loop
+                let previous = self.vars.get(&var).cloned();
+                let results: Result<Vec<String>, String> = list
+                    .split_whitespace()
+                    .map(|word| {
+                        let word = word.to_string();
+                        let value = Variable::new(word, Flavor::Simple, Origin::Automatic);
+                        self.vars.insert(var.clone(), value);
+                        self.eval_variable(&text, deps)
+                    })
+                    .collect();
+                match previous {
+                    Some(prev) => self.vars.insert(var, prev),
+                    None => self.vars.remove(&var),
+                };
+
+                Ok(results?.join(" "))
+            }
+            // the output of a shell command
+            "shell" => {
+                let command = self.eval_variable(&args.join(","), deps)?;
+                if !self.options.eval_shell {
+                    debug!("$(shell) isn't allowed; substituting a marker");
+                    return Ok(format!("{}{}>", SHELL_MARKER, command.trim()));
+                }
+                Ok(self.run_shell(&command))
+            }
+            // messages that make prints (or stops with) when the reference is expanded, which are
+            // reported as diagnostics unless they're in a recipe that make would have to run
+            "error" | "warning" | "info" => {
+                // undefined variables in the message are empty, like they are in make
+                let undefined_is_empty = std::mem::replace(&mut self.undefined_is_empty, true);
+                let message = self.eval_variable(&args.join(","), deps);
+                self.undefined_is_empty = undefined_is_empty;
+                let message = message?.trim().to_string();
+                if self.in_recipe {
+                    return Ok(String::new());
+                }
+                let severity = match name {
+                    "error" => Severity::Error,
+                    "warning" => Severity::Warning,
+                    _ => Severity::Info,
+                };
+                if name == "error" {
+                    self.error_call = Some(message.clone());
+                }
+                self.diagnose(severity, message);
+                Ok(String::new())
+            }
+            // where a variable was defined, how it's expanded, or its value without expanding it
+            "origin" | "flavor" | "value" => {
+                let varname = self.eval_variable(&args.join(","), deps)?.trim().to_string();
+                let var = match self.vars.get(&varname) {
+                    Some(var) => var,
+                    None if name == "value" => return Ok(String::new()),
+                    None => return Ok("undefined".to_string()),
+                };
+                Ok(match name {
+                    "origin" => var.origin_name().to_string(),
+                    "flavor" => var.flavor.expansion().to_string(),
+                    _ => var.value.clone(),
+                })
+            }
+            // the existing files matching each of the patterns
+            "wildcard" => {
+                let text = args.join(",");
+                if !self.options.filesystem_access {
+                    debug!("Filesystem access isn't allowed; leaving $(wildcard) unexpanded");
+                    return Ok(format!("$(wildcard {})", text));
+                }
+
+                let patterns = self.eval_variable(&text, deps)?;
+                let files: Vec<String> =
+                    patterns.split_whitespace().flat_map(|p| self.wildcard(p)).collect();
+                Ok(files.join(" "))
+            }
+            // text functions only need their arguments expanded
+            _ => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.eval_variable(arg, deps))
+                    .collect::<Result<Vec<String>, String>>()?;
+                functions::call(name, &args)
+            }
+        }
+    }
+}
+
+/// A reference to a variable or function, starting with a `$`
+enum Reference<'a> {
+    // text that is kept exactly as written
+    Literal(&'a str),
+    // a variable, like `$(NAME)`, `${NAME}`, or `$@`
+    Variable(&'a str),
+    // a function call like `$(name arg1,arg2)` with its unexpanded arguments
+    Function(&'a str, Vec<&'a str>),
+    // a substitution reference like `$(NAME:from=to)`
+    Substitution(&'a str, &'a str, &'a str),
+    // a BSD reference with modifiers, like `${NAME:M*.c:S/.c/.o/}`
+    Modified(&'a str, &'a str),
+}
+
+// how many variable references and calls can be nested inside each other, to stop runaway
+// recursion that isn't a cycle, like a `$(call)` to a function that calls itself
+const MAX_EXPANSION_DEPTH: usize = 100;
+
+// start of the marker substituted for a `$(shell)` call that isn't run, which ends with `>`
+const SHELL_MARKER: &str = "<shell:";
+
+// built-in functions that can be evaluated
+const FUNCTIONS: &[&str] = &[
+    "and", "call", "error", "eval", "flavor", "foreach", "if", "info", "or", "origin", "shell",
+    "value", "warning", "wildcard",
+];
+
+/// Parse the reference at the start of `text`, which must begin with a `$`. Returns the
+/// reference along with the number of bytes it spans.
+fn find_reference(text: &str, dialect: Dialect) -> (Reference<'_>, usize) {
+    let open = match text[1..].chars().next() {
+        Some(c) => c,
+        None => return (Reference::Literal("$"), 1),
+    };
+
+    let close = match open {
+        '(' => ')',
+        '{' => '}',
+        // non-enclosed variable names can only be a single character in make,
+        // so just accept the automatic variables and call parameters we want to resolve
+        // NMAKE's `$**` is all of the prerequisites
+        '*' if dialect == Dialect::Nmake && text[2..].starts_with('*') => {
+            return (Reference::Variable("^"), 3)
+        }
+        '@' | '<' | '^' | '+' | '?' | '|' | '*' | '0'..='9' => {
+            return (Reference::Variable(&text[1..2]), 2)
+        }
+        // an escaped dollar sign
+        '$' => return (Reference::Literal("$$"), 2),
+        _ => return (Reference::Literal("$"), 1),
+    };
+
+    // find the matching close, allowing for nested references
+    let mut depth = 0;
+    let mut end = None;
+    for (i, c) in text.char_indices().skip(2) {
+        if c == open {
+            depth += 1;
+        } else if c == close {
+            if depth == 0 {
+                end = Some(i);
+                break;
+            }
+            depth -= 1;
+        }
+    }
+
+    let end = match end {
+        Some(end) => end,
+        None => return (Reference::Literal("$"), 1),
+    };
+    let inner = &text[2..end];
+    let len = end + 1;
+
+    // function calls are a known name followed by whitespace and the arguments
+    if let Some(idx) = inner.find(char::is_whitespace) {
+        let name = &inner[..idx];
+        if FUNCTIONS.contains(&name) || functions::NAMES.contains(&name) {
+            return (
+                Reference::Function(name, split_args(inner[idx..].trim_start())),
+                len,
+            );
+        }
+    }
+
+    // BSD make's modifiers include the `:from=to` substitution
+    if dialect == Dialect::Bsd {
+        if let Some((name, modifiers)) = bsd::split_modifiers(inner) {
+            return (Reference::Modified(name, modifiers), len);
+        }
+    }
+
+    if let Some((name, from, to)) = split_substitution(inner) {
+        return (Reference::Substitution(name, from, to), len);
+    }
+
+    // a make variable name can't contain whitespace, :, #, or =, so leave anything else as-is
+    let invalid = |c: char| c.is_whitespace() || ":#=/\\[]".contains(c);
+    if inner.is_empty() || (!inner.contains('$') && inner.contains(invalid)) {
+        return (Reference::Literal(&text[..len]), len);
+    }
+
+    (Reference::Variable(inner), len)
+}
+
+/// Split a line starting with `export`, `unexport`, or `override` into the modifiers and the
+/// rest of the line
+fn split_modifiers(line: &str) -> Option<(String, String)> {
+    lazy_static! {
+        static ref MODIFIERS: Regex =
+            Regex::new(r"^(?P<mods>((export|unexport|override)(\s+|$))+)(?P<rest>[^#\r\n]*)")
+                .unwrap();
+    }
+
+    let matches = MODIFIERS.captures(line)?;
+    Some((matches["mods"].to_string(), matches["rest"].to_string()))
+}
+
+/// Split a line into its first word and the rest of the line, like a directive's keyword and
+/// its arguments
+fn split_keyword(line: &str) -> (&str, &str) {
+    match line.find(char::is_whitespace) {
+        Some(idx) => (&line[..idx], line[idx..].trim_start()),
+        None => (line, ""),
+    }
+}
+
+/// Replace references to a BSD `.for` loop variable with its value. A reference with
+/// modifiers, like `${f:R}`, becomes `${:Uvalue:R}` so the modifiers still apply to the value.
+fn substitute_loop_variable(line: &str, name: &str, value: &str) -> String {
+    let mut line = line.to_string();
+    for (open, close) in [('{', '}'), ('(', ')')] {
+        line = line.replace(&format!("${}{}{}", open, name, close), value);
+        let escaped = value.replace(':', "\\:");
+        line = line.replace(&format!("${}{}:", open, name), &format!("${}:U{}:", open, escaped));
+    }
+    line
+}
+
+/// Replace the `$$` in a target's prerequisites with the literal `$` it stands for, when it
+/// isn't left for the second expansion
+fn unescape_prerequisites(target: &mut Target) {
+    for prereq in target.prerequisites.iter_mut().chain(&mut target.order_only) {
+        *prereq = prereq.replace("$$", "$");
+    }
+}
+
+/// Split a list of search directories, which can be separated by colons or whitespace
+fn split_search_path(dirs: &str) -> Vec<String> {
+    dirs.split(|c: char| c == ':' || c.is_whitespace())
+        .filter(|d| !d.is_empty())
+        .map(|d| d.to_string())
+        .collect()
+}
+
+/// Split the inside of a substitution reference, like `NAME:from=to`, into its parts. The `:`
+/// and `=` have to be outside of any nested references.
+fn split_substitution(inner: &str) -> Option<(&str, &str, &str)> {
+    let mut depth = 0;
+    let mut colon = None;
+    for (i, c) in inner.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ':' if depth == 0 && colon.is_none() => colon = Some(i),
+            '=' if depth == 0 && colon.is_some() => {
+                let colon = colon?;
+                let name = &inner[..colon];
+                if name.is_empty() || (!name.contains('$') && name.contains(char::is_whitespace)) {
+                    return None;
+                }
+                return Some((name, &inner[colon + 1..i], &inner[i + 1..]));
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Split the arguments of `ifeq`/`ifneq` into the two values being compared. The arguments
+/// are either `(a,b)` or two quoted strings, like `"a" 'b'`.
+fn split_condition(args: &str) -> Option<(String, String)> {
+    let args = args.trim();
+
+    if let Some(inner) = args.strip_prefix('(') {
+        // find the closing paren, skipping over any nested references
+        let mut depth = 1;
+        let mut end = None;
+        for (i, c) in inner.char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        end = Some(i);
+                        break;
+                    }
+                }
+                _ => (),
+            }
+        }
+        let inner = &inner[..end?];
+
+        let parts = split_args(inner);
+        if parts.len() != 2 {
+            return None;
+        }
+        return Some((parts[0].trim().to_string(), parts[1].trim().to_string()));
+    }
+
+    // otherwise, each value is quoted with either kind of quote
+    let mut values = Vec::new();
+    let mut rest = args;
+    while values.len() < 2 {
+        let quote = rest.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let close = rest[1..].find(quote)? + 1;
+        values.push(rest[1..close].to_string());
+        rest = rest[close + 1..].trim_start();
+    }
+    Some((values.remove(0), values.remove(0)))
+}
+
+/// Split function arguments on commas that aren't nested inside another reference
+fn split_args(args: &str) -> Vec<&str> {
+    let mut split = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in args.char_indices() {
+        match c {
+            '(' | '{' => depth += 1,
+            ')' | '}' => depth -= 1,
+            ',' if depth == 0 => {
+                split.push(&args[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    split.push(&args[start..]);
+
+    split
+}
+
+/// Split a recipe line into its separate shell commands, without any recipe prefixes that
+/// silence the command or ignore its errors
+fn shell_commands(line: &str) -> Vec<&str> {
+    lazy_static! {
+        static ref COMMAND_SEP: Regex = Regex::new(r"&&|\|\||;").unwrap();
+    }
+
+    COMMAND_SEP
+        .split(line)
+        .map(|command| command.trim().trim_start_matches(['@', '-', '+']))
+        .collect()
+}
+
+/// Split a recipe line into groups of commands that run in the same directory, following the
+/// `cd DIR` commands between them, along with the directory each group runs in. `cd` and
+/// `export NAME=value` commands update the shell's state rather than being included, and
+/// references to exported variables in the commands after them, like `$$NAME` or `$${NAME}`,
+/// are replaced with their values. Each group is joined back into an indented line.
+fn split_shell_commands(line: &str, shell: &mut ShellState) -> Vec<(Option<String>, String)> {
+    lazy_static! {
+        static ref COMMAND_SEP: Regex = Regex::new(r"&&|\|\||;").unwrap();
+        static ref EXPORT: Regex = Regex::new(r"^[@+-]*export\s").unwrap();
+        static ref CD: Regex = Regex::new(r"^[@+-]*cd\s+(?P<dir>[^\s-]\S*)$").unwrap();
+        static ref WORD: Regex = Regex::new(r"\S+").unwrap();
+        static ref ASSIGNMENT: Regex = Regex::new(r"^(?P<name>[A-Za-z_]\w*)=").unwrap();
+    }
+
+    let (masked, _) = mask_quotes(line);
+    let indent = &line[..line.len() - line.trim_start().len()];
+    let mut groups: Vec<(Option<String>, Vec<String>)> = vec![(shell.dir.clone(), Vec::new())];
+    let mut changed = false;
+    let mut start = 0;
+    let ends = COMMAND_SEP
+        .find_iter(&masked)
+        .map(|sep| (sep.start(), sep.end()))
+        .chain(std::iter::once((line.len(), line.len())));
+    for (end, next) in ends {
+        let command = line[start..end].trim();
+        let masked_command = masked[start..end].trim();
+        start = next;
+
+        if EXPORT.is_match(masked_command) {
+            // the masked words have the same offsets as the command's, but quoted spaces don't
+            // split them
+            for word in WORD.find_iter(masked_command).skip(1) {
+                let word = &command[word.range()];
+                if let Some(name) = ASSIGNMENT.captures(word).map(|m| m["name"].to_string()) {
+                    let value = word[name.len() + 1..].trim_matches(['"', '\'']);
+                    let value = substitute_shell_variables(value, &shell.exports);
+                    shell.exports.push((name, value));
+                }
+            }
+        } else if let Some(matches) = CD.captures(masked_command) {
+            let dir = command[matches.name("dir").unwrap().range()].trim_matches(['"', '\'']);
+            let dir = substitute_shell_variables(dir, &shell.exports);
+            shell.dir = match &shell.dir {
+                Some(current) => Some(in_directory(current, &dir)),
+                None => Some(dir),
+            };
+            groups.push((shell.dir.clone(), Vec::new()));
+        } else {
+            let command = substitute_shell_variables(command, &shell.exports);
+            groups.last_mut().unwrap().1.push(command);
+            continue;
+        }
+        changed = true;
+    }
+
+    // the line is kept as it was written when there's nothing to take out of it
+    if !changed {
+        return vec![(shell.dir.clone(), substitute_shell_variables(line, &shell.exports))];
+    }
+    groups
+        .into_iter()
+        .filter_map(|(dir, commands)| {
+            let commands: Vec<String> = commands.into_iter().filter(|c| !c.is_empty()).collect();
+            if commands.is_empty() {
+                None
+            } else {
+                Some((dir, format!("{}{}", indent, commands.join("; "))))
+            }
+        })
+        .collect()
+}
+
+/// Expand the `{a,b}` alternatives in a word into a word for each, like bash does, so
+/// `out/{x,y}.o` becomes `out/x.o` and `out/y.o`. Shell variables like `$${NAME}` are left as
+/// they are.
+fn expand_braces(word: &str) -> Vec<String> {
+    // the start of each open brace, and whether it's a variable's
+    let mut open: Vec<(usize, bool)> = Vec::new();
+    let mut commas = Vec::new();
+    for (idx, c) in word.char_indices() {
+        match c {
+            '{' => open.push((idx, word[..idx].ends_with('$'))),
+            ',' if open.len() == 1 && !open[0].1 => commas.push(idx),
+            '}' => match open.pop() {
+                Some((start, false)) if open.is_empty() && !commas.is_empty() => {
+                    let mut bounds = vec![start];
+                    bounds.append(&mut commas);
+                    bounds.push(idx);
+                    let (prefix, suffix) = (&word[..start], &word[idx + 1..]);
+                    return bounds
+                        .windows(2)
+                        .map(|b| format!("{}{}{}", prefix, &word[b[0] + 1..b[1]], suffix))
+                        .flat_map(|expanded| expand_braces(&expanded))
+                        .collect();
+                }
+                _ if open.is_empty() => commas.clear(),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    vec![word.to_string()]
+}
+
+/// Get the path of `path` relative to the makefile's directory, from the directory `dir`
+/// (which is itself relative to the makefile's directory, unless it's absolute)
+fn in_directory(dir: &str, path: &str) -> String {
+    lexical_normalize(&Path::new(dir).join(path)).display().to_string()
+}
+
+/// Replace references to shell variables (escaped from make, like `$$NAME` or `$${NAME}`)
+/// with their values, using the last value of each variable
+fn substitute_shell_variables(text: &str, variables: &[(String, String)]) -> String {
+    let mut text = text.to_string();
+    for (name, value) in variables.iter().rev() {
+        let reference = format!(r"\$\$(\{{{0}\}}|{0}\b)", regex::escape(name));
+        if let Ok(re) = Regex::new(&reference) {
+            text = re.replace_all(&text, regex::NoExpand(value)).to_string();
+        }
+    }
+    text
+}
+
+/// Get the recipe given on the same line as a rule, after a `;`
+fn inline_recipe(line: &str) -> Option<&str> {
+    lazy_static! {
+        static ref INLINE: Regex = Regex::new(r"^[^;#]*:[^;#]*;(?P<recipe>[^\r\n]*)").unwrap();
+    }
+
+    let recipe = INLINE.captures(line)?.name("recipe")?.as_str().trim();
+    if recipe.is_empty() {
+        None
+    } else {
+        Some(recipe)
+    }
+}
+
+/// Resolve `.` and `..` components of a path without touching the filesystem
+fn lexical_normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            // only step back over a directory name, not a root or a leading ..
+            Component::ParentDir
+                if matches!(normalized.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Convert backslash path separators to forward slashes. A backslash only counts as a
+/// separator if it's followed by a character that could start a file name; other backslashes
+/// (like an escaped space) are left alone.
+fn normalize_separators(path: &str) -> String {
+    let mut normalized = String::with_capacity(path.len());
+    let mut chars = path.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek()) {
+            ('\\', Some(next)) if next.is_alphanumeric() || ['_', '.', '-'].contains(next) => {
+                normalized.push('/')
+            }
+            _ => normalized.push(c),
+        }
+    }
+    normalized
+}
+
+/// Get the attributes given to a target by the special targets that list it (or a pattern
+/// matching it), like "precious" for .PRECIOUS. Special targets that apply to every target
+/// when listed without prerequisites do so.
+fn special_attributes(special_targets: &BTreeMap<String, Vec<String>>, name: &str) -> Vec<String> {
+    const ALL_WHEN_EMPTY: &[&str] = &[".SECONDARY", ".IGNORE", ".SILENT"];
+    const ATTRIBUTES: &[&str] = &[
+        ".PRECIOUS", ".SECONDARY", ".INTERMEDIATE", ".NOTINTERMEDIATE", ".IGNORE", ".SILENT",
+        ".NOTPARALLEL",
+    ];
+
+    let mut attributes = Vec::new();
+    for (special, names) in special_targets {
+        if !ATTRIBUTES.contains(&special.as_str()) {
+            continue;
+        }
+        let applies = if names.is_empty() {
+            ALL_WHEN_EMPTY.contains(&special.as_str())
+        } else {
+            names.iter().any(|n| n == name || pattern_stem(n, name).is_some())
+        };
+        if applies {
+            attributes.push(special.trim_start_matches('.').to_lowercase());
+        }
+    }
+    attributes
+}
+
+/// Split a rule's prerequisites into the normal ones and the order-only ones after a `|`
+fn split_order_only(prereqs: Vec<String>) -> (Vec<String>, Vec<String>) {
+    let text = prereqs.join(" ");
+    let words = |s: &str| s.split_whitespace().map(|w| w.to_string()).collect();
+    match text.split_once('|') {
+        Some((normal, order_only)) => (words(normal), words(order_only)),
+        None => (prereqs, Vec::new()),
+    }
+}
+
+/// Split a static pattern rule, like `a.o b.o: %.o: %.c`, into its targets, target pattern,
+/// and prerequisite patterns
+fn split_static_pattern(line: &str) -> Option<(Vec<String>, String, Vec<String>)> {
+    lazy_static! {
+        static ref STATIC_PATTERN: Regex = Regex::new(
+            r"^(?P<targets>[^:#=;\s][^:#=;]*):(?P<pattern>[^:#=;]+):(?P<prereqs>[^:#;\r\n]*)"
+        )
+        .unwrap();
+    }
+
+    let matches = STATIC_PATTERN.captures(line)?;
+    let pattern = matches["pattern"].trim();
+    if !PatternRule::is_pattern(pattern) || pattern.contains(char::is_whitespace) {
+        return None;
+    }
+
+    let words = |s: &str| s.split_whitespace().map(|w| w.to_string()).collect();
+    Some((words(&matches["targets"]), pattern.to_string(), words(&matches["prereqs"])))
+}
+
+/// Copy a target defined by a pattern, replacing the first `%` of its prerequisites and
+/// outputs with a stem
+fn substitute_stem(target: &Target, stem: &str) -> Target {
+    let substitute = |s: &String| s.replacen('%', stem, 1);
+    let mut target = target.clone();
+    target.prerequisites = target.prerequisites.iter().map(substitute).collect();
+    target.order_only = target.order_only.iter().map(substitute).collect();
+    if let Some(outputs) = &mut target.output {
+        *outputs = outputs.iter().map(substitute).collect();
+    }
+    target.templated_outputs = target.templated_outputs.iter().map(substitute).collect();
+    for source in &mut target.output_sources {
+        source.output = substitute(&source.output);
+    }
+    target
+}
+
+/// Create a target from a pattern rule for each file in the makefile's directory that matches
+/// the rule's first `%` prerequisite
+fn instantiate_pattern(rule: &PatternRule) -> Vec<Target> {
+    let source = match rule.prerequisites.iter().find(|p| PatternRule::is_pattern(p)) {
+        Some(p) => p,
+        None => return Vec::new(),
+    };
+    // only the file name can contain the `%`
+    let (dir, name_pattern) = match source.rfind('/') {
+        Some(idx) if !source[..idx].contains('%') => (&source[..idx + 1], &source[idx + 1..]),
+        Some(_) => return Vec::new(),
+        None => ("", source.as_str()),
+    };
+
+    let base = rule
+        .file
+        .as_ref()
+        .and_then(|f| Path::new(f).parent())
+        .unwrap_or_else(|| Path::new(""));
+    let mut names: Vec<String> = match std::fs::read_dir(base.join(dir)) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(e) => {
+            debug!("Couldn't read {} for pattern {}: {}", dir, source, e);
+            return Vec::new();
+        }
+    };
+    names.sort();
+
+    let mut targets = Vec::new();
+    for name in names {
+        let stem = match pattern_stem(name_pattern, &name) {
+            Some(stem) if !stem.is_empty() => stem,
+            _ => continue,
+        };
+        let substitute = |s: &String| s.replacen('%', stem, 1);
+        debug!("Instantiating {} for {}{}", rule.target, dir, name);
+
+        let mut target = Target::new(substitute(&rule.target));
+        target.file = rule.file.clone();
+        target.prerequisites = rule.prerequisites.iter().map(substitute).collect();
+        target.order_only = rule.order_only.iter().map(substitute).collect();
+        if !rule.output.is_empty() {
+            target.output = Some(rule.output.iter().map(substitute).collect());
+        }
+        target.recipe = rule.recipe.clone();
+        targets.push(target);
+    }
+    targets
+}
+
+/// Remove repeated items from a list, keeping the first occurrence of each
+fn dedup_in_order(items: &mut Vec<String>) {
+    let mut seen = HashSet::new();
+    items.retain(|item| seen.insert(item.clone()));
+}
+
+/// Get the dependency file written by a compiler's `-MD` or `-MMD` flag, which is named after
+/// the `-o` object file with a `.d` extension. Lines that name the file with `-MF` are
+/// handled by the output regexes instead.
+fn implied_dependency_file(masked: &str, line: &str) -> Option<String> {
+    lazy_static! {
+        static ref DEP_FLAG: Regex = Regex::new(r"\s-MM?D(\s|$)").unwrap();
+        static ref DEP_FILE: Regex = Regex::new(r"\s-MF").unwrap();
+        static ref OBJECT: Regex = Regex::new(r"\s-o\s+(?P<path>[^\s]+)").unwrap();
+    }
+
+    if !DEP_FLAG.is_match(masked) || DEP_FILE.is_match(masked) {
+        return None;
+    }
+
+    let range = OBJECT.captures(masked)?.name("path")?.range();
+    let object = Path::new(line[range].trim_matches(['"', '\'']));
+    Some(object.with_extension("d").display().to_string())
+}
+
+/// A logical line read from a makefile
+struct LogicalLine {
+    text: String,
+    // number of physical lines it spans
+    count: usize,
+    // length of the line in bytes, including any that weren't kept because of the limit it
+    // was read with
+    length: usize,
+    // whether it had bytes that aren't valid UTF-8, which were replaced
+    invalid_utf8: bool,
+}
+
+/// Read the next logical line from a makefile, joining any lines continued with a trailing
+/// backslash. Continuations inside a quoted string are joined as-is (minus the next line's
+/// indentation), while others collapse to a single space as make does. Lines can end with
+/// `\r\n` as well as `\n`, and the line is returned ending with `\n` either way.
+/// Bytes that aren't valid UTF-8 are replaced rather than failing the read, and no more than
+/// `limit` bytes of each physical line are kept, if there's a limit.
+/// Returns `None` at the end of the file.
+fn read_logical_line<R: BufRead>(
+    reader: &mut R,
+    limit: Option<usize>,
+) -> Result<Option<LogicalLine>, String> {
+    let mut line = String::new();
+    let mut count = 0;
+    let mut dropped = 0;
+    let mut invalid_utf8 = false;
+
+    loop {
+        let mut bytes = Vec::new();
+        match read_physical_line(reader, &mut bytes, limit.unwrap_or(usize::MAX)) {
+            // eof
+            Ok((0, _)) => break,
+            Ok((_, skipped)) => {
+                count += 1;
+                dropped += skipped;
+            }
+            Err(e) => return Err(format!("Failed to read from file: {:?}", e)),
+        }
+        let next = match String::from_utf8(bytes) {
+            Ok(next) => next,
+            Err(e) => {
+                invalid_utf8 = true;
+                String::from_utf8_lossy(e.as_bytes()).into_owned()
+            }
+        };
+
+        let next = next.trim_end_matches(['\n', '\r']);
+
+        // join onto the previous line if there is one
+        if line.is_empty() {
+            line.push_str(next);
+        } else if mask_quotes(&line).1.is_some() {
+            line.push_str(next.strip_prefix('\t').unwrap_or(next));
+        } else {
+            line = format!("{} {}", line.trim_end(), next.trim_start());
+        }
+
+        // an odd number of trailing backslashes means the final one escapes the newline
+        let slashes = line.chars().rev().take_while(|c| *c == '\\').count();
+        if slashes % 2 == 0 {
+            break;
+        }
+        line.pop();
+    }
+
+    // a continuation on the last line of the file ends the line
+    if count == 0 {
+        return Ok(None);
+    }
+    line.push('\n');
+    Ok(Some(LogicalLine { length: line.len() + dropped, text: line, count, invalid_utf8 }))
+}
+
+/// Read the next physical line from a makefile as bytes, including its newline, keeping at
+/// most `limit` bytes of it. Returns the number of bytes read (which is 0 at the end of the
+/// file), and the number of those that weren't kept, not counting the newline.
+fn read_physical_line<R: BufRead>(
+    reader: &mut R,
+    line: &mut Vec<u8>,
+    limit: usize,
+) -> std::io::Result<(usize, usize)> {
+    let mut read = 0;
+    let mut newline = false;
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) => buf,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        if buf.is_empty() {
+            break;
+        }
+        let (chunk, done) = match buf.iter().position(|b| *b == b'\n') {
+            Some(idx) => (&buf[..=idx], true),
+            None => (buf, false),
+        };
+        let room = limit.saturating_sub(line.len());
+        line.extend_from_slice(&chunk[..chunk.len().min(room)]);
+        let used = chunk.len();
+        read += used;
+        reader.consume(used);
+        if done {
+            newline = true;
+            break;
+        }
+    }
+
+    // a character cut in half by the limit isn't kept either
+    if read > line.len() {
+        if let Err(e) = std::str::from_utf8(line) {
+            if e.error_len().is_none() {
+                line.truncate(e.valid_up_to());
+            }
+        }
+    }
+    let skipped = read - line.len();
+    let newline_skipped = newline && line.last() != Some(&b'\n');
+    Ok((read, skipped - newline_skipped as usize))
+}
+
+/// Remove a trailing comment from a line, leaving lines that are only a comment as they are.
+/// A `#` in quotes doesn't start a comment, and in a recipe only one starting a word does, like
+/// in the shell. Elsewhere, an escaped `\#` is a literal `#`.
+fn strip_comment(line: &str, recipe: bool) -> String {
+    if line.trim_start().starts_with('#') {
+        return line.to_string();
+    }
+
+    let line = match comment_start(line, recipe) {
+        Some(i) => line[..i].trim_end(),
+        None => line.trim_end_matches(['\r', '\n']),
+    };
+    if recipe {
+        line.to_string()
+    } else {
+        line.replace("\\#", "#")
+    }
+}
+
+/// Get the byte offset of the `#` starting a line's trailing comment, ignoring any `#` that's
+/// quoted or escaped. In a recipe, a comment also has to start a word.
+fn comment_start(line: &str, recipe: bool) -> Option<usize> {
+    // quoted text is masked, and the masked line has the same byte offsets as the original
+    let (masked, _) = mask_quotes(line);
+    let bytes = masked.as_bytes();
+    let start = masked.char_indices().find(|&(i, c)| {
+        let prev = if i > 0 { bytes[i - 1] } else { b' ' };
+        c == '#' && prev != b'\\' && (!recipe || prev.is_ascii_whitespace())
+    });
+    start.map(|(i, _)| i)
+}
+
+/// Get the text of a rule's trailing `##` comment, like the one in `build: ## Compile the app`
+fn doc_comment(line: &str) -> Option<String> {
+    let start = comment_start(line, false)?;
+    let text = line[start..].strip_prefix("##")?.trim();
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_string())
+    }
+}
+
+/// Replace the contents of any shell-quoted strings in a line with placeholder bytes,
+/// leaving the quotes themselves in place. The result has the same byte offsets as `line`.
+/// Also returns the quote character of a string left open at the end of the line.
+/// `$(shell)` markers are masked entirely, so they're taken as part of the word they're in.
+fn mask_quotes(line: &str) -> (String, Option<char>) {
+    // placeholder that won't match any regex looking for whitespace or commands
+    const MASK: char = '\u{1}';
+
+    let mut masked = String::with_capacity(line.len());
+    let mut quote: Option<char> = None;
+    let mut escaped = false;
+    let mut in_marker = false;
+
+    for (i, c) in line.char_indices() {
+        let in_quote = quote.is_some();
+
+        if !in_quote && !escaped && line[i..].starts_with(SHELL_MARKER) {
+            in_marker = true;
+        }
+        if in_marker {
+            masked.extend(std::iter::repeat_n(MASK, c.len_utf8()));
+            in_marker = c != '>';
+            continue;
+        }
+
+        if escaped {
+            escaped = false;
+        } else {
+            match (quote, c) {
+                (Some('\''), '\'') => quote = None,
+                // nothing is special inside single quotes
+                (Some('\''), _) => (),
+                // backslashes escape the next char everywhere else
+                (_, '\\') => escaped = true,
+                (None, '"') | (None, '\'') => quote = Some(c),
+                (Some('"'), '"') => quote = None,
+                _ => (),
+            }
+        }
+
+        // keep the quote characters themselves so quoted paths are still recognizable
+        if in_quote && quote.is_some() {
+            masked.extend(std::iter::repeat_n(MASK, c.len_utf8()));
+        } else {
+            masked.push(c);
+        }
+    }
+
+    (masked, quote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    /// Write `text` to a makefile in a new directory and parse it
+    fn parse(parser: &mut Parser, text: &str) -> Vec<Target> {
+        let dir = write_files(&[("Makefile", text)]);
+        parser.parse_file(dir.path().join("Makefile"), false).unwrap()
+    }
+
+    /// Write each `(name, text)` file into a new directory
+    fn write_files(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, text) in files {
+            let path = dir.path().join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, text).unwrap();
+        }
+        dir
+    }
+
+    fn target<'a>(targets: &'a [Target], name: &str) -> &'a Target {
+        targets.iter().find(|t| t.name == name).unwrap()
+    }
+
+    /// Parse `text` like `parse`, in strict mode
+    fn parse_strict(parser: &mut Parser, text: &str) -> Result<Vec<Target>, String> {
+        let dir = write_files(&[("Makefile", text)]);
+        parser.parse_file(dir.path().join("Makefile"), true)
+    }
+
+    /// Get the value of a variable once parsing is done
+    fn value<'a>(parser: &'a Parser, name: &str) -> Option<&'a str> {
+        parser.variables().get(name).map(|v| v.value.as_str())
+    }
+
+    #[test]
+    fn classifies_blank_lines() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.classify_line(""), LineKind::Blank);
+        assert_eq!(parser.classify_line("  \t "), LineKind::Blank);
+    }
+
+    #[test]
+    fn classifies_comments() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.classify_line("# build everything"), LineKind::Comment);
+    }
+
+    #[test]
+    fn classifies_recipes_only_after_a_target() {
+        let mut parser = Parser::new();
+        assert_ne!(parser.classify_line("\tgcc -o app main.c"), LineKind::Recipe);
+
+        parser.targets.push(Target::new("app".to_string()));
+        assert_eq!(parser.classify_line("\tgcc -o app main.c"), LineKind::Recipe);
+    }
+
+    #[test]
+    fn classifies_directives() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.classify_line("include common.mk"), LineKind::Directive);
+        assert_eq!(parser.classify_line("ifeq ($(CC),gcc)"), LineKind::Directive);
+        assert_eq!(parser.classify_line(".PHONY: all"), LineKind::Directive);
+    }
+
+    #[test]
+    fn classifies_targets() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.classify_line("app: main.o util.o"),
+            LineKind::Target {
+                name: "app".to_string(),
+                prereqs: vec!["main.o".to_string(), "util.o".to_string()],
+                double_colon: false,
+            }
+        );
+        assert_eq!(
+            parser.classify_line("clean::"),
+            LineKind::Target { name: "clean".to_string(), prereqs: Vec::new(), double_colon: true }
+        );
+    }
+
+    #[test]
+    fn classifies_variables() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.classify_line("CFLAGS := -O2 -Wall"),
+            LineKind::Variable {
+                name: "CFLAGS".to_string(),
+                value: "-O2 -Wall".to_string(),
+                flavor: Flavor::Simple,
+            }
+        );
+        assert_eq!(
+            parser.classify_line("PREFIX ?= /usr/local"),
+            LineKind::Variable {
+                name: "PREFIX".to_string(),
+                value: "/usr/local".to_string(),
+                flavor: Flavor::Conditional,
+            }
+        );
+    }
+
+    #[test]
+    fn classifies_target_variables() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.classify_line("release: CFLAGS += -O3"),
+            LineKind::TargetVariable {
+                targets: vec!["release".to_string()],
+                name: "CFLAGS".to_string(),
+                value: "-O3".to_string(),
+                flavor: Flavor::Append,
+            }
+        );
+    }
+
+    #[test]
+    fn classifies_unrecognized_lines() {
+        let mut parser = Parser::new();
+        assert_eq!(parser.classify_line("$(info hello)"), LineKind::Other);
+    }
+
+    #[test]
+    fn env_file_values_override_conditional_assignments() {
+        let dir = tempfile::tempdir().unwrap();
+        let env = dir.path().join(".env");
+        fs::write(&env, "# build settings\n\nexport OUT_DIR=\"dist\"\n").unwrap();
+
+        let mut parser = Parser::new();
+        parser.load_env_file(&env).unwrap();
+        let text = "OUT_DIR ?= build\napp:\n\tgcc -o $(OUT_DIR)/app main.c\n";
+        let targets = parse(&mut parser, text);
+
+        assert_eq!(target(&targets, "app").output, Some(vec!["dist/app".to_string()]));
+        assert_eq!(parser.variables()["OUT_DIR"].origin, Origin::Environment);
+    }
+
+    #[test]
+    fn records_paths_removed_by_rm() {
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, "clean:\n\trm -rf build dist\n\trm -f *.o\n");
+        assert_eq!(target(&targets, "clean").removes, ["build", "dist", "*.o"]);
+    }
+
+    #[test]
+    fn ignores_output_flags_in_quoted_strings() {
+        let mut parser = Parser::new();
+        let targets = parse(
+            &mut parser,
+            "app:\n\techo \"building with \\\n\t-o fake > fake.txt\" && gcc -o real main.c\n",
+        );
+        assert_eq!(target(&targets, "app").output, Some(vec!["real".to_string()]));
+    }
+
+    #[test]
+    fn includes_declared_only_targets_when_asked() {
+        let text = ".PHONY: all lint\nall:\n\t@echo done\n";
+
+        let targets = parse(&mut Parser::new(), text);
+        assert!(!targets.iter().any(|t| t.name == "lint"));
+
+        let targets = parse(&mut Parser::new().include_declared_only(true), text);
+        let lint = target(&targets, "lint");
+        assert!(lint.phony);
+        assert!(lint.output.is_none() && lint.recipe.is_empty());
+    }
+
+    #[test]
+    fn stops_following_includes_past_the_depth_limit() {
+        let dir = write_files(&[
+            ("Makefile", "include one.mk\nroot:\n"),
+            ("one.mk", "include two.mk\none:\n"),
+            ("two.mk", "include three.mk\ntwo:\n"),
+            ("three.mk", "three:\n"),
+        ]);
+        let mut parser = Parser::new().max_include_depth(2);
+        let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["two", "one", "root"]);
+        let diagnostics = parser.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].severity, Severity::Warning);
+        assert!(diagnostics[0].message.starts_with("Include depth limit of 2 exceeded"));
+    }
+
+    #[test]
+    fn stops_include_cycles() {
+        let dir = write_files(&[
+            ("Makefile", "include a.mk\nroot:\n"),
+            ("a.mk", "include Makefile\na:\n"),
+        ]);
+        let mut parser = Parser::new();
+        let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+
+        assert_eq!(targets.len(), 2);
+        assert!(parser.diagnostics()[0].message.starts_with("Include cycle found"));
+    }
+
+    #[test]
+    fn and_returns_the_last_argument_unless_one_is_empty() {
+        let mut parser = Parser::new();
+        parser.set_variable("A", "yes", Origin::File);
+        parser.set_variable("EMPTY", "", Origin::File);
+
+        assert_eq!(parser.eval_variable("$(and $(A),last)", &[]).unwrap(), "last");
+        assert_eq!(parser.eval_variable("$(and $(A),$(EMPTY),last)", &[]).unwrap(), "");
+        assert_eq!(parser.eval_variable("$(and $(UNDEFINED),last)", &[]).unwrap(), "");
+    }
+
+    #[test]
+    fn or_returns_the_first_non_empty_argument() {
+        let mut parser = Parser::new();
+        parser.set_variable("A", "first", Origin::File);
+
+        assert_eq!(parser.eval_variable("$(or $(UNDEFINED), ,$(A),last)", &[]).unwrap(), "first");
+        assert_eq!(parser.eval_variable("$(or $(UNDEFINED),)", &[]).unwrap(), "");
+    }
+
+    #[test]
+    fn and_and_or_stop_at_the_deciding_argument() {
+        let mut parser = Parser::new();
+        parser.eval_variable("$(or found,$(error skipped))", &[]).unwrap();
+        parser.eval_variable("$(and ,$(error skipped))", &[]).unwrap();
+        assert!(parser.diagnostics().is_empty());
+
+        parser.eval_variable("$(or ,$(error reached))", &[]).unwrap();
+        assert_eq!(parser.diagnostics().len(), 1);
+    }
+
+    #[test]
+    fn detects_dependency_files_from_mf() {
+        let text = "build/foo.o: foo.c\n\tgcc -MMD -MF build/foo.d -c -o build/foo.o foo.c\n";
+        let targets = parse(&mut Parser::new(), text);
+        let output = target(&targets, "build/foo.o").output.clone().unwrap();
+        assert!(output.contains(&"build/foo.d".to_string()), "{:?}", output);
+        assert!(output.contains(&"build/foo.o".to_string()), "{:?}", output);
+    }
+
+    #[test]
+    fn infers_dependency_files_from_md() {
+        let text = "build/bar.o: bar.c\n\tgcc -MD -c -o build/bar.o bar.c\n";
+        let targets = parse(&mut Parser::new(), text);
+        let output = target(&targets, "build/bar.o").output.clone().unwrap();
+        assert!(output.contains(&"build/bar.d".to_string()), "{:?}", output);
+    }
+
+    #[test]
+    fn folds_the_case_of_target_names_when_asked() {
+        let text = "Build: a.o\n\tgcc -o app a.o\nbuild: b.o\n";
+
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(targets.len(), 2);
+
+        let targets = parse(&mut Parser::new().fold_case_targets(true), text);
+        assert_eq!(targets.len(), 1);
+        assert_eq!(targets[0].name, "Build");
+        assert_eq!(targets[0].prerequisites, ["a.o", "b.o"]);
+    }
+
+    #[test]
+    fn records_goals_passed_to_make_as_delegates() {
+        let text = "all: ; $(MAKE) build test\nbuild:\ntest:\nsub:\n\t$(MAKE) -C lib all\n";
+        let targets = parse(&mut Parser::new().detect_delegation(true), text);
+        assert_eq!(target(&targets, "all").delegates_to, ["build", "test"]);
+        assert!(target(&targets, "sub").delegates_to.is_empty());
+
+        let targets = parse(&mut Parser::new(), text);
+        assert!(target(&targets, "all").delegates_to.is_empty());
+    }
+
+    #[test]
+    fn serializes_identically_across_runs() {
+        let text = "ZLIB := 1\nAPP = app\nCFLAGS := -O2\nBINDIR = bin\nMODE ?= release\n\
+            $(APP): CFLAGS += -g\n$(APP): main.o\n\tgcc $(CFLAGS) -o $(BINDIR)/$(APP) main.o\n";
+        let dir = write_files(&[("Makefile", text)]);
+        let serialize = || {
+            let mut parser = Parser::new();
+            let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+            serde_json::to_string(&(targets, parser.variables())).unwrap()
+        };
+
+        let first = serialize();
+        for _ in 0..5 {
+            assert_eq!(serialize(), first);
+        }
+    }
+
+    #[test]
+    fn tracks_each_assignment_to_a_variable() {
+        let dir = write_files(&[
+            ("Makefile", "CFLAGS = -O2\ninclude extra.mk\nall:\n"),
+            ("extra.mk", "\nCFLAGS += -Wall\n"),
+        ]);
+        let mut parser = Parser::new().track_assignments(true);
+        parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+
+        let assignments = &parser.assignments()["CFLAGS"];
+        let events: Vec<(usize, &str, &str)> = assignments
+            .iter()
+            .map(|a| (a.line, a.operator.as_str(), a.value.as_str()))
+            .collect();
+        assert_eq!(events, [(1, "=", "-O2"), (2, "+=", "-Wall")]);
+        assert!(assignments[0].source_file.ends_with("Makefile"));
+        assert!(assignments[1].source_file.ends_with("extra.mk"));
+
+        let mut parser = Parser::new();
+        parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+        assert!(parser.assignments().is_empty());
+    }
+
+    #[test]
+    fn detects_objcopy_outputs() {
+        let text = "app.bin: app\n\tobjcopy -O binary app app.bin\n\
+            app.hex: app\n\tarm-none-eabi-objcopy app app.hex\n";
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(target(&targets, "app.bin").output, Some(vec!["app.bin".to_string()]));
+        assert_eq!(target(&targets, "app.hex").output, Some(vec!["app.hex".to_string()]));
+    }
+
+    #[test]
+    fn detects_strip_outputs() {
+        let targets = parse(&mut Parser::new(), "a.stripped: a\n\tstrip -o a.stripped a\n");
+        assert_eq!(target(&targets, "a.stripped").output, Some(vec!["a.stripped".to_string()]));
+    }
+
+    #[test]
+    fn makeflags_supply_include_dirs_and_overrides() {
+        let dir = write_files(&[
+            ("Makefile", "MODE = debug\ninclude common.mk\n"),
+            ("shared/common.mk", "app:\n\tgcc -o build/$(MODE)/app main.c\n"),
+        ]);
+        let shared = dir.path().join("shared");
+
+        let mut parser = Parser::new();
+        parser.apply_makeflags(&format!("-k -I {} -- MODE=release", shared.display()));
+        let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+
+        assert_eq!(target(&targets, "app").output, Some(vec!["build/release/app".to_string()]));
+        assert!(parser.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn refuses_includes_outside_the_makefile_directory() {
+        let dir = write_files(&[("outside.mk", "outside:\n"), ("project/inside.mk", "inside:\n")]);
+        let text = format!(
+            "include inside.mk\n-include {}\n-include ../outside.mk\n-include ../../etc/passwd\n",
+            dir.path().join("outside.mk").display()
+        );
+        fs::write(dir.path().join("project/Makefile"), text).unwrap();
+        let makefile = dir.path().join("project/Makefile");
+
+        let mut parser = Parser::new().relative_includes_only(true);
+        let targets = parser.parse_file(&makefile, false).unwrap();
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["inside"]);
+        let messages: Vec<&str> = parser.diagnostics().iter().map(|d| d.message.as_str()).collect();
+        assert_eq!(messages.len(), 3, "{:?}", messages);
+        assert!(messages.iter().all(|m| m.starts_with("Refusing to include")));
+
+        let targets = Parser::new().parse_file(&makefile, false).unwrap();
+        assert_eq!(targets.len(), 2);
+    }
+
+    #[test]
+    fn detects_rules_generated_by_eval() {
+        let text = "OBJ = main.o\n$(eval $(OBJ): main.c ; gcc -c -o $(OBJ) main.c)\n";
+
+        let targets = parse(&mut Parser::new().allow_eval(true), text);
+        let obj = target(&targets, "main.o");
+        assert_eq!(obj.prerequisites, ["main.c"]);
+        assert_eq!(obj.output, Some(vec!["main.o".to_string()]));
+
+        let targets = parse(&mut Parser::new(), text);
+        assert!(!targets.iter().any(|t| t.name == "main.o"));
+    }
+
+    #[test]
+    fn detects_rules_generated_by_eval_of_a_template() {
+        let text = "define PROGRAM\n$(1): $(1).o\n\tgcc -o $(1) $(1).o\nendef\n\
+            $(foreach prog,server client,$(eval $(call PROGRAM,$(prog))))\n";
+        let targets = parse(&mut Parser::new().allow_eval(true), text);
+        assert_eq!(target(&targets, "server").output, Some(vec!["server".to_string()]));
+        assert_eq!(target(&targets, "client").prerequisites, ["client.o"]);
+    }
+
+    #[test]
+    fn graph_only_skips_recipes() {
+        let text = "all: app docs\napp: main.o util.o\n\tgcc -o app main.o util.o\n\
+            main.o: main.c\n\tgcc -c -o main.o main.c\ndocs:\n\tdoxygen\n";
+        let targets = parse(&mut Parser::new().graph_only(true), text);
+
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["all", "app", "main.o", "docs"]);
+        assert_eq!(target(&targets, "app").prerequisites, ["main.o", "util.o"]);
+        assert!(targets.iter().all(|t| t.recipe.is_empty() && t.output.is_none()));
+    }
+
+    #[test]
+    fn normalizes_backslash_separators_but_not_escapes() {
+        assert_eq!(normalize_separators(r"build\obj\foo.o"), "build/obj/foo.o");
+        assert_eq!(normalize_separators(r"my\ dir\app.exe"), r"my\ dir/app.exe");
+    }
+
+    #[test]
+    fn normalizes_separators_in_outputs_when_asked() {
+        let text = "app:\n\tgcc -o build\\obj\\foo.o foo.c\n";
+        let targets = parse(&mut Parser::new().normalize_separators(true), text);
+        assert_eq!(target(&targets, "app").output, Some(vec!["build/obj/foo.o".to_string()]));
+
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(target(&targets, "app").output, Some(vec![r"build\obj\foo.o".to_string()]));
+    }
+
+    #[test]
+    fn calls_the_line_callback_for_each_line() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let recorder = Rc::clone(&seen);
+        let mut parser = Parser::new().on_line(move |number, text, kind| {
+            let kind = match kind {
+                LineKind::Target { .. } => "target",
+                LineKind::Variable { .. } => "variable",
+                LineKind::Comment => "comment",
+                LineKind::Recipe => "recipe",
+                LineKind::Directive => "directive",
+                LineKind::Blank => "blank",
+                _ => "other",
+            };
+            recorder.borrow_mut().push((number, text.trim_end().to_string(), kind));
+        });
+        let text = "# tools\nCC = gcc\n\n.PHONY: all\nall: app\n\
+            app:\n\t$(CC) -o app \\\n\t\tmain.c\n";
+        parse(&mut parser, text);
+
+        let seen = seen.borrow();
+        let count = |kind: &str| seen.iter().filter(|(_, _, k)| *k == kind).count();
+        assert_eq!(seen.len(), 7);
+        assert_eq!(
+            [count("comment"), count("variable"), count("blank"), count("directive")],
+            [1, 1, 1, 1]
+        );
+        assert_eq!([count("target"), count("recipe"), count("other")], [2, 1, 0]);
+        assert_eq!(seen[1], (2, "CC = gcc".to_string(), "variable"));
+        assert_eq!(seen[6].0, 7);
+    }
+
+    #[test]
+    fn groups_double_colon_rules_when_asked() {
+        let text = "foo:: a.c\n\tgcc -o a.out a.c\nfoo:: b.c\n\tgcc -o b.out b.c\n";
+
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(targets.len(), 2);
+        assert!(targets.iter().all(|t| t.double_colon && t.variants.is_empty()));
+
+        let targets = parse(&mut Parser::new().group_double_colon(true), text);
+        assert_eq!(targets.len(), 1);
+        let variants = &targets[0].variants;
+        assert_eq!(variants.len(), 2);
+        assert_eq!(variants[0].prerequisites, ["a.c"]);
+        assert_eq!(variants[0].output, ["a.out"]);
+        assert_eq!(variants[1].prerequisites, ["b.c"]);
+        assert_eq!(variants[1].output, ["b.out"]);
+        assert_eq!(variants[1].recipe.len(), 1);
+    }
+
+    #[test]
+    fn keeps_recipes_unexpanded_when_asked() {
+        let text = "APP = app\nCC = gcc\n$(APP): main.c\n\t$(CC) -o $(APP) main.c\n";
+
+        let targets = parse(&mut Parser::new().expand_in_recipes(false), text);
+        let app = target(&targets, "app");
+        assert_eq!(app.recipe[0].command, "$(CC) -o $(APP) main.c");
+        assert_eq!(app.output, Some(vec!["app".to_string()]));
+
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(target(&targets, "app").recipe[0].command, "gcc -o app main.c");
+    }
+
+    #[test]
+    fn detects_outputs_with_rules_from_a_rules_file() {
+        let dir = write_files(&[(
+            "rules.json",
+            r#"{
+                "outputs": [{ "tool": "zipper", "pattern": "\\s(?P<path>\\S+\\.zip)" }],
+                "variables": { "ZIPPER": "zipper" }
+            }"#,
+        )]);
+        let mut parser = Parser::new();
+        parser.load_rules_file(dir.path().join("rules.json")).unwrap();
+
+        let text = "dist:\n\t$(ZIPPER) -9 release.zip build/*\n";
+        let targets = parse(&mut parser, text);
+        assert_eq!(target(&targets, "dist").output, Some(vec!["release.zip".to_string()]));
+    }
+
+    #[test]
+    fn templates_outputs_written_in_shell_loops() {
+        let text = "SRCS = a.c b.c\nobjs:\n\tfor f in $(SRCS); do cc -c -o $$f.o $$f; done\n";
+        let targets = parse(&mut Parser::new(), text);
+        let objs = target(&targets, "objs");
+        assert_eq!(objs.templated_outputs, ["$$f.o"]);
+        assert!(objs.output.is_none());
+    }
+
+    #[test]
+    fn reports_recursive_variables_and_recovers_when_asked() {
+        let text = "A = $(B)\nB = x$(A)\nout/$(A)app:\n\ttouch $@\nother:\n";
+
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert!(!targets.iter().any(|t| t.name == "out/xapp"));
+        let message = &parser.diagnostics()[0].message;
+        assert_eq!(message, "Variable A has a recursive dependency: A -> B -> A");
+
+        let mut parser = Parser::new().break_recursion(true);
+        let targets = parse(&mut parser, text);
+        assert!(targets.iter().any(|t| t.name == "out/xapp"));
+        assert!(!parser.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn truncates_or_skips_overlong_lines() {
+        // 40,008 bytes, counting the newline
+        let long = format!("OBJS = {}\n", "x.o ".repeat(10_000));
+        let text = format!("{}app: main.o\n\tgcc -o app main.o\n", long);
+
+        let mut parser = Parser::new().max_line_length(100);
+        let targets = parse(&mut parser, &text);
+        assert_eq!(target(&targets, "app").output, Some(vec!["app".to_string()]));
+        assert_eq!(parser.variables()["OBJS"].value.len(), 100 - "OBJS = ".len());
+        assert_eq!(parser.diagnostics()[0].message, "Truncating line of 40008 bytes to 100");
+
+        let mut parser = Parser::new().max_line_length(100).skip_long_lines(true);
+        let targets = parse(&mut parser, &text);
+        assert_eq!(targets.len(), 1);
+        assert!(!parser.variables().contains_key("OBJS"));
+        assert_eq!(parser.diagnostics()[0].message, "Skipping line of 40008 bytes (limit is 100)");
+    }
+
+    #[test]
+    fn profiles_select_different_branches() {
+        let text = "DEBUG ?= 0\n\
+            ifeq ($(DEBUG),1)\nBUILD = build/debug\nelse\nBUILD = build/release\nendif\n\
+            app:\n\tgcc -o $(BUILD)/app main.c\n\
+            ifeq ($(DEBUG),1)\nvalgrind: app\n\tvalgrind $(BUILD)/app\nendif\n";
+        let run = |debug: &str| {
+            let mut parser = Parser::new();
+            parser.set_variable("DEBUG", debug, Origin::CommandLine);
+            let targets = parse(&mut parser, text);
+            let names: Vec<String> = targets.iter().map(|t| t.name.clone()).collect();
+            (names, target(&targets, "app").output.clone(), parser.variables()["BUILD"].clone())
+        };
+
+        let (names, output, build) = run("1");
+        assert_eq!(names, ["app", "valgrind"]);
+        assert_eq!(output, Some(vec!["build/debug/app".to_string()]));
+        assert_eq!(build.value, "build/debug");
+
+        let (names, output, build) = run("0");
+        assert_eq!(names, ["app"]);
+        assert_eq!(output, Some(vec!["build/release/app".to_string()]));
+        assert_eq!(build.value, "build/release");
+    }
+
+    #[test]
+    fn skips_heredoc_bodies_in_one_shell() {
+        let text = ".ONESHELL:\nconfig:\n\tcat > build/config.h <<EOF\n\
+            \t#define CC gcc -o fake\n\techo > fake.txt\n\tEOF\n\
+            \tcp build/config.h include/\n";
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(
+            target(&targets, "config").output,
+            Some(vec!["build/config.h".to_string(), "include/".to_string()])
+        );
+    }
+
+    #[test]
+    fn runs_the_lines_after_a_heredoc_as_commands() {
+        // without .ONESHELL, each line is run by its own shell, so the heredoc ends with its line
+        let text = "config:\n\tcat > build/config.h <<EOF\n\tgcc -o app main.c\n";
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(
+            target(&targets, "config").output,
+            Some(vec!["build/config.h".to_string(), "app".to_string()])
+        );
+    }
+
+    #[test]
+    fn keeps_automatic_variables_out_of_the_variables() {
+        let text = "app: main.o util.o\n\tcc -o $@ $^\nlib.a: util.o\n\tcp $< $@\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(target(&targets, "app").recipe[0].command, "cc -o app main.o util.o");
+        assert_eq!(target(&targets, "lib.a").output, Some(vec!["lib.a".to_string()]));
+        assert!(parser.variables().values().all(|var| var.origin != Origin::Automatic));
+        assert!(!parser.variables().contains_key("@"));
+    }
+
+    #[test]
+    fn classifies_path_and_dashed_target_names() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.classify_line("build/out/app: main.o"),
+            LineKind::Target {
+                name: "build/out/app".to_string(),
+                prereqs: vec!["main.o".to_string()],
+                double_colon: false,
+            }
+        );
+        assert_eq!(
+            parser.classify_line("install-docs:"),
+            LineKind::Target {
+                name: "install-docs".to_string(),
+                prereqs: Vec::new(),
+                double_colon: false,
+            }
+        );
+
+        let text = "build/out/app: main.o\n\tcc -o $@ $^\n\
+            install-docs:\n\tcp README /usr/share/doc\n";
+        let targets = parse(&mut Parser::new(), text);
+        assert_eq!(
+            target(&targets, "build/out/app").output,
+            Some(vec!["build/out/app".to_string()])
+        );
+        assert!(target(&targets, "install-docs").prerequisites.is_empty());
+    }
+
+    #[test]
+    fn classifies_assignments_with_colons_in_the_value() {
+        let mut parser = Parser::new();
+        assert_eq!(
+            parser.classify_line("VAR := a:b"),
+            LineKind::Variable {
+                name: "VAR".to_string(),
+                value: "a:b".to_string(),
+                flavor: Flavor::Simple,
+            }
+        );
+    }
+
+    #[test]
+    fn evaluates_bsd_conditions() {
+        let text = "DEBUG = 1\nSRCS = main.c util.h\n\
+            .if defined(DEBUG)\nA = debug\n.else\nA = release\n.endif\n\
+            .if empty(SRCS)\nB = none\n.elif empty(SRCS:M*.c)\nB = headers\n\
+            .else\nB = sources\n.endif\n\
+            .if make(install) && !defined(NOMAN)\nC = install\n.endif\n\
+            .ifmake clean\nD = clean\n.endif\n";
+        let run = |goals: &str| {
+            let mut parser = Parser::new().dialect(Dialect::Bsd);
+            parser.set_variable("MAKECMDGOALS", goals, Origin::Default);
+            parse(&mut parser, text);
+            parser
+        };
+
+        let parser = run("install");
+        assert_eq!(value(&parser, "A"), Some("debug"));
+        assert_eq!(value(&parser, "B"), Some("sources"));
+        assert_eq!(value(&parser, "C"), Some("install"));
+        assert_eq!(value(&parser, "D"), None);
+
+        let parser = run("clean");
+        assert_eq!(value(&parser, "C"), None);
+        assert_eq!(value(&parser, "D"), Some("clean"));
+    }
+
+    #[test]
+    fn expands_nested_bsd_for_loops() {
+        let text = ".for dir in src lib\n.for ext in c h\nFILES += ${dir}/x.${ext}\n.endfor\n\
+            ${dir}.a:\n\tar rcs ${.TARGET} ${dir}/*.o\n.endfor\n";
+        let mut parser = Parser::new().dialect(Dialect::Bsd);
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "FILES"), Some("src/x.c src/x.h lib/x.c lib/x.h"));
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["src.a", "lib.a"]);
+    }
+
+    #[test]
+    fn applies_bsd_modifiers_in_references() {
+        let text = "SRCS = src/main.c src/util.c README\n\
+            OBJS := ${SRCS:M*.c:T:S/.c/.o/}\nDOCS := ${SRCS:N*.c:tl}\nUNSET := ${NOPE:Udefault}\n\
+            app: ${OBJS}\n\tcc -o ${.TARGET} ${.ALLSRC}\n";
+        let mut parser = Parser::new().dialect(Dialect::Bsd);
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "OBJS"), Some("main.o util.o"));
+        assert_eq!(value(&parser, "DOCS"), Some("readme"));
+        assert_eq!(value(&parser, "UNSET"), Some("default"));
+        assert_eq!(target(&targets, "app").prerequisites, ["main.o", "util.o"]);
+        assert_eq!(target(&targets, "app").recipe[0].command, "cc -o app main.o util.o");
+    }
+
+    #[test]
+    fn rejects_an_unbalanced_bsd_endif_in_strict_mode() {
+        let text = "A = 1\n.endif\n";
+        let result = parse_strict(&mut Parser::new().dialect(Dialect::Bsd), text);
+        assert_eq!(result.unwrap_err(), "Found endif without a matching conditional");
+
+        let mut parser = Parser::new().dialect(Dialect::Bsd);
+        parse(&mut parser, text);
+        assert_eq!(parser.diagnostics()[0].severity, Severity::Warning);
+    }
+
+    #[test]
+    fn lists_bsd_info_directives_as_diagnostics() {
+        let mut parser = Parser::new().dialect(Dialect::Bsd);
+        parse(&mut parser, ".info building ${.CURDIR:T}\n.warning deprecated\n");
+        let diagnostics = parser.diagnostics();
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].severity, Severity::Info);
+        assert_eq!(diagnostics[1].severity, Severity::Warning);
+        assert_eq!(diagnostics[1].message, "deprecated");
+        assert_eq!(diagnostics[0].line, Some(1));
+    }
+
+    #[test]
+    fn expands_recursive_variables_with_later_values() {
+        let text = "A = $(B)\nB = x\nall: $(A)\n\tcp $(A) out/$(A)\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "A"), Some("$(B)"));
+        assert_eq!(target(&targets, "all").prerequisites, ["x"]);
+        assert_eq!(target(&targets, "all").output, Some(vec!["out/x".to_string()]));
+    }
+
+    #[test]
+    fn reports_self_referencing_variables_once_per_line() {
+        let text = "A = $(A) y\nlib: $(A)\nall:\n\techo $(A) > out.txt\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        // the rule is kept with the reference unexpanded
+        assert_eq!(target(&targets, "lib").prerequisites, ["$(A)"]);
+
+        let lines: Vec<Option<usize>> = parser.diagnostics().iter().map(|d| d.line).collect();
+        assert_eq!(lines, [Some(2), Some(4)]);
+        assert!(parser
+            .diagnostics()
+            .iter()
+            .all(|d| d.message == "Variable A has a recursive dependency: A -> A"));
+    }
+
+    #[test]
+    fn only_expands_include_wildcards_with_filesystem_access() {
+        let dir = write_files(&[
+            ("Makefile", "-include conf/*.mk\nall:\n"),
+            ("conf/a.mk", "a:\n"),
+            ("conf/b.mk", "b:\n"),
+        ]);
+        let run = |parser: Parser| {
+            let mut parser = parser;
+            let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+            targets.iter().map(|t| t.name.clone()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(Parser::new()), ["a", "b", "all"]);
+        assert_eq!(run(Parser::new().filesystem_access(false)), ["all"]);
+    }
+
+    #[test]
+    fn expands_computed_variable_names() {
+        let text = "ARCH = arm\narm_CC = arm-gcc\nx86_CC = gcc\nCC = $($(ARCH)_CC)\n\
+            $(ARCH)_FLAGS := -mthumb\n\
+            name = $(1)_CC\nX86 = $($(call name,x86))\n\
+            app:\n\t$(CC) $(arm_FLAGS) -o app main.c\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "arm_FLAGS"), Some("-mthumb"));
+        assert_eq!(parser.eval_variable("$(CC)", &[]).unwrap(), "arm-gcc");
+        assert_eq!(parser.eval_variable("$(X86)", &[]).unwrap(), "gcc");
+        assert_eq!(target(&targets, "app").recipe[0].command, "arm-gcc -mthumb -o app main.c");
+    }
+
+    #[test]
+    fn limits_how_deeply_references_are_nested() {
+        // each variable refers to the next, ending with a value
+        let chain = |length: usize| {
+            let mut text: String =
+                (0..length).map(|i| format!("V{} = $(V{})\n", i, i + 1)).collect();
+            text.push_str(&format!("V{} = x\nall: $(V0)\n", length));
+            text
+        };
+
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, &chain(50));
+        assert_eq!(target(&targets, "all").prerequisites, ["x"]);
+        assert!(parser.diagnostics().is_empty());
+
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, &chain(150));
+        assert_eq!(target(&targets, "all").prerequisites, ["$(V0)"]);
+        let diagnostics = parser.diagnostics();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].message, "Variable V100 is nested too deeply to expand");
+    }
+
+    #[test]
+    fn parses_nested_conditionals() {
+        let text = "ifdef DEBUG\n  ifeq ($(CC),clang)\nFLAGS = -fsanitize=address\n  else\n\
+            FLAGS = -g\n  endif\nelse\n  ifndef OPT\nFLAGS = -O2\n  endif\nendif\n";
+        let run = |vars: &[(&str, &str)]| {
+            let mut parser = Parser::new();
+            for (name, value) in vars {
+                parser.set_variable(name, value, Origin::CommandLine);
+            }
+            parse(&mut parser, text);
+            value(&parser, "FLAGS").map(|v| v.to_string())
+        };
+
+        assert_eq!(run(&[("DEBUG", "1"), ("CC", "clang")]).as_deref(), Some("-fsanitize=address"));
+        assert_eq!(run(&[("DEBUG", "1"), ("CC", "gcc")]).as_deref(), Some("-g"));
+        assert_eq!(run(&[]).as_deref(), Some("-O2"));
+        // a branch inside a skipped one isn't taken, even when its own condition is true
+        assert_eq!(run(&[("OPT", "1")]), None);
+    }
+
+    #[test]
+    fn takes_the_first_true_branch_of_else_chains() {
+        let text = "ifeq ($(OS),linux)\nEXT = so\nelse ifeq ($(OS),darwin)\nEXT = dylib\n\
+            else ifneq ($(OS),)\nEXT = dll\nelse\nEXT = a\nendif\n";
+        let run = |os: &str| {
+            let mut parser = Parser::new();
+            parser.set_variable("OS", os, Origin::CommandLine);
+            parse(&mut parser, text);
+            value(&parser, "EXT").unwrap().to_string()
+        };
+
+        assert_eq!(run("linux"), "so");
+        assert_eq!(run("darwin"), "dylib");
+        assert_eq!(run("windows"), "dll");
+        assert_eq!(run(""), "a");
+    }
+
+    #[test]
+    fn compares_both_forms_of_ifeq_arguments() {
+        let text = "CC = gcc\nifeq ($(CC), gcc)\nA = 1\nendif\nifeq \"$(CC)\" 'gcc'\nB = 1\nendif\n\
+            ifneq '$(CC)' \"clang\"\nC = 1\nendif\nifeq ($(strip $(CC)),clang)\nD = 1\nendif\n";
+        let mut parser = Parser::new();
+        parse(&mut parser, text);
+        assert_eq!(value(&parser, "A"), Some("1"));
+        assert_eq!(value(&parser, "B"), Some("1"));
+        assert_eq!(value(&parser, "C"), Some("1"));
+        assert_eq!(value(&parser, "D"), None);
+    }
+
+    #[test]
+    fn rejects_unbalanced_conditionals_in_strict_mode() {
+        let missing = parse_strict(&mut Parser::new(), "ifdef DEBUG\nA = 1\n");
+        assert!(missing.unwrap_err().starts_with("Missing endif at the end of "));
+
+        let extra = parse_strict(&mut Parser::new(), "A = 1\nendif\n");
+        assert_eq!(extra.unwrap_err(), "Found endif without a matching conditional");
+
+        let extra_else = parse_strict(&mut Parser::new(), "ifdef A\nelse\nelse\nendif\n");
+        assert_eq!(extra_else.unwrap_err(), "Found else without a matching conditional");
+
+        // otherwise they're reported, and the rest of the makefile is still parsed
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, "endif\nall:\n");
+        assert_eq!(targets.len(), 1);
+        assert_eq!(parser.diagnostics()[0].line, Some(1));
+    }
+
+    #[test]
+    fn keeps_multi_line_define_values() {
+        let text = "define HELP\nUsage:\n  make all\nendef\n\
+            define RULE =\ngen.txt:\n\techo hi > gen.txt\nendef\n\
+            define GREETING :=\nhello $(NAME)\nendef\nNAME = world\n\
+            $(eval $(RULE))\n";
+        let mut parser = Parser::new().allow_eval(true);
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "HELP"), Some("Usage:\n  make all"));
+        assert_eq!(parser.variables()["HELP"].flavor, Flavor::Recursive);
+        // the flavor can be given after the name, like in an assignment
+        assert_eq!(parser.variables()["GREETING"].flavor, Flavor::Simple);
+        assert_eq!(parser.variables()["GREETING"].span.as_ref().unwrap().line_end, 11);
+        // the value of a define can hold a whole rule
+        assert_eq!(target(&targets, "gen.txt").output, Some(vec!["gen.txt".to_string()]));
+    }
+
+    #[test]
+    fn appends_with_the_existing_flavor() {
+        let text = "CFLAGS = -O2\nCFLAGS += $(DEBUG)\nDEBUG = -g\n\
+            B = b\nS := a\nS += $(B)\nB = changed\nE =\nE += e\nN += $(LATER)\nLATER = z\n";
+        let mut parser = Parser::new();
+        parse(&mut parser, text);
+        let vars = parser.variables().clone();
+
+        // a recursive value keeps the appended reference, which gets its later value
+        assert_eq!(vars["CFLAGS"].value, "-O2 $(DEBUG)");
+        assert_eq!(vars["CFLAGS"].flavor, Flavor::Recursive);
+        assert_eq!(parser.eval_variable("$(CFLAGS)", &[]).unwrap(), "-O2 -g");
+        // a simple value has the appended text expanded right away
+        assert_eq!(vars["S"].value, "a b");
+        assert_eq!(vars["S"].flavor, Flavor::Simple);
+        // no space is added to an empty value, and appending to an unset variable defines it
+        assert_eq!(vars["E"].value, "e");
+        assert_eq!(vars["N"].value, "$(LATER)");
+        assert_eq!(vars["N"].flavor, Flavor::Recursive);
+    }
+
+    #[test]
+    fn keeps_shell_assignments_unexpanded_unless_allowed() {
+        let text = "VERSION != echo 1.2\nDIST = app-$(VERSION).tar\nall:\n\ttar cf $(DIST) src\n";
+
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(parser.variables()["VERSION"].value, "echo 1.2");
+        assert_eq!(parser.variables()["VERSION"].flavor, Flavor::Shell);
+        assert_eq!(target(&targets, "all").recipe[0].command, "tar cf app-$(VERSION).tar src");
+
+        let mut parser = Parser::new().allow_shell_assign(true);
+        let targets = parse(&mut parser, text);
+        assert_eq!(parser.variables()["VERSION"].value, "1.2");
+        assert_eq!(target(&targets, "all").recipe[0].command, "tar cf app-1.2.tar src");
+    }
+
+    #[test]
+    fn resolves_conditional_assignments_against_the_environment() {
+        // a name no other test uses, since the environment is shared between them
+        std::env::set_var("MAKEPARSE_TEST_PREFIX", "/opt");
+        let text = "MAKEPARSE_TEST_PREFIX ?= /usr/local\nCC = gcc\nCC ?= cc\nLD ?= ld\n";
+
+        let mut parser = Parser::new();
+        parse(&mut parser, text);
+        assert_eq!(value(&parser, "MAKEPARSE_TEST_PREFIX"), Some("/usr/local"));
+
+        let mut parser = Parser::new().conditional_from_env(true);
+        parse(&mut parser, text);
+        let prefix = &parser.variables()["MAKEPARSE_TEST_PREFIX"];
+        assert_eq!((prefix.value.as_str(), prefix.origin), ("/opt", Origin::Environment));
+        // ?= never replaces a value the makefile already gave
+        assert_eq!(value(&parser, "CC"), Some("gcc"));
+        assert_eq!(value(&parser, "LD"), Some("ld"));
+    }
+
+    #[test]
+    fn applies_export_and_override_directives() {
+        let text = "override MODE = release\nOPT = 1\nexport BIN = /usr/bin\n\
+            CC = gcc\nLD = ld\nexport CC LD\nunexport LD\noverride CFLAGS += -Wall\n";
+        let mut parser = Parser::new();
+        parser.set_variable("MODE", "debug", Origin::CommandLine);
+        parser.set_variable("OPT", "2", Origin::CommandLine);
+        parser.set_variable("CFLAGS", "-O2", Origin::CommandLine);
+        parse(&mut parser, text);
+        let vars = parser.variables();
+
+        // only an override beats a variable given on the command line
+        assert_eq!(vars["MODE"].value, "release");
+        assert_eq!(vars["MODE"].origin_name(), "override");
+        assert_eq!(vars["OPT"].value, "2");
+        assert_eq!(vars["CFLAGS"].value, "-O2 -Wall");
+
+        assert!(vars["BIN"].exported && vars["CC"].exported);
+        assert!(!vars["LD"].exported && !vars["OPT"].exported);
+    }
+
+    #[test]
+    fn lists_pattern_rules_separately_from_targets() {
+        let text = "CC = cc\nall: app\n%.o: %.c | build\n\t$(CC) -c -o $@ $<\n\
+            app: main.o\n\tcc -o app main.o\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["all", "app"]);
+
+        let rules = parser.pattern_rules();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].target, "%.o");
+        assert_eq!(rules[0].prerequisites, ["%.c"]);
+        assert_eq!(rules[0].order_only, ["build"]);
+        assert_eq!(rules[0].output, ["%.o"]);
+    }
+
+    #[test]
+    fn converts_suffix_rules_into_pattern_rules() {
+        let text = ".SUFFIXES: .proto .pb.cc\n.c.o:\n\tcc -c -o $@ $<\n\
+            .proto.pb.cc:\n\tprotoc --cpp_out=. $<\n.foo.bar:\n\tcp $< $@\nall:\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+
+        let rules: Vec<(&str, &str)> = parser
+            .pattern_rules()
+            .iter()
+            .map(|r| (r.target.as_str(), r.prerequisites[0].as_str()))
+            .collect();
+        assert_eq!(rules, [("%.o", "%.c"), ("%.pb.cc", "%.proto")]);
+        assert_eq!(parser.pattern_rules()[0].output, ["%.o"]);
+
+        // a rule with suffixes that aren't known is an ordinary target
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, [".foo.bar", "all"]);
+    }
+
+    #[test]
+    fn expands_static_pattern_rules_for_each_target() {
+        let text = "OBJS = main.o util.o\nall: $(OBJS)\n$(OBJS): %.o: src/%.c | build\n\
+            \tcc -c -o $@ $<\nbad.x: %.o: %.c\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["all", "main.o", "util.o"]);
+
+        let util = target(&targets, "util.o");
+        assert_eq!(util.prerequisites, ["src/util.c"]);
+        assert_eq!(util.order_only, ["build"]);
+        assert_eq!(util.output, Some(vec!["util.o".to_string()]));
+
+        // a target that doesn't match the pattern is left out
+        assert_eq!(
+            parser.diagnostics()[0].message,
+            "Target 'bad.x' doesn't match the target pattern '%.o'"
+        );
+    }
+
+    #[test]
+    fn keeps_each_double_colon_rule_as_its_own_target() {
+        let text = "log:: a.txt\n\tcp a.txt a.log\nlog:: b.txt\n\tcp b.txt b.log\n\
+            obj: a.o\nobj: b.o\n\tld -o obj a.o b.o\n";
+        let targets = parse(&mut Parser::new(), text);
+        let names: Vec<(&str, bool)> =
+            targets.iter().map(|t| (t.name.as_str(), t.double_colon)).collect();
+        assert_eq!(names, [("log", true), ("log", true), ("obj", false)]);
+
+        // each :: rule keeps its own prerequisites and outputs, while : rules are merged
+        assert_eq!(targets[0].prerequisites, ["a.txt"]);
+        assert_eq!(targets[0].output, Some(vec!["a.log".to_string()]));
+        assert_eq!(targets[1].prerequisites, ["b.txt"]);
+        assert_eq!(targets[1].output, Some(vec!["b.log".to_string()]));
+        // the default goal runs every rule for it
+        assert!(targets[0].default && targets[1].default);
+        assert_eq!(targets[2].prerequisites, ["a.o", "b.o"]);
+    }
+
+    #[test]
+    fn gives_each_target_of_a_rule_the_same_prerequisites_and_outputs() {
+        let text = "all: docs\nfoo bar baz: gen.sh\n\tsh gen.sh > out/gen.txt\n";
+        let targets = parse(&mut Parser::new(), text);
+        let names: Vec<&str> = targets.iter().map(|t| t.name.as_str()).collect();
+        assert_eq!(names, ["all", "foo", "bar", "baz"]);
+        for name in ["foo", "bar", "baz"] {
+            let target = target(&targets, name);
+            assert_eq!(target.prerequisites, ["gen.sh"]);
+            assert_eq!(target.output, Some(vec!["out/gen.txt".to_string()]));
+            assert_eq!(target.recipe.len(), 1);
+            assert!(!target.default);
+        }
+    }
+
+    #[test]
+    fn splits_order_only_prerequisites() {
+        let text = "DIRS = build build/obj\napp: main.o util.o | $(DIRS)\n\tcc -o app main.o\n\
+            setup: | build\nplain: a b\n";
+        let targets = parse(&mut Parser::new(), text);
+
+        let app = target(&targets, "app");
+        assert_eq!(app.prerequisites, ["main.o", "util.o"]);
+        assert_eq!(app.order_only, ["build", "build/obj"]);
+        let setup = target(&targets, "setup");
+        assert!(setup.prerequisites.is_empty());
+        assert_eq!(setup.order_only, ["build"]);
+        assert!(target(&targets, "plain").order_only.is_empty());
+    }
+
+    #[test]
+    fn serializes_prerequisites_of_each_target() {
+        let text = "app: main.o util.o | build\n\tcc -o app main.o util.o\nclean:\n";
+        let targets = parse(&mut Parser::new(), text);
+        let json = serde_json::to_value(&targets).unwrap();
+
+        assert_eq!(json[0]["prerequisites"], serde_json::json!(["main.o", "util.o"]));
+        assert_eq!(json[0]["order_only"], serde_json::json!(["build"]));
+        // prerequisites are always listed, but order-only ones only when there are some
+        assert_eq!(json[1]["prerequisites"], serde_json::json!([]));
+        assert!(json[1].get("order_only").is_none());
+    }
+
+    #[test]
+    fn captures_recipe_lines_in_order() {
+        let text = "all: main.o ; echo start\n\tcc -c main.c \\\n\t  -o main.o\n\n# note\n\
+            \tcc -o app main.o\nnext:\n";
+        let targets = parse(&mut Parser::new(), text);
+        let commands: Vec<&str> =
+            target(&targets, "all").recipe.iter().map(|r| r.command.as_str()).collect();
+        // an inline recipe comes first, and continued lines are joined
+        assert_eq!(commands, ["echo start", "cc -c main.c -o main.o", "cc -o app main.o"]);
+        assert!(target(&targets, "next").recipe.is_empty());
+    }
+
+    #[test]
+    fn scopes_target_specific_variables_to_their_target() {
+        let text = "CFLAGS = -O2\nfoo: CFLAGS += -g\nfoo: OUT = build/foo\n\
+            foo: main.c\n\tcc $(CFLAGS) -o $(OUT) main.c\n\
+            bar: main.c\n\tcc $(CFLAGS) -o bar main.c\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+
+        let foo = target(&targets, "foo");
+        assert_eq!(foo.recipe[0].command, "cc -O2 -g -o build/foo main.c");
+        assert_eq!(foo.output, Some(vec!["build/foo".to_string()]));
+        assert_eq!(foo.variables["CFLAGS"].flavor, Flavor::Append);
+        assert_eq!(target(&targets, "bar").recipe[0].command, "cc -O2 -o bar main.c");
+        // the global value is untouched
+        assert_eq!(value(&parser, "CFLAGS"), Some("-O2"));
+        assert_eq!(value(&parser, "OUT"), None);
+    }
+
+    #[test]
+    fn flags_phony_targets() {
+        let text = ".PHONY: all\nall: app\napp:\n\tcc -o app main.c\n\
+            .PHONY: clean install\nclean:\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        let phony: Vec<(&str, bool)> = targets.iter().map(|t| (t.name.as_str(), t.phony)).collect();
+        assert_eq!(phony, [("all", true), ("app", false), ("clean", true)]);
+        // a name declared phony without a rule is only listed as declared
+        assert_eq!(parser.declared_phony(), ["all", "clean", "install"]);
+        assert!(!parser.special_targets().contains_key(".PHONY"));
+    }
+
+    #[test]
+    fn records_other_special_targets() {
+        let text = ".PRECIOUS: app.o\n.SECONDARY: gen.c\n.INTERMEDIATE: gen.c\n.DELETE_ON_ERROR:\n\
+            .NOTPARALLEL:\nall: app.o\napp.o:\n\ttouch app.o\ngen.c:\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+
+        // special targets aren't targets themselves, but mark the targets they list
+        assert!(targets.iter().all(|t| !t.name.starts_with('.')));
+        assert_eq!(target(&targets, "app.o").attributes, ["precious"]);
+        assert_eq!(target(&targets, "gen.c").attributes, ["intermediate", "secondary"]);
+        assert!(target(&targets, "all").attributes.is_empty());
+
+        let special: Vec<&str> = parser.special_targets().keys().map(|k| k.as_str()).collect();
+        assert_eq!(
+            special,
+            [".DELETE_ON_ERROR", ".INTERMEDIATE", ".NOTPARALLEL", ".PRECIOUS", ".SECONDARY"]
+        );
+        assert!(parser.special_targets()[".NOTPARALLEL"].is_empty());
+    }
+
+    #[test]
+    fn honors_the_default_goal_variable() {
+        let run = |text: &str| {
+            let targets = parse(&mut Parser::new(), text);
+            let defaults: Vec<String> =
+                targets.iter().filter(|t| t.default).map(|t| t.name.clone()).collect();
+            defaults
+        };
+
+        assert_eq!(run("all: app\napp:\nclean:\n"), ["all"]);
+        assert_eq!(run("all: app\napp:\nclean:\n.DEFAULT_GOAL := clean\n"), ["clean"]);
+        // a goal that isn't a target leaves the first target as the default
+        assert_eq!(run(".DEFAULT_GOAL = missing\nall:\nclean:\n"), ["all"]);
+        // special and pattern targets are never the default
+        assert_eq!(run(".PHONY: all\n%.o: %.c\nall:\n"), ["all"]);
+    }
+
+    #[test]
+    fn expands_wildcards_against_the_makefile_directory() {
+        let dir = write_files(&[
+            ("Makefile", "SRCS := $(wildcard src/*.c)\nall: $(SRCS) $(wildcard missing/*.c)\n"),
+            ("src/util.c", ""),
+            ("src/main.c", ""),
+            ("src/main.h", ""),
+        ]);
+        let run = |parser: Parser| {
+            let mut parser = parser;
+            let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+            (targets[0].prerequisites.clone(), value(&parser, "SRCS").unwrap().to_string())
+        };
+
+        // matches are sorted and given as written in the pattern
+        let (prereqs, srcs) = run(Parser::new());
+        assert_eq!(prereqs, ["src/main.c", "src/util.c"]);
+        assert_eq!(srcs, "src/main.c src/util.c");
+        let (_, srcs) = run(Parser::new().filesystem_access(false));
+        assert_eq!(srcs, "$(wildcard src/*.c)");
+    }
+
+    #[test]
+    fn only_runs_shell_calls_when_asked() {
+        let text = "REV := $(shell echo abc123)\nall:\n\tcp app dist/app-$(REV)\n";
+
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "REV"), Some("<shell:echo abc123>"));
+        // the marker is kept whole as part of an output
+        let output = "dist/app-<shell:echo abc123>".to_string();
+        assert_eq!(target(&targets, "all").output, Some(vec![output]));
+
+        let mut parser = Parser::new().eval_shell(true);
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "REV"), Some("abc123"));
+        assert_eq!(target(&targets, "all").output, Some(vec!["dist/app-abc123".to_string()]));
+    }
+
+    #[test]
+    fn expands_foreach_once_per_word() {
+        let text = "d = kept\nDIRS = src lib\nOBJS := $(foreach d,$(DIRS),$(d)/x.o $(d)/y.o)\n\
+            EMPTY := $(foreach d,,$(d))\nall: $(OBJS)\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "OBJS"), Some("src/x.o src/y.o lib/x.o lib/y.o"));
+        assert_eq!(value(&parser, "EMPTY"), Some(""));
+        assert_eq!(target(&targets, "all").prerequisites.len(), 4);
+        // the loop variable gets its old value back afterwards
+        assert_eq!(value(&parser, "d"), Some("kept"));
+    }
+
+    #[test]
+    fn expands_only_the_chosen_branch_of_if() {
+        let mut parser = Parser::new();
+        let text = "DEBUG = 1\nSPACES = \nOUT = $(if $(DEBUG),build/debug,build/release)\n";
+        parse(&mut parser, text);
+        assert_eq!(parser.eval_variable("$(OUT)", &[]).unwrap(), "build/debug");
+        // a condition of only whitespace is false, and a missing else is empty
+        assert_eq!(parser.eval_variable("$(if $(SPACES) ,yes,no)", &[]).unwrap(), "no");
+        assert_eq!(parser.eval_variable("$(if ,yes)", &[]).unwrap(), "");
+        assert_eq!(parser.eval_variable("$(if x,a,b,c)", &[]).unwrap(), "a");
+
+        // the branch that isn't taken isn't expanded
+        parser.eval_variable("$(if x,ok,$(error skipped))", &[]).unwrap();
+        assert!(parser.diagnostics().is_empty());
+    }
+
+    #[test]
+    fn defines_variables_passed_to_eval() {
+        let text = "DIR = build\n$(eval OUT := $(DIR)/app)\n$(eval LATE = $$(DIR)/late)\n\
+            all:\n\tcp app $(OUT)\n\t$(eval gen: ; touch gen)\n";
+        let mut parser = Parser::new().allow_eval(true);
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "OUT"), Some("build/app"));
+        // text escaped with `$$` is only expanded when the eval'd line is parsed
+        assert_eq!(value(&parser, "LATE"), Some("$(DIR)/late"));
+        assert_eq!(target(&targets, "all").output, Some(vec!["build/app".to_string()]));
+        // a rule from $(eval) in a recipe would only be defined when the recipe runs
+        assert!(!targets.iter().any(|t| t.name == "gen"));
+    }
+
+    #[test]
+    fn expands_user_defined_functions_with_call() {
+        let text = "objects = $(patsubst %.c,$(2)/%.o,$(1))\n\
+            reverse = $(2) $(1)\nwrap = [$(call reverse,$(1),$(2))] $(1)\n\
+            OBJS := $(call objects,main.c util.c,build)\n";
+        let mut parser = Parser::new();
+        parse(&mut parser, text);
+        assert_eq!(value(&parser, "OBJS"), Some("build/main.o build/util.o"));
+        // nested calls get their own parameters, and the outer ones come back afterwards
+        assert_eq!(parser.eval_variable("$(call wrap,a,b)", &[]).unwrap(), "[b a] a");
+        // parameters that aren't given are empty, and so is calling an undefined function
+        assert_eq!(parser.eval_variable("$(call reverse,a)", &[]).unwrap().trim(), "a");
+        assert_eq!(parser.eval_variable("$(call missing,a)", &[]).unwrap(), "");
+        assert!(!parser.variables().contains_key("1"));
+    }
+
+    #[test]
+    fn describes_variables_with_origin_flavor_and_value() {
+        let text = "CC = $(TOOLCHAIN)gcc\nOPT := -O2\noverride MODE = release\n\
+            WHERE := $(origin CC) $(origin MODE) $(origin NOPE) $(origin GOAL)\n";
+        let mut parser = Parser::new();
+        parser.set_variable("GOAL", "all", Origin::CommandLine);
+        parse(&mut parser, text);
+        assert_eq!(value(&parser, "WHERE"), Some("file override undefined command line"));
+        let flavors = parser.eval_variable("$(flavor CC) $(flavor OPT) $(flavor NOPE)", &[]);
+        assert_eq!(flavors.unwrap(), "recursive simple undefined");
+        // $(value) gives the value without expanding it
+        assert_eq!(parser.eval_variable("$(value CC)", &[]).unwrap(), "$(TOOLCHAIN)gcc");
+    }
+
+    #[test]
+    fn expands_substitution_references() {
+        let text = "SRCS = main.c util.c README\nOBJS := $(SRCS:.c=.o)\n\
+            DEPS := $(SRCS:%.c=build/%.d)\napp: $(OBJS:README=)\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        // only the ends of words are replaced
+        assert_eq!(value(&parser, "OBJS"), Some("main.o util.o README"));
+        assert_eq!(value(&parser, "DEPS"), Some("build/main.d build/util.d README"));
+        assert_eq!(target(&targets, "app").prerequisites, ["main.o", "util.o"]);
+    }
+
+    #[test]
+    fn resolves_prerequisites_through_vpath() {
+        let dir = write_files(&[
+            ("Makefile", "vpath %.c src\nvpath %.h include\nVPATH = lib:other\n\
+                app: main.c util.c config.h local.c\n"),
+            ("src/main.c", ""),
+            ("lib/util.c", ""),
+            ("include/config.h", ""),
+            ("local.c", ""),
+        ]);
+        let mut parser = Parser::new();
+        let mut targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+
+        let search_paths = parser.search_paths();
+        assert_eq!(search_paths[0].pattern.as_deref(), Some("%.c"));
+        assert_eq!(search_paths[2].pattern, None);
+        assert_eq!(search_paths[2].directories, ["lib", "other"]);
+
+        crate::analysis::resolve_search_paths(&mut targets, dir.path(), &search_paths);
+        // vpath directives are searched before VPATH, and files next to the makefile stay
+        assert_eq!(
+            target(&targets, "app").prerequisites,
+            ["src/main.c", "lib/util.c", "include/config.h", "local.c"]
+        );
+    }
+
+    #[test]
+    fn expands_escaped_prerequisites_a_second_time() {
+        let text = ".SECONDEXPANSION:\nSRC_DIR = early\n\
+            main.o util.o: $$(SRC_DIR)/$$(@:.o=.c) $(SRC_DIR)/common.h $$$$literal\n\
+            \tcc -c -o $@ $<\nSRC_DIR = src\n";
+        let targets = parse(&mut Parser::new(), text);
+        // escaped references get the final values, and the automatic variables of each target
+        assert_eq!(
+            target(&targets, "util.o").prerequisites,
+            ["src/util.c", "early/common.h", "$literal"]
+        );
+        assert_eq!(target(&targets, "main.o").prerequisites[0], "src/main.c");
+
+        // without .SECONDEXPANSION, `$$` is only unescaped
+        let targets = parse(&mut Parser::new(), "app: $$(SRC) a\n");
+        assert_eq!(target(&targets, "app").prerequisites, ["$(SRC)", "a"]);
+    }
+
+    #[test]
+    fn tracks_recipe_line_modifiers() {
+        let text = "all:\n\t@echo building\n\t-rm -f old.o\n\t+$(MAKE) -C sub\n\t@- cp a b\n\
+            \tcc -o app a.c\n";
+        let mut parser = Parser::new();
+        parser.set_variable("MAKE", "make", Origin::Default);
+        let targets = parse(&mut parser, text);
+        let recipe: Vec<(&str, bool, bool, bool)> = target(&targets, "all")
+            .recipe
+            .iter()
+            .map(|r| (r.command.as_str(), r.silent, r.ignore_errors, r.always_run))
+            .collect();
+        assert_eq!(
+            recipe,
+            [
+                ("echo building", true, false, false),
+                ("rm -f old.o", false, true, false),
+                ("make -C sub", false, false, true),
+                ("cp a b", true, true, false),
+                ("cc -o app a.c", false, false, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn lists_every_target_of_a_grouped_rule_as_an_output() {
+        let text = "all: parser.c\nparser.c parser.h &: parser.y\n\tbison -d -o parser.c parser.y\n\
+            a b: c\n";
+        let targets = parse(&mut Parser::new(), text);
+        for name in ["parser.c", "parser.h"] {
+            let target = target(&targets, name);
+            assert_eq!(target.group, ["parser.c", "parser.h"]);
+            assert_eq!(target.prerequisites, ["parser.y"]);
+            assert_eq!(target.output, Some(vec!["parser.c".to_string(), "parser.h".to_string()]));
+        }
+        // an ordinary rule with several targets isn't a group
+        assert!(target(&targets, "b").group.is_empty());
+    }
+
+    #[test]
+    fn reads_recipes_with_a_custom_prefix() {
+        let text = ".RECIPEPREFIX = >\nall:\n> cp a out/a\nlater:\n> cp b out/b\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(target(&targets, "all").output, Some(vec!["out/a".to_string()]));
+        assert_eq!(target(&targets, "later").recipe[0].command, "cp b out/b");
+
+        // the option sets the prefix until the makefile sets its own
+        let text = "all:\n+cp a out/a\n";
+        let targets = parse(&mut Parser::new().recipe_prefix('+'), text);
+        assert_eq!(target(&targets, "all").recipe[0].command, "cp a out/a");
+    }
+
+    #[test]
+    fn strips_trailing_comments_outside_of_quotes() {
+        let text = "URL = http://x/#frag # comment\nMSG := \"a # b\" # trailing\nESC = a\\#b\n\
+            all: dep # comment\n\techo \"# not a comment\" > out.txt # shell comment\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "URL"), Some("http://x/"));
+        assert_eq!(value(&parser, "MSG"), Some("\"a # b\""));
+        assert_eq!(value(&parser, "ESC"), Some("a#b"));
+
+        let all = target(&targets, "all");
+        assert_eq!(all.prerequisites, ["dep"]);
+        assert_eq!(all.recipe[0].command, "echo \"# not a comment\" > out.txt");
+        assert_eq!(all.output, Some(vec!["out.txt".to_string()]));
+    }
+
+    #[test]
+    fn keeps_escaped_dollars_in_recipes() {
+        let text = "PREFIX = /usr\ninstall:\n\tfor f in a b; do cp $$f $(PREFIX)/bin/$$f; done\n\
+            \tcp app $$HOME/bin/app\n\techo $$$$ > pid.txt\n";
+        let targets = parse(&mut Parser::new(), text);
+        let install = target(&targets, "install");
+        assert_eq!(install.recipe[0].command, "for f in a b; do cp $$f /usr/bin/$$f; done");
+        assert_eq!(install.recipe[2].command, "echo $$$$ > pid.txt");
+        // outputs built from shell variables are only templates for the real paths
+        assert_eq!(install.templated_outputs, ["/usr/bin/$$f", "$$HOME/bin/app"]);
+        assert_eq!(install.output, Some(vec!["pid.txt".to_string()]));
+    }
+
+    #[test]
+    fn records_spans_of_rules_and_variables() {
+        let dir = write_files(&[
+            ("Makefile", "include common.mk\nexport CC = gcc\n\napp: main.o\n\tcc -o app \\\n\
+                \t  main.o\n\napp: util.o\n"),
+            ("common.mk", "\nOPT := -O2\n"),
+        ]);
+        let mut parser = Parser::new();
+        let targets = parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+        let spans: Vec<(usize, usize, usize)> = target(&targets, "app")
+            .spans
+            .iter()
+            .map(|s| (s.line_start, s.line_end, s.column))
+            .collect();
+        // a rule spans its recipe, including continued lines, and each rule has its own span
+        assert_eq!(spans, [(4, 6, 1), (8, 8, 1)]);
+
+        let span = |name: &str| parser.variables()[name].span.clone().unwrap();
+        assert_eq!((span("CC").line_start, span("CC").column), (2, 8));
+        assert!(span("OPT").file.ends_with("common.mk"));
+        assert_eq!((span("OPT").line_start, span("OPT").column), (2, 1));
+    }
+
+    #[test]
+    fn describes_targets_with_doc_comments() {
+        let text = "# Build the app\n# with optimizations\nbuild:\n\tcc -o app main.c\n\n\
+            test: build ## Run the tests\n\n# Not a description\n\nclean:\n\n\
+            # Install it\ninstall:\ninstall: ## Copy the app\n";
+        let targets = parse(&mut Parser::new(), text);
+        let description = |name| target(&targets, name).description.as_deref();
+        // consecutive comment lines are joined
+        assert_eq!(description("build"), Some("Build the app with optimizations"));
+        assert_eq!(description("test"), Some("Run the tests"));
+        // a blank line separates comments from the rule after them
+        assert_eq!(description("clean"), None);
+        // the first rule with a description gives it
+        assert_eq!(description("install"), Some("Install it"));
+    }
+
+    #[test]
+    fn undefines_variables() {
+        let text = "DEBUG = 1\nundefine DEBUG\nifdef DEBUG\nA = 1\nendif\n\
+            LEVEL = 2\nundefine LEVEL\noverride undefine MODE\n";
+        let mut parser = Parser::new().track_assignments(true);
+        parser.set_variable("LEVEL", "3", Origin::CommandLine);
+        parser.set_variable("MODE", "fast", Origin::CommandLine);
+        parse(&mut parser, text);
+        assert_eq!(value(&parser, "DEBUG"), None);
+        assert_eq!(value(&parser, "A"), None);
+        // a command line variable is only removed with override
+        assert_eq!(value(&parser, "LEVEL"), Some("3"));
+        assert_eq!(value(&parser, "MODE"), None);
+
+        let debug = &parser.assignments()["DEBUG"];
+        assert_eq!(debug[1].operator, "undefine");
+        assert_eq!(debug[1].line, 2);
+    }
+
+    #[test]
+    fn reports_error_warning_and_info_calls() {
+        let text = "ifndef TOOLCHAIN\n$(error TOOLCHAIN not set)\nendif\n\
+            ifdef NEVER\n$(error not reached)\nendif\n\
+            $(warning deprecated $(UNSET)option)\n$(info building)\n\
+            all:\n\t$(error only when run)\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(targets.len(), 1);
+        let found: Vec<(Severity, &str, Option<usize>)> = parser
+            .diagnostics()
+            .iter()
+            .map(|d| (d.severity, d.message.as_str(), d.line))
+            .collect();
+        // calls in an inactive branch or a recipe aren't expanded
+        assert_eq!(
+            found,
+            [
+                (Severity::Error, "TOOLCHAIN not set", Some(2)),
+                (Severity::Warning, "deprecated option", Some(7)),
+                (Severity::Info, "building", Some(8)),
+            ]
+        );
+
+        // strict mode only stops at an error call when asked to
+        assert!(parse_strict(&mut Parser::new(), text).is_ok());
+        let result = parse_strict(&mut Parser::new().fatal_error_calls(true), text);
+        assert_eq!(result.unwrap_err(), "$(error) called: TOOLCHAIN not set");
+    }
+
+    #[test]
+    fn defines_make_builtin_variables() {
+        let dir = write_files(&[
+            ("Makefile", "BEFORE := $(.DEFAULT_GOAL)\nall:\nAFTER := $(.DEFAULT_GOAL)\n\
+                include sub.mk\nLIST := $(MAKEFILE_LIST)\nRECURSE := $(MAKE) -C lib\n"),
+            ("sub.mk", "IN_SUB := $(words $(MAKEFILE_LIST))\n"),
+        ]);
+        let mut parser = Parser::new();
+        parser.set_variable("MAKECMDGOALS", "all install", Origin::Default);
+        parser.parse_file(dir.path().join("Makefile"), false).unwrap();
+
+        let curdir = Path::new(value(&parser, "CURDIR").unwrap());
+        assert_eq!(curdir, lexical_normalize(dir.path()));
+        assert_eq!(value(&parser, "RECURSE"), Some("make -C lib"));
+        // a value given before parsing is kept
+        assert_eq!(value(&parser, "MAKECMDGOALS"), Some("all install"));
+        // the first target is the default goal once it's been read
+        assert_eq!(value(&parser, "BEFORE"), Some(""));
+        assert_eq!(value(&parser, "AFTER"), Some("all"));
+
+        assert_eq!(value(&parser, "IN_SUB"), Some("2"));
+        let list: Vec<&str> = value(&parser, "LIST").unwrap().split_whitespace().collect();
+        assert_eq!(list.len(), 2);
+        assert!(list[0].ends_with("Makefile") && list[1].ends_with("sub.mk"));
+    }
+
+    #[test]
+    fn applies_pattern_specific_variables_to_matching_targets() {
+        let text = "CFLAGS = -O2\n%.o: CFLAGS += -fPIC\nlib/%.o: CFLAGS += -Ilib\n\
+            main.o: main.c\n\tcc $(CFLAGS) -c main.c\n\
+            lib/util.o: CFLAGS = -g\nlib/util.o: lib/util.c\n\tcc $(CFLAGS) -c lib/util.c\n\
+            lib/io.o: lib/io.c\n\tcc $(CFLAGS) -c lib/io.c\n\
+            app: main.o\n\tcc $(CFLAGS) -o app main.o\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        // they're not targets of their own
+        assert!(targets.iter().all(|t| !t.name.contains('%')));
+        assert_eq!(parser.pattern_variables()["%.o"]["CFLAGS"].value, "-fPIC");
+
+        assert_eq!(target(&targets, "main.o").recipe[0].command, "cc -O2 -fPIC -c main.c");
+        // the more specific pattern comes last
+        let io = target(&targets, "lib/io.o");
+        assert_eq!(io.recipe[0].command, "cc -O2 -fPIC -Ilib -c lib/io.c");
+        // a target's own variables take precedence
+        assert_eq!(target(&targets, "lib/util.o").recipe[0].command, "cc -g -c lib/util.c");
+        assert_eq!(target(&targets, "app").recipe[0].command, "cc -O2 -o app main.o");
+    }
+
+    #[test]
+    fn substitutes_exports_into_outputs_on_the_same_line() {
+        let text = "install:\n\texport DESTDIR=/tmp/pkg && cp app $$DESTDIR/bin/app\n\
+            \texport PREFIX=/opt LIBDIR=lib; cp lib.a $${PREFIX}/$$LIBDIR/lib.a\n\
+            \tcp app.1 $$DESTDIR/man/app.1\n";
+        let targets = parse(&mut Parser::new(), text);
+        let install = target(&targets, "install");
+        let exports: Vec<(&str, &str)> =
+            install.recipe_exports.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        assert_eq!(exports, [("DESTDIR", "/tmp/pkg"), ("LIBDIR", "lib"), ("PREFIX", "/opt")]);
+        assert_eq!(
+            install.output,
+            Some(vec!["/tmp/pkg/bin/app".to_string(), "/opt/lib/lib.a".to_string()])
+        );
+        // each line is run by its own shell, so the export doesn't reach the next one
+        assert_eq!(install.templated_outputs, ["$$DESTDIR/man/app.1"]);
+    }
+
+    #[test]
+    fn follows_cd_commands_and_brace_groups() {
+        let text = "app:\n\tcd build && cc -o app main.c\n\tcp app dist/app\n\
+            list:\n\t{ echo a; echo b; } > list.txt\n\t{ cat a; } >> log.txt\n";
+        let targets = parse(&mut Parser::new(), text);
+        // a cd only applies to the rest of its line
+        assert_eq!(
+            target(&targets, "app").output,
+            Some(vec!["build/app".to_string(), "dist/app".to_string()])
+        );
+        // the redirect of a group is its output, rather than one of its commands'
+        assert_eq!(
+            target(&targets, "list").output,
+            Some(vec!["list.txt".to_string(), "log.txt".to_string()])
+        );
+    }
+
+    #[test]
+    fn carries_shell_state_across_lines_in_one_shell() {
+        let text = ".ONESHELL:\nsetup:\n\tcd out\n\tcc -o app main.c\n\
+            \texport DIR=gen\n\tcp x $$DIR/x\nnext:\n\tcp y y.bak\n";
+        let targets = parse(&mut Parser::new(), text);
+        let setup = target(&targets, "setup");
+        assert_eq!(setup.output, Some(vec!["out/app".to_string(), "out/gen/x".to_string()]));
+        assert_eq!(setup.recipe_exports["DIR"], "gen");
+        // the shell doesn't carry over into the next recipe
+        assert_eq!(target(&targets, "next").output, Some(vec!["y.bak".to_string()]));
+    }
+
+    #[test]
+    fn expands_prerequisites_defined_after_the_rule() {
+        let text = "all: $(OBJS) | $(DIRS)\n\tcc -o app $(OBJS)\nOBJS := a.o b.o\nDIRS = out\n";
+        let targets = parse(&mut Parser::new(), text);
+        let all = target(&targets, "all");
+        assert_eq!(all.prerequisites, ["a.o", "b.o"]);
+        assert_eq!(all.order_only, ["out"]);
+        assert_eq!(all.recipe[0].command, "cc -o app a.o b.o");
+    }
+
+    #[test]
+    fn expands_simple_variables_when_they_are_assigned() {
+        let text = "X = 1\nSIMPLE := $(X)\nRECURSIVE = $(X)\nX = 2\n\
+            all:\n\techo $(SIMPLE) $(RECURSIVE) > out.txt\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(value(&parser, "SIMPLE"), Some("1"));
+        assert_eq!(value(&parser, "RECURSIVE"), Some("$(X)"));
+        // recipes are expanded with the final values
+        assert_eq!(target(&targets, "all").recipe[0].command, "echo 1 2 > out.txt");
+    }
+
+    #[test]
+    fn expands_recipes_with_target_variables_set_after_the_rule() {
+        let text = "CFLAGS = -Wall\napp: main.o\n\tcc $(CFLAGS) -o $(OUT) main.o\n\
+            app: OUT = build/app\napp: CFLAGS += $(OPT)\nOPT = -O2\n";
+        let targets = parse(&mut Parser::new(), text);
+        let app = target(&targets, "app");
+        assert_eq!(app.recipe[0].command, "cc -Wall -O2 -o build/app main.o");
+        assert_eq!(app.output, Some(vec!["build/app".to_string()]));
+    }
+
+    #[test]
+    fn parses_makefiles_with_a_byte_order_mark_and_crlf_endings() {
+        let text = "\u{feff}app: main.o\r\n\tcc -o app main.o\r\nCC = gcc\r\n";
+        let mut parser = Parser::new();
+        let targets = parse(&mut parser, text);
+        assert_eq!(targets[0].name, "app");
+        assert_eq!(targets[0].prerequisites, ["main.o"]);
+        assert_eq!(targets[0].recipe[0].command, "cc -o app main.o");
+        assert_eq!(value(&parser, "CC"), Some("gcc"));
+    }
+
+    #[test]
+    fn replaces_invalid_utf8_and_keeps_parsing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("Makefile");
+        fs::write(&path, b"NAME = caf\xe9\nall: app\n\tcp app out/caf\xe9\nclean:\n").unwrap();
+        let mut parser = Parser::new();
+        let targets = parser.parse_file(&path, false).unwrap();
+        assert_eq!(value(&parser, "NAME"), Some("caf\u{fffd}"));
+        assert_eq!(targets.len(), 2);
+        assert_eq!(target(&targets, "all").output, Some(vec!["out/caf\u{fffd}".to_string()]));
+
+        let lines: Vec<(Severity, Option<usize>)> =
+            parser.diagnostics().iter().map(|d| (d.severity, d.line)).collect();
+        assert_eq!(lines, [(Severity::Warning, Some(1)), (Severity::Warning, Some(3))]);
+    }
+
+    #[test]
+    fn only_builds_kbuild_objects_of_set_options() {
+        let text = "obj-y += core.o\nobj-$(CONFIG_NET) += net.o\nobj-$(CONFIG_USB) += usb.o\n";
+        let run = |options: &[&str]| {
+            let mut parser = Parser::new().kbuild(true);
+            for option in options {
+                parser.set_variable(option, "y", Origin::CommandLine);
+            }
+            let targets = parse(&mut parser, text);
+            let archive = targets.iter().find(|t| t.name.ends_with("/built-in.a")).unwrap();
+            let names = archive.prerequisites.iter().map(|p| p.rsplit('/').next().unwrap());
+            names.map(String::from).collect::<Vec<String>>()
+        };
+        assert_eq!(run(&[]), ["core.o"]);
+        assert_eq!(run(&["CONFIG_NET"]), ["core.o", "net.o"]);
+    }
+
+    #[test]
+    fn finds_builtin_rules_turned_off_by_makeflags() {
+        let mut parser = Parser::new();
+        parse(&mut parser, "all:\n");
+        assert!(!parser.builtin_rules_disabled());
+
+        let mut parser = Parser::new();
+        parse(&mut parser, "MAKEFLAGS += -r\nall:\n");
+        assert!(parser.builtin_rules_disabled());
+
+        let mut parser = Parser::new();
+        parser.apply_makeflags("-rk -- CC=clang");
+        parse(&mut parser, "all:\n");
+        assert!(parser.builtin_rules_disabled());
+    }
+
+    #[test]
+    fn parses_nmake_makefiles() {
+        let text = "SRCS = main.c util.c\nOBJS = $(SRCS:.c=.obj)\n\
+            !IFDEF DEBUG\nMODE = debug\n!ELSE\nMODE = release\n!ENDIF\n\
+            app.exe: $(OBJS)\n    link /OUT:$@ $**\n    echo $** > $(@B).rsp\n\
+            {src}.c{obj}.obj:\n\tcl /c $<\n{}.cpp.obj:\n\tcl /c $<\n";
+        let run = |debug: bool| {
+            let mut parser = Parser::new().dialect(Dialect::Nmake);
+            if debug {
+                parser.set_variable("DEBUG", "1", Origin::CommandLine);
+            }
+            let targets = parse(&mut parser, text);
+            (parser, targets)
+        };
+
+        let (parser, targets) = run(false);
+        assert_eq!(value(&parser, "MODE"), Some("release"));
+        assert_eq!(run(true).0.variables()["MODE"].value, "debug");
+
+        let app = target(&targets, "app.exe");
+        assert_eq!(app.prerequisites, ["main.obj", "util.obj"]);
+        assert_eq!(app.recipe[1].command, "echo main.obj util.obj > app.rsp");
+        assert_eq!(app.output, Some(vec!["app.rsp".to_string()]));
+
+        // inference rules are pattern rules, in the directories their paths give
+        assert_eq!(targets.len(), 1);
+        let rules: Vec<(&str, &str)> = parser
+            .pattern_rules()
+            .iter()
+            .map(|r| (r.target.as_str(), r.prerequisites[0].as_str()))
+            .collect();
+        assert_eq!(rules, [("obj/%.obj", "src/%.c"), ("%.obj", "%.cpp")]);
     }
 }
@@ -0,0 +1,166 @@
+//!
+//! Evaluates simple path queries against the analysis output
+//!
+
+use serde_json::Value;
+
+/// Select part of a JSON value using a path like `targets[name=build].output`.
+///
+/// A path is a list of `.`-separated field names, each optionally followed by selectors:
+/// * `[N]` picks the Nth element of an array
+/// * `[key=value]` picks the first element of an array whose `key` field equals `value`
+///
+/// Accessing a field on an array gets that field from every element.
+pub fn query(value: &Value, expr: &str) -> Result<Value, String> {
+    let mut current = value.clone();
+
+    for segment in split_segments(expr)? {
+        let (field, selectors) = match segment.find('[') {
+            Some(idx) => (&segment[..idx], &segment[idx..]),
+            None => (segment, ""),
+        };
+
+        if !field.is_empty() {
+            current = select_field(&current, field)?;
+        }
+
+        for selector in selectors.split_terminator(']') {
+            let selector = match selector.strip_prefix('[') {
+                Some(s) => s,
+                None => return Err(format!("Invalid selector '{}' in query", selector)),
+            };
+            current = select_element(&current, selector)?;
+        }
+    }
+
+    Ok(current)
+}
+
+/// Split a query on the dots that aren't inside a selector
+fn split_segments(expr: &str) -> Result<Vec<&str>, String> {
+    let mut segments = Vec::new();
+    let mut in_selector = false;
+    let mut start = 0;
+
+    for (i, c) in expr.char_indices() {
+        match c {
+            '[' if !in_selector => in_selector = true,
+            ']' if in_selector => in_selector = false,
+            '.' if !in_selector => {
+                segments.push(&expr[start..i]);
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    segments.push(&expr[start..]);
+
+    if in_selector {
+        return Err(format!("Unclosed selector in query '{}'", expr));
+    }
+    if segments.iter().any(|s| s.is_empty()) {
+        return Err(format!("Empty field name in query '{}'", expr));
+    }
+
+    Ok(segments)
+}
+
+/// Get a field from an object, or from each object in an array
+fn select_field(value: &Value, field: &str) -> Result<Value, String> {
+    match value {
+        Value::Object(map) => match map.get(field) {
+            Some(v) => Ok(v.clone()),
+            None => Err(format!("No field '{}'", field)),
+        },
+        Value::Array(items) => items
+            .iter()
+            .map(|item| select_field(item, field))
+            .collect::<Result<Vec<Value>, String>>()
+            .map(Value::Array),
+        _ => Err(format!("Can't get field '{}' from a {}", field, type_name(value))),
+    }
+}
+
+/// Pick a single element from an array, by index or by matching a field's value
+fn select_element(value: &Value, selector: &str) -> Result<Value, String> {
+    let items = match value {
+        Value::Array(items) => items,
+        _ => return Err(format!("Can't select [{}] from a {}", selector, type_name(value))),
+    };
+
+    if let Ok(idx) = selector.parse::<usize>() {
+        return match items.get(idx) {
+            Some(v) => Ok(v.clone()),
+            None => Err(format!("Index {} is out of range", idx)),
+        };
+    }
+
+    let (key, expected) = match selector.split_once('=') {
+        Some(kv) => kv,
+        None => return Err(format!("Invalid selector [{}]", selector)),
+    };
+
+    items
+        .iter()
+        .find(|item| match item.get(key) {
+            // compare strings without their JSON quotes
+            Some(Value::String(s)) => s == expected,
+            // anything else is compared as JSON, like `default=true`
+            Some(v) => serde_json::from_str::<Value>(expected).ok().as_ref() == Some(v),
+            None => false,
+        })
+        .cloned()
+        .ok_or_else(|| format!("No element with {} = '{}'", key, expected))
+}
+
+/// Describe a JSON value's type for error messages
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde_json::json;
+
+    fn analysis() -> Value {
+        json!({
+            "targets": [
+                { "name": "all", "default": true, "output": null },
+                { "name": "build", "default": false, "output": ["bin/app", "bin/app.map"] },
+            ]
+        })
+    }
+
+    #[test]
+    fn selects_a_target_by_name() {
+        let result = query(&analysis(), "targets[name=build].output").unwrap();
+        assert_eq!(result, json!(["bin/app", "bin/app.map"]));
+
+        let result = query(&analysis(), "targets[default=true].name").unwrap();
+        assert_eq!(result, json!("all"));
+    }
+
+    #[test]
+    fn selects_by_index_and_from_every_element() {
+        assert_eq!(query(&analysis(), "targets[1].output[0]").unwrap(), json!("bin/app"));
+        assert_eq!(query(&analysis(), "targets.name").unwrap(), json!(["all", "build"]));
+    }
+
+    #[test]
+    fn reports_invalid_queries() {
+        let error = query(&analysis(), "targets[name=test]").unwrap_err();
+        assert_eq!(error, "No element with name = 'test'");
+        assert_eq!(query(&analysis(), "targets[0].missing").unwrap_err(), "No field 'missing'");
+        assert!(query(&analysis(), "targets[0").is_err());
+        assert!(query(&analysis(), "targets..name").is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//!
+//! Renders targets in human-readable formats
+//!
+
+use terminal_size::{terminal_size, Width};
+
+use crate::types::Target;
+
+/// Width used for table output when the terminal size can't be determined
+pub const DEFAULT_WIDTH: usize = 80;
+
+// separator placed between the table columns
+const SEPARATOR: &str = " | ";
+
+/// Get the width of the terminal, falling back to `DEFAULT_WIDTH` when stdout isn't a terminal
+pub fn terminal_width() -> usize {
+    match terminal_size() {
+        Some((Width(w), _)) => w as usize,
+        None => DEFAULT_WIDTH,
+    }
+}
+
+/// Render targets as a table of names and outputs, with one output per row.
+/// No line in the result will be longer than `width` characters; long names and
+/// paths are shortened to fit.
+pub fn render_table(targets: &[Target], width: usize) -> String {
+    // the default target is marked with a trailing '*'
+    let names: Vec<String> = targets
+        .iter()
+        .map(|t| if t.default { format!("{}*", t.name) } else { t.name.clone() })
+        .collect();
+
+    // the name column gets at most a third of the table, the outputs get the rest
+    let name_width = names
+        .iter()
+        .map(|n| n.chars().count())
+        .chain(std::iter::once("TARGET".len()))
+        .max()
+        .unwrap_or(0)
+        .min(width / 3)
+        .max(1);
+    let output_width = width.saturating_sub(name_width + SEPARATOR.len()).max(1);
+
+    let mut lines = vec![row("TARGET", "OUTPUT", name_width, output_width)];
+    lines.push(fit(&"-".repeat(width), width));
+
+    for (target, name) in targets.iter().zip(names.iter()) {
+        match &target.output {
+            Some(outputs) if !outputs.is_empty() => {
+                for (i, output) in outputs.iter().enumerate() {
+                    // only label the first row for each target
+                    let label = if i == 0 { name.as_str() } else { "" };
+                    lines.push(row(label, output, name_width, output_width));
+                }
+            }
+            _ => lines.push(row(name, "-", name_width, output_width)),
+        }
+    }
+
+    if targets.iter().any(|t| t.default) {
+        lines.push(fit("* default target", width));
+    }
+
+    lines
+        .into_iter()
+        .map(|line| fit(&line, width))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Format a single table row, padding the name column to a fixed width
+fn row(name: &str, output: &str, name_width: usize, output_width: usize) -> String {
+    format!(
+        "{:<width$}{}{}",
+        fit(name, name_width),
+        SEPARATOR,
+        fit(output, output_width),
+        width = name_width
+    )
+    .trim_end()
+    .to_string()
+}
+
+/// Shorten a string to at most `width` characters, replacing the start of it with "..."
+/// so the more specific end of a path stays visible
+fn fit(text: &str, width: usize) -> String {
+    let len = text.chars().count();
+    if len <= width {
+        return text.to_string();
+    }
+
+    if width <= 3 {
+        return text.chars().take(width).collect();
+    }
+
+    let tail: String = text.chars().skip(len - (width - 3)).collect();
+    format!("...{}", tail)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fits_lines_to_the_width() {
+        let mut app = Target::new("a-target-with-a-rather-long-name".to_string());
+        app.default = true;
+        app.output = Some(vec![
+            "build/release/x86_64-unknown-linux-gnu/bin/a-target-with-a-rather-long-name"
+                .to_string(),
+            "app.map".to_string(),
+        ]);
+        let clean = Target::new("clean".to_string());
+
+        let table = render_table(&[app, clean], 40);
+        assert!(table.lines().all(|line| line.chars().count() <= 40), "{}", table);
+        assert!(table.contains("app.map"));
+        assert!(table.contains("-name"));
+    }
+
+    #[test]
+    fn shortens_the_start_of_long_text() {
+        assert_eq!(fit("build/obj/main.o", 10), ".../main.o");
+        assert_eq!(fit("main.o", 10), "main.o");
+    }
+}
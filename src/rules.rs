@@ -0,0 +1,124 @@
+//!
+//! Custom output detection rules and variables, loaded from a JSON definitions file
+//!
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// The contents of a rules file
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RulesFile {
+    // extra ways of finding outputs in recipes
+    #[serde(default)]
+    pub outputs: Vec<OutputRule>,
+    // variables defined before parsing, like a toolchain's implicit variables
+    #[serde(default)]
+    pub variables: BTreeMap<String, String>,
+}
+
+/// A way of finding the output of a particular tool. The output is either the path given
+/// after `flag`, or the `path` group of `pattern` matched against the rest of the command.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputRule {
+    // name of the command the rule applies to
+    pub tool: String,
+    // flag that's followed by the output path, like `--cpp_out`
+    #[serde(default)]
+    pub flag: Option<String>,
+    // regex with a `path` capture group, matched against the arguments after the tool
+    #[serde(default)]
+    pub pattern: Option<String>,
+}
+
+impl RulesFile {
+    /// Read a rules file
+    pub fn load<P: AsRef<Path>>(filepath: P) -> Result<Self, String> {
+        let filepath = filepath.as_ref();
+
+        let contents = std::fs::read_to_string(filepath)
+            .map_err(|e| format!("Couldn't read {}: {}", filepath.display(), e))?;
+
+        serde_json::from_str(&contents)
+            .map_err(|e| format!("Invalid rules file {}: {}", filepath.display(), e))
+    }
+}
+
+impl OutputRule {
+    /// Build the regex used to find this rule's outputs in a recipe line, in the same form as
+    /// the parser's built-in output regexes
+    pub fn to_regex(&self) -> Result<Regex, String> {
+        let args = match (&self.flag, &self.pattern) {
+            (_, Some(pattern)) => pattern.clone(),
+            // the path may follow the flag directly, after an `=`, or as the next argument
+            (Some(flag), None) => format!(r"\s{}(=|\s*)(?P<path>[^\s;&|]+)", regex::escape(flag)),
+            (None, None) => {
+                return Err(format!("Output rule for '{}' needs a flag or a pattern", self.tool))
+            }
+        };
+
+        let re = Regex::new(&format!(
+            r"( {{4}}|\t)+[^\n\r#]*\b{}\b[^\n\r#;&|]*?{}",
+            regex::escape(&self.tool),
+            args
+        ))
+        .map_err(|e| format!("Invalid output rule for '{}': {}", self.tool, e))?;
+
+        if !re.capture_names().any(|name| name == Some("path")) {
+            return Err(format!("Output rule for '{}' has no 'path' group", self.tool));
+        }
+        Ok(re)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(flag: Option<&str>, pattern: Option<&str>) -> OutputRule {
+        OutputRule {
+            tool: "protoc".to_string(),
+            flag: flag.map(String::from),
+            pattern: pattern.map(String::from),
+        }
+    }
+
+    #[test]
+    fn finds_the_path_after_a_flag() {
+        let re = rule(Some("--cpp_out"), None).to_regex().unwrap();
+        for line in ["\tprotoc --cpp_out=gen api.proto", "\tprotoc -I. --cpp_out gen api.proto"] {
+            assert_eq!(&re.captures(line).unwrap()["path"], "gen");
+        }
+        assert!(!re.is_match("\tgcc --cpp_out=gen api.c"));
+    }
+
+    #[test]
+    fn rejects_rules_without_a_path() {
+        assert!(rule(None, None).to_regex().is_err());
+        assert!(rule(None, Some(r"\s(\S+\.pb)")).to_regex().is_err());
+        assert!(rule(None, Some(r"\s(?P<path>\S+\.pb)")).to_regex().is_ok());
+    }
+
+    #[test]
+    fn loads_a_rules_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("rules.json");
+        std::fs::write(
+            &path,
+            r#"{
+                "outputs": [{ "tool": "protoc", "flag": "--cpp_out" }],
+                "variables": { "A": "1" }
+            }"#,
+        )
+        .unwrap();
+
+        let rules = RulesFile::load(&path).unwrap();
+        assert_eq!(rules.outputs, [rule(Some("--cpp_out"), None)]);
+        assert_eq!(rules.variables["A"], "1");
+
+        std::fs::write(&path, r#"{ "outputs": [{ "flag": "-o" }] }"#).unwrap();
+        assert!(RulesFile::load(&path).is_err());
+    }
+}
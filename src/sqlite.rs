@@ -0,0 +1,177 @@
+//!
+//! Writes parse results into a SQLite database
+//!
+//! The schema is documented in the README and should only be changed in backwards
+//! compatible ways (i.e. by adding tables or nullable columns).
+//!
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use rusqlite::{params, Connection};
+
+use crate::types::{Target, Variable};
+
+const SCHEMA: &str = "
+    CREATE TABLE targets (
+        id INTEGER PRIMARY KEY,
+        name TEXT NOT NULL,
+        is_default INTEGER NOT NULL
+    );
+    CREATE TABLE prerequisites (
+        target_id INTEGER NOT NULL REFERENCES targets(id),
+        position INTEGER NOT NULL,
+        name TEXT NOT NULL
+    );
+    CREATE TABLE outputs (
+        target_id INTEGER NOT NULL REFERENCES targets(id),
+        position INTEGER NOT NULL,
+        path TEXT NOT NULL
+    );
+    CREATE TABLE variables (
+        name TEXT PRIMARY KEY,
+        value TEXT NOT NULL,
+        flavor TEXT NOT NULL,
+        origin TEXT NOT NULL
+    );
+";
+
+/// Write targets and variables to a new SQLite database at `path`, replacing any
+/// existing file
+pub fn write_sqlite<P: AsRef<Path>>(
+    path: P,
+    targets: &[Target],
+    vars: &BTreeMap<String, Variable>,
+) -> Result<(), String> {
+    let path = path.as_ref();
+
+    if path.exists() {
+        if let Err(e) = std::fs::remove_file(path) {
+            return Err(format!("Couldn't replace {}: {}", path.display(), e));
+        }
+    }
+
+    let mut conn = Connection::open(path)
+        .map_err(|e| format!("Couldn't open database {}: {}", path.display(), e))?;
+
+    insert_all(&mut conn, targets, vars).map_err(|e| format!("Failed to write database: {}", e))
+}
+
+/// Create the schema and insert everything in a single transaction
+fn insert_all(
+    conn: &mut Connection,
+    targets: &[Target],
+    vars: &BTreeMap<String, Variable>,
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(SCHEMA)?;
+
+    for (id, target) in targets.iter().enumerate() {
+        tx.execute(
+            "INSERT INTO targets (id, name, is_default) VALUES (?1, ?2, ?3)",
+            params![id as i64, target.name, target.default],
+        )?;
+
+        for (pos, prereq) in target.prerequisites.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO prerequisites (target_id, position, name) VALUES (?1, ?2, ?3)",
+                params![id as i64, pos as i64, prereq],
+            )?;
+        }
+
+        for (pos, output) in target.output.iter().flatten().enumerate() {
+            tx.execute(
+                "INSERT INTO outputs (target_id, position, path) VALUES (?1, ?2, ?3)",
+                params![id as i64, pos as i64, output],
+            )?;
+        }
+    }
+
+    for (name, var) in vars {
+        // store enum values with the same names used in the JSON output
+        let flavor = serde_json::to_value(var.flavor).unwrap();
+        let origin = serde_json::to_value(var.origin).unwrap();
+        tx.execute(
+            "INSERT INTO variables (name, value, flavor, origin) VALUES (?1, ?2, ?3, ?4)",
+            params![name, var.value, flavor.as_str(), origin.as_str()],
+        )?;
+    }
+
+    tx.commit()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parser::Parser;
+
+    #[test]
+    fn round_trips_an_analysis() {
+        let dir = tempfile::tempdir().unwrap();
+        let makefile = dir.path().join("Makefile");
+        std::fs::write(
+            &makefile,
+            "CC := gcc\n\
+            app: zlib.o main.o util.o\n\
+            \t$(CC) -o app zlib.o main.o util.o\n\
+            \t$(CC) -o app.debug -g zlib.o main.o util.o\n\
+            clean:\n\
+            \trm -f app\n",
+        )
+        .unwrap();
+        let mut parser = Parser::new();
+        let targets = parser.parse_file(&makefile, true).unwrap();
+
+        let db = dir.path().join("results.db");
+        write_sqlite(&db, &targets, parser.variables()).unwrap();
+        let conn = Connection::open(&db).unwrap();
+
+        let query = |sql: &str| -> Vec<String> {
+            let mut stmt = conn.prepare(sql).unwrap();
+            let rows = stmt.query_map([], |row| row.get(0)).unwrap();
+            rows.map(|r| r.unwrap()).collect()
+        };
+        assert_eq!(query("SELECT name FROM targets ORDER BY id"), ["app", "clean"]);
+        assert_eq!(query("SELECT name FROM targets WHERE is_default = 1"), ["app"]);
+        assert_eq!(
+            query(
+                "SELECT p.name FROM prerequisites p JOIN targets t ON t.id = p.target_id \
+                WHERE t.name = 'app' ORDER BY p.position"
+            ),
+            ["zlib.o", "main.o", "util.o"]
+        );
+        assert_eq!(
+            query(
+                "SELECT o.path FROM outputs o JOIN targets t ON t.id = o.target_id \
+                WHERE t.name = 'app' ORDER BY o.position"
+            ),
+            ["app", "app.debug"]
+        );
+        assert_eq!(
+            query("SELECT name FROM targets WHERE id NOT IN (SELECT target_id FROM outputs)"),
+            ["clean"]
+        );
+        assert_eq!(
+            query(
+                "SELECT value || ',' || flavor || ',' || origin FROM variables \
+                WHERE name = 'CC'"
+            ),
+            ["gcc,simple,file"]
+        );
+    }
+
+    #[test]
+    fn replaces_an_existing_database() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("results.db");
+        write_sqlite(&db, &[Target::new("old".to_string())], &BTreeMap::new()).unwrap();
+        write_sqlite(&db, &[Target::new("new".to_string())], &BTreeMap::new()).unwrap();
+
+        let conn = Connection::open(&db).unwrap();
+        let names: String = conn
+            .query_row("SELECT group_concat(name) FROM targets", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(names, "new");
+    }
+}
@@ -2,6 +2,8 @@
 //! Stores data relevant to a single make target
 //!
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -10,8 +12,72 @@ pub struct Target {
     pub name: String,
     // whether this is the default (first) target or not
     pub default: bool,
+    // whether the target is declared .PHONY, so it doesn't name a file
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub phony: bool,
+    // what the target does, from a trailing `##` comment or the comments right before its rule
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    // special targets that list this one, like "precious" for .PRECIOUS
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attributes: Vec<String>,
+    // makefile that the target's first rule is in, which may be an included file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
     // output path associated with the target (may be a file or folder)
     pub output: Option<Vec<String>>,
+    // prerequisites listed after the target's colon
+    pub prerequisites: Vec<String>,
+    // prerequisites listed after a `|`, which must exist but don't cause a rebuild when changed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order_only: Vec<String>,
+    // outputs whose paths depend on shell variables, like those written in a loop
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub templated_outputs: Vec<String>,
+    // paths deleted by `rm` commands in the target's recipe
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removes: Vec<String>,
+    // variables exported by commands in the target's recipe, like `export DESTDIR=/tmp/pkg`,
+    // which only apply to the rest of the recipe line they're on
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub recipe_exports: BTreeMap<String, String>,
+    // goals passed to recursive `$(MAKE)` calls in the target's recipe
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub delegates_to: Vec<String>,
+    // what each prerequisite refers to, when prerequisites have been resolved
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub resolved_prerequisites: BTreeMap<String, PrereqKind>,
+    // whether the target is defined by `::` rules, which each have their own recipe
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub double_colon: bool,
+    // each `::` rule for the target, when they're grouped together
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub variants: Vec<Variant>,
+    // every target of a grouped (`&:`) rule, whose recipe makes all of them at once
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub group: Vec<String>,
+    // target-specific variables, which apply when expanding the target's recipe. An append
+    // (`+=`) is added to the value the variable has outside of the target.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, Variable>,
+    // lines of the target's recipe, without their indentation, when recipes are requested
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipe: Vec<RecipeLine>,
+    // where each output was found
+    #[serde(skip)]
+    pub output_sources: Vec<OutputSource>,
+    // the target's definition as written, before variable expansion
+    #[serde(skip)]
+    pub raw: RawText,
+    // where each of the rules defining the target is, including its recipe
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<Span>,
+    // the targets listed before a static pattern rule, when this is that rule's target pattern
+    #[serde(skip)]
+    pub static_targets: Vec<String>,
+    // the names after the first of a rule that defines several targets, which share its recipe
+    #[serde(skip)]
+    pub other_names: Vec<String>,
 }
 
 impl Target {
@@ -19,9 +85,176 @@ impl Target {
         Target {
             name,
             default: false,
+            phony: false,
+            description: None,
+            attributes: Vec::new(),
+            file: None,
             output: None,
+            prerequisites: Vec::new(),
+            order_only: Vec::new(),
+            templated_outputs: Vec::new(),
+            removes: Vec::new(),
+            recipe_exports: BTreeMap::new(),
+            delegates_to: Vec::new(),
+            resolved_prerequisites: BTreeMap::new(),
+            double_colon: false,
+            variants: Vec::new(),
+            group: Vec::new(),
+            variables: BTreeMap::new(),
+            recipe: Vec::new(),
+            output_sources: Vec::new(),
+            raw: RawText::default(),
+            spans: Vec::new(),
+            static_targets: Vec::new(),
+            other_names: Vec::new(),
+        }
+    }
+}
+
+/// One of the `::` rules for a target, which is run independently of the others
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Variant {
+    pub prerequisites: Vec<String>,
+    pub output: Vec<String>,
+    pub recipe: Vec<RecipeLine>,
+}
+
+impl Variant {
+    /// Get the variant for a single `::` rule
+    pub fn of(target: &Target) -> Self {
+        Variant {
+            prerequisites: target.prerequisites.clone(),
+            output: target.output.clone().unwrap_or_default(),
+            recipe: target.recipe.clone(),
+        }
+    }
+}
+
+/// A rule with a `%` in its target, which applies to any target matching the pattern
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PatternRule {
+    pub target: String,
+    pub prerequisites: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub order_only: Vec<String>,
+    // outputs found in the recipe, with `%` standing in for the stem
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub output: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recipe: Vec<RecipeLine>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+}
+
+impl PatternRule {
+    /// Get the pattern rule parsed as a target
+    pub fn of(target: &Target) -> Self {
+        PatternRule {
+            target: target.name.clone(),
+            prerequisites: target.prerequisites.clone(),
+            order_only: target.order_only.clone(),
+            output: target.output.clone().unwrap_or_default(),
+            recipe: target.recipe.clone(),
+            file: target.file.clone(),
         }
     }
+
+    /// Whether a target name is a pattern
+    pub fn is_pattern(name: &str) -> bool {
+        name.contains('%')
+    }
+}
+
+/// Directories searched for files that don't exist where they're named, from a `vpath`
+/// directive or the `VPATH` variable
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchPath {
+    // the `%` pattern of names the directories are searched for, or none for `VPATH`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pattern: Option<String>,
+    pub directories: Vec<String>,
+}
+
+impl SearchPath {
+    /// Whether the directories are searched for a file name
+    pub fn applies_to(&self, name: &str) -> bool {
+        match &self.pattern {
+            Some(pattern) if pattern.contains('%') => {
+                crate::functions::pattern_stem(pattern, name).is_some()
+            }
+            Some(pattern) => pattern == name,
+            None => true,
+        }
+    }
+}
+
+/// A line of a recipe, with the prefixes that change how make runs it split off
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RecipeLine {
+    pub command: String,
+    // `@`, the command isn't echoed before it's run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub silent: bool,
+    // `-`, a failure of the command is ignored
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ignore_errors: bool,
+    // `+`, the command is run even by `make -n`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub always_run: bool,
+}
+
+impl RecipeLine {
+    /// Split the `@`, `-`, and `+` prefixes off of a recipe line, in any order
+    pub fn parse(line: &str) -> Self {
+        let mut recipe = RecipeLine::default();
+
+        let mut command = line.trim();
+        loop {
+            match command.chars().next() {
+                Some('@') => recipe.silent = true,
+                Some('-') => recipe.ignore_errors = true,
+                Some('+') => recipe.always_run = true,
+                _ => break,
+            }
+            command = command[1..].trim_start();
+        }
+        recipe.command = command.to_string();
+        recipe
+    }
+}
+
+/// The recipe line that an output was found in
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OutputSource {
+    pub output: String,
+    // the expanded recipe line, without its indentation
+    pub command: String,
+    pub file: String,
+    pub line: usize,
+    // column (starting at 1) of the output as written on the line, or of the command if the
+    // output isn't written out
+    pub column: usize,
+    // how the output was found: "annotation" for an `# Output:` comment, otherwise the
+    // name of the command or flag that produced it
+    pub via: String,
+}
+
+/// The lines of a makefile that a rule or variable definition covers
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Span {
+    pub file: String,
+    pub line_start: usize,
+    pub line_end: usize,
+    // column (starting at 1) that the name of the target or variable starts at
+    pub column: usize,
+}
+
+/// Parts of a target's definition before any variables were expanded
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawText {
+    pub name: String,
+    pub prerequisites: Vec<String>,
+    pub outputs: Vec<String>,
 }
 
 impl PartialEq for Target {
@@ -29,3 +262,207 @@ impl PartialEq for Target {
         self.name == other.name.as_str()
     }
 }
+
+/// Everything found while analyzing a makefile
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Analysis {
+    pub targets: Vec<Target>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pattern_rules: Vec<PatternRule>,
+    // pattern-specific variables, like `%.o: CFLAGS += -fPIC`, for each pattern they apply to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pattern_variables: BTreeMap<String, BTreeMap<String, Variable>>,
+    // the shell that recipes are run with, when the makefile sets SHELL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shell: Option<String>,
+    // whether .ONESHELL is given, so each recipe is run by one shell instead of one per line
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub one_shell: bool,
+    // special targets like .PRECIOUS, with the names listed as their prerequisites
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub special_targets: BTreeMap<String, Vec<String>>,
+    // vpath directives in the order they're searched, followed by the VPATH directories
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_paths: Vec<SearchPath>,
+    // goals given on the command line, which the targets are restricted to
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub requested_goals: Vec<String>,
+    // variables set on the command line to select a build profile
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub profile: BTreeMap<String, String>,
+    // variables defined by the end of parsing, sorted by name so the output is reproducible
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub variables: BTreeMap<String, Variable>,
+    // every assignment made to each variable, in the order they were parsed
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub assignments: BTreeMap<String, Vec<Assignment>>,
+    // problems found while parsing that didn't stop the analysis
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+/// How serious a diagnostic is
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Error,
+}
+
+/// A problem found while parsing, along with where it was found
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // file being parsed when the problem was found
+    pub file: Option<String>,
+    // line number (starting at 1) within that file
+    pub line: Option<usize>,
+}
+
+/// What a prerequisite name refers to
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrereqKind {
+    // another target in the makefile
+    Target,
+    // a file that exists (or is assumed to)
+    File,
+    // neither a target nor a known file
+    Unknown,
+}
+
+/// The variant of make whose syntax a makefile is written in
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    // GNU make
+    Gnu,
+    // BSD make (bmake), with `.include`, `.if`, `.for`, and `${VAR:modifiers}`
+    Bsd,
+    // Microsoft's NMAKE, with `!include`, `!if`, and macros like `$(@B)` and `$**`
+    Nmake,
+}
+
+/// Assignment operator used when defining a variable
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Flavor {
+    // `=`, expanded each time the variable is used
+    Recursive,
+    // `:=`, expanded once when the variable is defined
+    Simple,
+    // `?=`, only assigned if the variable isn't already set
+    Conditional,
+    // `+=`, appended to the existing value, keeping that variable's flavor
+    Append,
+    // `!=`, set to the output of a shell command
+    Shell,
+}
+
+impl Flavor {
+    /// Get the operator used to make an assignment of this flavor
+    pub fn operator(&self) -> &'static str {
+        match self {
+            Flavor::Recursive => "=",
+            Flavor::Simple => ":=",
+            Flavor::Conditional => "?=",
+            Flavor::Append => "+=",
+            Flavor::Shell => "!=",
+        }
+    }
+
+    /// Get the name `$(flavor)` gives a variable assigned with this flavor. Only `:=` makes a
+    /// simply expanded variable.
+    pub fn expansion(&self) -> &'static str {
+        match self {
+            Flavor::Simple => "simple",
+            _ => "recursive",
+        }
+    }
+}
+
+/// A single assignment to a variable
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Assignment {
+    pub line: usize,
+    pub operator: String,
+    pub value: String,
+    pub source_file: String,
+}
+
+/// Where a variable's value came from
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Origin {
+    // defined in a parsed makefile
+    File,
+    // seeded from the environment (or an env file)
+    Environment,
+    // overridden on the command line (or in MAKEFLAGS)
+    #[serde(rename = "command line")]
+    CommandLine,
+    // set by the parser itself, like `$@`
+    Automatic,
+    // a default set before parsing, like those from a rules file
+    Default,
+}
+
+/// A variable's value along with how and where it was defined
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Variable {
+    pub value: String,
+    pub flavor: Flavor,
+    pub origin: Origin,
+    // whether the variable is exported to the environment of recipes
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub exported: bool,
+    // whether the variable was assigned with `override`, taking precedence over the command line
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub overridden: bool,
+    // where the variable was defined, if it was defined in a makefile
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub span: Option<Span>,
+}
+
+impl Variable {
+    pub fn new(value: String, flavor: Flavor, origin: Origin) -> Self {
+        Variable { value, flavor, origin, exported: false, overridden: false, span: None }
+    }
+
+    /// Get the name `$(origin)` gives the variable's origin
+    pub fn origin_name(&self) -> &'static str {
+        if self.overridden {
+            return "override";
+        }
+        match self.origin {
+            Origin::File => "file",
+            Origin::Environment => "environment",
+            Origin::CommandLine => "command line",
+            Origin::Automatic => "automatic",
+            Origin::Default => "default",
+        }
+    }
+}
+
+/// Classification of a single line of a Makefile
+#[derive(Clone, Debug, PartialEq)]
+pub enum LineKind {
+    // a rule definition, with the prerequisites listed after the colon (or double colon)
+    Target { name: String, prereqs: Vec<String>, double_colon: bool },
+    // a variable assignment
+    Variable { name: String, value: String, flavor: Flavor },
+    // a variable assignment that only applies to the recipes of the targets listed before it
+    TargetVariable { targets: Vec<String>, name: String, value: String, flavor: Flavor },
+    // a comment line
+    Comment,
+    // an indented line belonging to the current target's recipe
+    Recipe,
+    // a make directive (include, conditionals, special targets, etc.)
+    Directive,
+    // an empty or whitespace-only line
+    Blank,
+    // anything the parser doesn't recognize
+    Other,
+}